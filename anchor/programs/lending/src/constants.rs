@@ -2,5 +2,26 @@ use anchor_lang::prelude::*;
 
 #[constant]
 pub const MAXIMUM_AGE: u64 = 10000;
+// Confidence interval allowed for new banks, in basis points of the price (2%)
+pub const DEFAULT_ORACLE_MAX_CONFIDENCE: u64 = 200;
+// Flash loan fee for new banks, in basis points (0.09%)
+pub const DEFAULT_FLASH_LOAN_FEE: u64 = 9;
 pub const MAX_MINTS: usize = 64;
-pub const MAX_BORROW_POSITIONS: usize = 64;
\ No newline at end of file
+pub const MAX_BORROW_POSITIONS: usize = 64;
+pub const MAX_ISOLATED_BORROW_MINTS: usize = 8;
+pub const MAX_POSITION_COLLATERALS: usize = 4;
+// Risk parameters (LTV, liquidation threshold, bonus, close factor) are in basis points
+pub const BPS_DENOMINATOR: u64 = 10_000;
+// Interest rates are expressed per accrual period in millionths (5_000 = 0.5%)
+pub const RATE_PRECISION: u64 = 1_000_000;
+// Most accrual periods compounded in one call; a bank idle for longer catches up over several calls
+pub const MAX_COMPOUND_PERIODS: i64 = 31_536_000;
+// USD amounts stored on chain, e.g. isolated debt ceilings, are in millionths of a dollar
+pub const USD_PRECISION: u64 = 1_000_000;
+// Bits of the `paused_operations` masks on `ProtocolConfig` and `Bank`
+pub const PAUSE_DEPOSIT: u8 = 1 << 0;
+pub const PAUSE_WITHDRAW: u8 = 1 << 1;
+pub const PAUSE_BORROW: u8 = 1 << 2;
+pub const PAUSE_REPAY: u8 = 1 << 3;
+pub const PAUSE_LIQUIDATE: u8 = 1 << 4;
+pub const PAUSE_ALL: u8 = PAUSE_DEPOSIT | PAUSE_WITHDRAW | PAUSE_BORROW | PAUSE_REPAY | PAUSE_LIQUIDATE;
@@ -36,4 +36,80 @@ pub enum ErrorCode {
     InsufficientCollateral,
     #[msg("Already Repayed")]
     AlreadyRepayed,
+    #[msg("Invalid Liquidation Amount")]
+    InvalidLiquidationAmount,
+    #[msg("Invalid Price")]
+    InvalidPrice,
+    #[msg("Invalid Interest Accrual Period")]
+    InvalidInterestAccrualPeriod,
+    #[msg("Invalid Rate Model")]
+    InvalidRateModel,
+    #[msg("Invalid Risk Parameters")]
+    InvalidRiskParameters,
+    #[msg("Invalid Fee")]
+    InvalidFee,
+    #[msg("Invalid Fee Recipient")]
+    InvalidFeeRecipient,
+    #[msg("No Fees To Collect")]
+    NoFeesToCollect,
+    #[msg("Invalid Health Accounts")]
+    InvalidHealthAccounts,
+    #[msg("Invalid Amount Mode")]
+    InvalidAmountMode,
+    #[msg("Invalid Repay Amount")]
+    InvalidRepayAmount,
+    #[msg("Invalid Oracle Config")]
+    InvalidOracleConfig,
+    #[msg("Price Confidence Too Wide")]
+    PriceConfidenceTooWide,
+    #[msg("Insufficient Price Verification")]
+    InsufficientPriceVerification,
+    #[msg("Bank Locked")]
+    BankLocked,
+    #[msg("Missing Flash Repay")]
+    MissingFlashRepay,
+    #[msg("Flash Loan Not Active")]
+    FlashLoanNotActive,
+    #[msg("Flash Loan CPI Not Allowed")]
+    FlashLoanCpiNotAllowed,
+    #[msg("Invalid Flash Loan Amount")]
+    InvalidFlashLoanAmount,
+    #[msg("Supply Cap Exceeded")]
+    SupplyCapExceeded,
+    #[msg("Borrow Cap Exceeded")]
+    BorrowCapExceeded,
+    #[msg("Unauthorized")]
+    Unauthorized,
+    #[msg("Protocol Paused")]
+    ProtocolPaused,
+    #[msg("Bank Paused")]
+    BankPaused,
+    #[msg("Invalid Pause Flags")]
+    InvalidPauseFlags,
+    #[msg("Unsupported Mint Extension")]
+    UnsupportedMintExtension,
+    #[msg("Flash Loan Underpaid")]
+    FlashLoanUnderpaid,
+    #[msg("Isolated Collateral Cannot Be Mixed")]
+    IsolatedCollateralMixed,
+    #[msg("Borrow Mint Not Allowed Against Isolated Collateral")]
+    IsolatedBorrowMintNotAllowed,
+    #[msg("Isolated Debt Ceiling Exceeded")]
+    IsolatedDebtCeilingExceeded,
+    #[msg("Invalid Isolation Config")]
+    InvalidIsolationConfig,
+    #[msg("Invalid E-Mode Category")]
+    InvalidEModeCategory,
+    #[msg("Bank Not In E-Mode Category")]
+    EModeCategoryMismatch,
+    #[msg("Too Many Position Collaterals")]
+    TooManyPositionCollaterals,
+    #[msg("Invalid Collateral Accounts")]
+    InvalidCollateralAccounts,
+    #[msg("Account Not Empty")]
+    AccountNotEmpty,
+    #[msg("Isolation Cannot Change With Locked Collateral")]
+    IsolationLocked,
+    #[msg("Borrow And Collateral Mint Must Differ")]
+    SameBorrowAndCollateralMint,
 }
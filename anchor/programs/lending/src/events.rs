@@ -0,0 +1,166 @@
+use anchor_lang::prelude::*;
+
+use crate::instructions::BankConfigUpdate;
+use crate::state::PositionCollateral;
+
+// Emitted through `emit_cpi!`, so they are recorded as instruction data of a
+// self-CPI and survive log truncation. Shares and indices are raw on-chain
+// values: indices are WAD-scaled, prices carry their Pyth exponent.
+
+#[event]
+pub struct BankInitialized {
+    pub bank: Pubkey,
+    pub mint: Pubkey,
+    pub authority: Pubkey,
+    pub receipt_mint: Pubkey,
+    pub oracle_feed_id: [u8; 32],
+    pub liquidation_threshold: u64,
+    pub liquidation_bonus: u64,
+    pub max_ltv: u64,
+    pub deposit_fee: u64,
+    pub withdrawal_fee: u64,
+    pub supply_cap: u64,
+    pub borrow_cap: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct Deposited {
+    pub user: Pubkey,
+    pub bank: Pubkey,
+    pub mint: Pubkey,
+    /// Tokens received by the treasury, including the deposit fee.
+    pub amount: u64,
+    pub fee: u64,
+    pub shares: u64,
+    pub supply_index: u128,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct Withdrawn {
+    pub user: Pubkey,
+    pub bank: Pubkey,
+    pub mint: Pubkey,
+    /// Tokens the burned shares were worth, including the withdrawal fee.
+    pub amount: u64,
+    pub fee: u64,
+    pub shares: u64,
+    pub supply_index: u128,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct Borrowed {
+    pub user: Pubkey,
+    pub position: Pubkey,
+    pub bank_borrow: Pubkey,
+    pub bank_collateral: Pubkey,
+    pub amount: u64,
+    pub borrow_shares: u64,
+    pub collateral_shares_locked: u64,
+    /// Shares locked from collaterals beyond `bank_collateral`.
+    pub extra_collateral_locked: Vec<PositionCollateral>,
+    pub borrow_index: u128,
+    pub borrow_price: i64,
+    pub borrow_price_exponent: i32,
+    pub collateral_price: i64,
+    pub collateral_price_exponent: i32,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct Repaid {
+    pub user: Pubkey,
+    pub position: Pubkey,
+    pub bank_borrow: Pubkey,
+    pub bank_collateral: Pubkey,
+    /// Tokens received by the treasury.
+    pub amount: u64,
+    pub borrow_shares: u64,
+    pub collateral_shares_unlocked: u64,
+    /// Shares unlocked from collaterals beyond `bank_collateral`.
+    pub extra_collateral_unlocked: Vec<PositionCollateral>,
+    pub borrow_index: u128,
+    pub borrow_price: i64,
+    pub borrow_price_exponent: i32,
+    pub collateral_price: i64,
+    pub collateral_price_exponent: i32,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct Liquidated {
+    pub liquidator: Pubkey,
+    pub borrower: Pubkey,
+    pub position: Pubkey,
+    pub bank_borrow: Pubkey,
+    pub bank_collateral: Pubkey,
+    pub repay_amount: u64,
+    pub seize_amount: u64,
+    pub borrow_shares_burned: u64,
+    pub collateral_shares_seized: u64,
+    pub borrow_index: u128,
+    pub supply_index: u128,
+    pub borrow_price: i64,
+    pub borrow_price_exponent: i32,
+    pub collateral_price: i64,
+    pub collateral_price_exponent: i32,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct InterestAccrued {
+    pub mint: Pubkey,
+    pub periods: i64,
+    pub supply_index: u128,
+    pub borrow_index: u128,
+    /// Rates per accrual period in millionths, as published on the bank.
+    pub deposit_interest_rate: u64,
+    pub borrow_interest_rate: u64,
+    /// Reserve-factor interest booked by this accrual.
+    pub protocol_fee: u64,
+    pub accumulated_fees: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ConfigUpdated {
+    pub bank: Pubkey,
+    pub mint: Pubkey,
+    pub authority: Pubkey,
+    pub update: BankConfigUpdate,
+    /// Values replaced by `update`, set only for the fields it changes.
+    pub previous: BankConfigUpdate,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct FeesCollected {
+    pub bank: Pubkey,
+    pub mint: Pubkey,
+    pub authority: Pubkey,
+    pub recipient_token_account: Pubkey,
+    pub amount: u64,
+    /// Fees still owed to the protocol after this collection.
+    pub accumulated_fees: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CollateralAdded {
+    pub user: Pubkey,
+    pub position: Pubkey,
+    pub bank: Pubkey,
+    pub shares: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CollateralRemoved {
+    pub user: Pubkey,
+    pub position: Pubkey,
+    pub bank: Pubkey,
+    pub shares: u64,
+    pub timestamp: i64,
+}
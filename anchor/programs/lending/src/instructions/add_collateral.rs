@@ -0,0 +1,146 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{burn, Burn, Mint, TokenAccount, TokenInterface};
+
+use crate::constants::PAUSE_DEPOSIT;
+use crate::error::ErrorCode;
+use crate::events::CollateralAdded;
+use crate::state::{AmountMode, Bank, BorrowPosition, EModeCategory, ProtocolConfig, UserGlobalState, UserTokenState};
+use crate::math::Rounding;
+use crate::utils::*;
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct AddCollateral<'info> {
+    pub signer: Signer<'info>,
+
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        seeds = [mint.key().as_ref()],
+        bump,
+        constraint = !bank.flash_loan_active @ ErrorCode::BankLocked,
+    )]
+    pub bank: Box<Account<'info, Bank>>,
+
+    #[account(
+        mut,
+        seeds = [signer.key().as_ref(), mint.key().as_ref()],
+        bump,
+    )]
+    pub user_token_state: Box<Account<'info, UserTokenState>>,
+
+    #[account(
+        mut,
+        seeds = [b"receipt", mint.key().as_ref()],
+        bump,
+    )]
+    pub receipt_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program,
+    )]
+    pub user_receipt_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"position",
+            signer.key().as_ref(),
+            borrow_position.collateral_mint.as_ref(),
+            borrow_position.borrow_mint.as_ref(),
+            &position_id.to_le_bytes(),
+        ],
+        bump,
+        constraint = borrow_position.active @ ErrorCode::AlreadyRepayed,
+    )]
+    pub borrow_position: Box<Account<'info, BorrowPosition>>,
+
+    #[account(
+        seeds = [b"user_global", signer.key().as_ref()],
+        bump,
+    )]
+    pub user_global_state: Box<Account<'info, UserGlobalState>>,
+
+    #[account(
+        seeds = [b"emode".as_ref(), &[user_global_state.emode_category]],
+        bump = emode_category.bump,
+    )]
+    pub emode_category: Option<Box<Account<'info, EModeCategory>>>,
+
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+/// Locks more of the signer's free deposit shares of `mint` behind an open
+/// position, raising its health. `Max` locks every free share.
+pub fn process_add_collateral(ctx: Context<AddCollateral>, _position_id: u64, mode: AmountMode) -> Result<()> {
+    require_not_paused(&ctx.accounts.protocol_config, &ctx.accounts.bank, PAUSE_DEPOSIT)?;
+    // Collateral in the borrowed mint could never be seized by a liquidation.
+    require_keys_neq!(
+        ctx.accounts.bank.mint_address,
+        ctx.accounts.borrow_position.borrow_mint,
+        ErrorCode::SameBorrowAndCollateralMint
+    );
+    let current_time = Clock::get()?.unix_timestamp;
+    let bank = &mut ctx.accounts.bank;
+    let user_state = &mut ctx.accounts.user_token_state;
+    let position = &mut ctx.accounts.borrow_position;
+
+    let free_shares = free_deposit_shares(user_state, ctx.accounts.user_receipt_account.amount);
+    let shares = match mode {
+        AmountMode::Tokens(amount) => calculate_deposit_shares(bank, amount, Rounding::Up)?,
+        AmountMode::Shares(shares) => shares,
+        AmountMode::Max => free_shares,
+    };
+    debug_msg!("Adding {} of {} free shares of {} to position {}", shares, free_shares, bank.mint_address, position.key());
+    require!(shares > 0, ErrorCode::InvalidAmountMode);
+    require!(shares <= free_shares, ErrorCode::InsufficientCollateral);
+
+    // Isolated collateral only ever backs a position on its own.
+    if bank.isolated {
+        require!(
+            position.collaterals.iter().all(|collateral| collateral.mint == bank.mint_address),
+            ErrorCode::IsolatedCollateralMixed
+        );
+    } else {
+        require!(position.isolated_debt == 0, ErrorCode::IsolatedCollateralMixed);
+    }
+    if let Some(category) = user_emode(&ctx.accounts.user_global_state, &ctx.accounts.emode_category)? {
+        require!(in_emode(bank, category), ErrorCode::EModeCategoryMismatch);
+    }
+
+    burn(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Burn {
+                mint: ctx.accounts.receipt_mint.to_account_info(),
+                from: ctx.accounts.user_receipt_account.to_account_info(),
+                authority: ctx.accounts.signer.to_account_info(),
+            },
+        ),
+        shares,
+    )?;
+    lock_collateral_shares(bank, user_state, shares, current_time)?;
+    add_position_collateral(position, bank.mint_address, shares)?;
+    position.last_updated = current_time;
+    debug_msg!("Position collaterals: {:?}", position.collaterals);
+
+    emit_cpi!(CollateralAdded {
+        user: ctx.accounts.signer.key(),
+        position: position.key(),
+        bank: bank.key(),
+        shares,
+        timestamp: current_time,
+    });
+    Ok(())
+}
@@ -1,14 +1,16 @@
-use std::ops::{Add, Div, Mul};
-
 use anchor_lang::prelude::*;
 use anchor_spl::associated_token::AssociatedToken;
-use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface, TransferChecked, transfer_checked};
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface, TransferChecked, Burn, burn, transfer_checked};
 use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
 
 use crate::state::*;
+use crate::constants::{MAX_POSITION_COLLATERALS, PAUSE_BORROW};
 use crate::error::ErrorCode;
+use crate::events::Borrowed;
+use crate::math::{Decimal, Rounding};
 use crate::utils::*;
 
+#[event_cpi]
 #[derive(Accounts)]
 #[instruction(position_id: u64)]
 pub struct Borrow<'info> {
@@ -16,12 +18,16 @@ pub struct Borrow<'info> {
     pub signer: Signer<'info>,
 
     pub mint_borrow: Box<InterfaceAccount<'info, Mint>>,
+
+    // Both banks and user token states are written, so they must be distinct accounts
+    #[account(constraint = mint_collateral.key() != mint_borrow.key() @ ErrorCode::SameBorrowAndCollateralMint)]
     pub mint_collateral: Box<InterfaceAccount<'info, Mint>>,
 
     #[account(
         mut, 
         seeds = [mint_borrow.key().as_ref()],
         bump,
+        constraint = !bank_borrow.flash_loan_active @ ErrorCode::BankLocked,
     )]
     pub bank_borrow: Box<Account<'info, Bank>>,
 
@@ -54,6 +60,7 @@ pub struct Borrow<'info> {
         mut, 
         seeds = [mint_collateral.key().as_ref()],
         bump,
+        constraint = !bank_collateral.flash_loan_active @ ErrorCode::BankLocked,
     )]  
     pub bank_collateral: Box<Account<'info, Bank>>,
 
@@ -83,7 +90,37 @@ pub struct Borrow<'info> {
     pub user_collateral_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
 
     #[account(
-        init,
+        mut,
+        seeds = [b"receipt", mint_collateral.key().as_ref()],
+        bump,
+    )]
+    pub receipt_mint_collateral: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        associated_token::mint = receipt_mint_collateral,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program,
+    )]
+    pub user_collateral_receipt_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        seeds = [b"receipt", mint_borrow.key().as_ref()],
+        bump,
+    )]
+    pub receipt_mint_borrow: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init_if_needed,
+        payer = signer,
+        associated_token::mint = receipt_mint_borrow,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program,
+    )]
+    pub user_borrow_receipt_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init_if_needed,
         space = 8 + BorrowPosition::INIT_SPACE,
         payer = signer,
         seeds = [
@@ -98,9 +135,7 @@ pub struct Borrow<'info> {
     pub borrow_position: Box<Account<'info, BorrowPosition>>,
 
     pub price_update_borrow_token: Box<Account<'info, PriceUpdateV2>>,
-    pub pyth_network_feed_id_borrow_token: Box<Account<'info, PythNetworkFeedId>>,
     pub price_update_collateral_token: Box<Account<'info, PriceUpdateV2>>,
-    pub pyth_network_feed_id_collateral_token: Box<Account<'info, PythNetworkFeedId>>,
     
     #[account(
         mut,
@@ -109,191 +144,316 @@ pub struct Borrow<'info> {
     )]
     pub user_global_state: Box<Account<'info, UserGlobalState>>,
 
+    #[account(
+        seeds = [b"emode".as_ref(), &[user_global_state.emode_category]],
+        bump = emode_category.bump,
+    )]
+    pub emode_category: Option<Box<Account<'info, EModeCategory>>>,
+
     pub associated_token_program: Program<'info, AssociatedToken>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
     pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
 }
 
-pub fn process_borrow(ctx: Context<Borrow>, position_id: u64, amount: u64) -> Result<()> {
-    msg!("Starting borrow process for amount: {}", amount);
-    msg!("User: {}", ctx.accounts.signer.key());
-    msg!("Borrow mint: {}", ctx.accounts.mint_borrow.key());
-    msg!("Collateral mint: {}", ctx.accounts.mint_collateral.key());
+/// Opens position `position_id`, borrowing against `mint_collateral` and,
+/// when its free deposits do not cover the loan, the
+/// `extra_collateral_count` collaterals that lead `remaining_accounts`
+/// (see `load_extra_collaterals`). The account health accounts follow them.
+///
+/// If the position is already open, the loan is added to it instead: the
+/// new debt goes through the same account-wide LTV check and the collateral
+/// locked for it joins the position's collaterals.
+pub fn process_borrow<'info>(
+    ctx: Context<'_, '_, 'info, 'info, Borrow<'info>>,
+    position_id: u64,
+    mode: AmountMode,
+    extra_collateral_count: u8,
+) -> Result<()> {
+    // A bank paused for borrowing, e.g. over a misbehaving oracle, can
+    // neither lend nor back new loans as collateral.
+    require_not_paused(&ctx.accounts.protocol_config, &ctx.accounts.bank_borrow, PAUSE_BORROW)?;
+    require_not_paused(&ctx.accounts.protocol_config, &ctx.accounts.bank_collateral, PAUSE_BORROW)?;
+
+    // A fresh (or merely initialized) account has no owner yet. A repaid
+    // position keeps its owner and stays closed.
+    let extending = ctx.accounts.borrow_position.active;
+    require!(
+        extending || ctx.accounts.borrow_position.owner == Pubkey::default(),
+        ErrorCode::AlreadyRepayed
+    );
+
+    debug_msg!("Starting borrow process: {:?}", mode);
+    debug_msg!("Extending existing position: {}", extending);
+    debug_msg!("User: {}", ctx.accounts.signer.key());
+    debug_msg!("Borrow mint: {}", ctx.accounts.mint_borrow.key());
+    debug_msg!("Collateral mint: {}", ctx.accounts.mint_collateral.key());
     
     // if amount < ctx.accounts.bank_borrow.min_deposit {
-    //     msg!("ERROR: Borrow amount {} is less than minimum deposit {}", amount, ctx.accounts.bank_borrow.min_deposit);
+    //     debug_msg!("ERROR: Borrow amount {} is less than minimum deposit {}", amount, ctx.accounts.bank_borrow.min_deposit);
     //     return Err(ErrorCode::BorrowAmountTooSmall.into());
     // }
 
     let current_time = Clock::get()?.unix_timestamp;
-    msg!("Current timestamp: {}", current_time);
+    debug_msg!("Current timestamp: {}", current_time);
     
+    // Treasury balances drive each bank's utilization when accruing interest.
+    let borrow_liquidity = ctx.accounts.bank_borrow_token_account.amount;
+    let collateral_liquidity = ctx.accounts.bank_collateral_token_account.amount;
     let bank_borrow = &mut ctx.accounts.bank_borrow;
     let bank_collateral = &mut ctx.accounts.bank_collateral;
     let user_borrow = &mut ctx.accounts.user_borrow_account;
     let user_collateral = &mut ctx.accounts.user_collateral_account;
 
-    msg!("Bank borrow state before interest accrual:");
-    msg!("  Total borrowed shares: {}", bank_borrow.total_borrowed_shares);
-    msg!("  Last compound time: {}", bank_borrow.last_compound_time);
-    
-    msg!("Bank collateral state before interest accrual:");
-    msg!("  Total deposited shares: {}", bank_collateral.total_deposited_shares);
-    msg!("  Total collateral shares: {}", bank_collateral.total_collateral_shares);
-    msg!("  Last compound time: {}", bank_collateral.last_compound_time);
-
-    msg!("Accruing interest for collateral bank");
-    accrue_interest(bank_collateral, current_time)?;
-    msg!("Accruing interest for borrow bank");
-    accrue_interest(bank_borrow, current_time)?;
-
-    msg!("Bank borrow state after interest accrual:");
-    msg!("  Total borrowed shares: {}", bank_borrow.total_borrowed_shares);
-    msg!("  Last compound time: {}", bank_borrow.last_compound_time);
+    debug_msg!("Bank borrow state before interest accrual:");
+    debug_msg!("  Total borrowed shares: {}", bank_borrow.total_borrowed_shares);
+    debug_msg!("  Last compound time: {}", bank_borrow.last_compound_time);
     
-    msg!("Bank collateral state after interest accrual:");
-    msg!("  Total deposited shares: {}", bank_collateral.total_deposited_shares);
-    msg!("  Total collateral shares: {}", bank_collateral.total_collateral_shares);
-    msg!("  Last compound time: {}", bank_collateral.last_compound_time);
-
-    msg!("User collateral state:");
-    msg!("  Deposited shares: {}", user_collateral.deposited_shares);
-    msg!("  Collateral shares: {}", user_collateral.collateral_shares);
-    msg!("  Last updated deposited: {}", user_collateral.last_updated_deposited);
-    msg!("  Last updated collateral: {}", user_collateral.last_updated_collateral);
-
-    msg!("User borrow state:");
-    msg!("  Borrowed shares: {}", user_borrow.borrowed_shares);
-    msg!("  Last updated borrowed: {}", user_borrow.last_updated_borrowed);
-
-    msg!("Calculating collateral value");
-    let collateral_assets = calculate_user_assets(
-        bank_collateral,
-        user_collateral.deposited_shares,
-        user_collateral.last_updated_deposited
-    )?;
-    msg!("Collateral assets: {}", collateral_assets);
+    debug_msg!("Bank collateral state before interest accrual:");
+    debug_msg!("  Total deposited shares: {}", bank_collateral.total_deposited_shares);
+    debug_msg!("  Total collateral shares: {}", bank_collateral.total_collateral_shares);
+    debug_msg!("  Last compound time: {}", bank_collateral.last_compound_time);
+
+    debug_msg!("Accruing interest for collateral bank");
+    if let Some(event) = accrue_interest(bank_collateral, collateral_liquidity, current_time)? {
+        emit_cpi!(event);
+    }
+    debug_msg!("Accruing interest for borrow bank");
+    if let Some(event) = accrue_interest(bank_borrow, borrow_liquidity, current_time)? {
+        emit_cpi!(event);
+    }
+    sync_mint_interest_config(bank_collateral, &ctx.accounts.mint_collateral.to_account_info())?;
+    sync_mint_interest_config(bank_borrow, &ctx.accounts.mint_borrow.to_account_info())?;
+
+    debug_msg!("Bank borrow state after interest accrual:");
+    debug_msg!("  Total borrowed shares: {}", bank_borrow.total_borrowed_shares);
+    debug_msg!("  Last compound time: {}", bank_borrow.last_compound_time);
     
-    msg!("Getting collateral token price from Pyth oracle");
-    msg!("Collateral price feed account: {}", ctx.accounts.price_update_collateral_token.key());
-    msg!("Collateral Pyth network feed ID: {}", ctx.accounts.pyth_network_feed_id_collateral_token.key());
+    debug_msg!("Bank collateral state after interest accrual:");
+    debug_msg!("  Total deposited shares: {}", bank_collateral.total_deposited_shares);
+    debug_msg!("  Total collateral shares: {}", bank_collateral.total_collateral_shares);
+    debug_msg!("  Last compound time: {}", bank_collateral.last_compound_time);
+
+    debug_msg!("User collateral state:");
+    debug_msg!("  Deposited shares: {}", user_collateral.deposited_shares);
+    debug_msg!("  Collateral shares: {}", user_collateral.collateral_shares);
+    debug_msg!("  Last updated deposited: {}", user_collateral.last_updated_deposited);
+    debug_msg!("  Last updated collateral: {}", user_collateral.last_updated_collateral);
+
+    debug_msg!("User borrow state:");
+    debug_msg!("  Borrowed shares: {}", user_borrow.borrowed_shares);
+    debug_msg!("  Last updated borrowed: {}", user_borrow.last_updated_borrowed);
+
+    debug_msg!("Getting collateral token price from Pyth oracle");
+    debug_msg!("Collateral price feed account: {}", ctx.accounts.price_update_collateral_token.key());
     let collateral_price = get_validated_price(
         &ctx.accounts.price_update_collateral_token,
-        &ctx.accounts.pyth_network_feed_id_collateral_token,
-    )?;
-    msg!("Collateral price: {} with exponent {}", collateral_price.price, collateral_price.exponent);
-    
-    let collateral_value = (collateral_assets as f64)
-        .div(10u128.pow(ctx.accounts.mint_collateral.decimals as u32) as f64)
-        .mul(collateral_price.price as f64)
-        .div(10u128.pow((-1 * collateral_price.exponent) as u32) as f64);
-    msg!("Total collateral value in USD: {}", collateral_value);
-
-    msg!("Calculating existing debt");
-    let existing_debt = calculate_user_assets(
-        bank_borrow,
-        user_borrow.borrowed_shares,
-        user_borrow.last_updated_borrowed
+        bank_collateral,
     )?;
-    msg!("Existing debt in token units: {}", existing_debt);
+    debug_msg!("Collateral price: {} with exponent {}", collateral_price.price, collateral_price.exponent);
 
-    msg!("Getting borrow token price from Pyth oracle");
-    msg!("Borrow price feed account: {}", ctx.accounts.price_update_borrow_token.key());
-    msg!("Borrow Pyth network feed ID: {}", ctx.accounts.pyth_network_feed_id_borrow_token.key());
+    debug_msg!("Getting borrow token price from Pyth oracle");
+    debug_msg!("Borrow price feed account: {}", ctx.accounts.price_update_borrow_token.key());
     let borrow_price = get_validated_price(
         &ctx.accounts.price_update_borrow_token,
-        &ctx.accounts.pyth_network_feed_id_borrow_token,
+        bank_borrow,
     )?;
-    msg!("Borrow token price: {} with exponent {}", borrow_price.price, borrow_price.exponent);
-    
-    msg!("Calculating borrow value");
-    msg!("Amount: {}", amount);
-    msg!("Borrow token decimals: {}", ctx.accounts.mint_borrow.decimals);
-    msg!("Borrow price: {}", borrow_price.price);
-    msg!("Borrow price exponent: {}", borrow_price.exponent);
-    
-    let borrow_value = (amount as f64)
-        .div(10u128.pow(ctx.accounts.mint_borrow.decimals as u32) as f64)
-        .mul(borrow_price.price as f64)
-        .div(10u128.pow((-1 * borrow_price.exponent) as u32) as f64);
-    msg!("Requested borrow value in USD: {}", borrow_value);
-
-    let max_borrow_value = collateral_value
-        .mul(bank_collateral.max_ltv as f64)
-        .div(10_000.0);
-    msg!("Max LTV: {}%", bank_collateral.max_ltv as f64 / 100.0);
-    msg!("Max borrow value allowed in USD: {}", max_borrow_value);
-    
-    // Convert the existing debt (denominated in smallest units of the borrow token)
-    // into its USD value by accounting for the token decimals first and then the
-    // oracle price exponent. Without dividing by the token decimals, the USD value
-    // would be overstated by several orders of magnitude, which incorrectly
-    // triggers the LTV check.
-    let existing_debt_value = (existing_debt as f64)
-        .div(10u128.pow(ctx.accounts.mint_borrow.decimals as u32) as f64)
-        .mul(borrow_price.price as f64)
-        .div(10u128.pow((-1 * borrow_price.exponent) as u32) as f64);
-
-    let total_debt_value = existing_debt_value + borrow_value;
-    msg!("Existing debt value in USD: {}", existing_debt_value);
-    msg!("Total debt value after this borrow in USD: {}", total_debt_value);
-    
+    debug_msg!("Borrow token price: {} with exponent {}", borrow_price.price, borrow_price.exponent);
+
+    // Share requests round the tokens handed out down. There is no single
+    // maximum to borrow, since it depends on which collateral gets locked.
+    let amount = match mode {
+        AmountMode::Tokens(amount) => amount,
+        AmountMode::Shares(shares) => calculate_debt_amount(bank_borrow, shares, Rounding::Down)?,
+        AmountMode::Max => return Err(ErrorCode::InvalidAmountMode.into()),
+    };
+    require!(amount > 0, ErrorCode::BorrowAmountTooSmall);
+
+    debug_msg!("Calculating borrow value");
+    debug_msg!("Amount: {}", amount);
+    debug_msg!("Borrow token decimals: {}", ctx.accounts.mint_borrow.decimals);
+    let borrow_value = calculate_token_value(
+        amount,
+        ctx.accounts.mint_borrow.decimals,
+        &borrow_price,
+        Rounding::Up,
+    )?;
+    debug_msg!("Requested borrow value in USD: {}", borrow_value);
+
+    // Collaterals beyond `mint_collateral` lead `remaining_accounts`.
+    let (mut extra_collaterals, health_accounts) = load_extra_collaterals(
+        &ctx.accounts.signer.key(),
+        ctx.remaining_accounts,
+        extra_collateral_count as usize,
+    )?;
+    require!(extra_collaterals.len() < MAX_POSITION_COLLATERALS, ErrorCode::TooManyPositionCollaterals);
+    for collateral in extra_collaterals.iter() {
+        require_not_paused(&ctx.accounts.protocol_config, &collateral.bank, PAUSE_BORROW)?;
+        require!(
+            collateral.bank.mint_address != bank_collateral.mint_address
+                && collateral.bank.mint_address != bank_borrow.mint_address,
+            ErrorCode::InvalidCollateralAccounts
+        );
+    }
+
+    // Every deposit of the user backs every loan, so the new debt is checked
+    // against the LTV-weighted value of all of their collateral.
+    debug_msg!("Calculating account health");
+    let mut known_assets = vec![
+        HealthAsset {
+            bank: bank_collateral,
+            user_state: user_collateral,
+            receipt_balance: ctx.accounts.user_collateral_receipt_account.amount,
+            price: collateral_price,
+        },
+        HealthAsset {
+            bank: bank_borrow,
+            user_state: user_borrow,
+            receipt_balance: ctx.accounts.user_borrow_receipt_account.amount,
+            price: borrow_price,
+        },
+    ];
+    known_assets.extend(extra_collaterals.iter().map(ExtraCollateral::health_asset));
+    let emode = user_emode(&ctx.accounts.user_global_state, &ctx.accounts.emode_category)?;
+    if let Some(category) = emode {
+        require!(
+            in_emode(bank_borrow, category)
+                && in_emode(bank_collateral, category)
+                && extra_collaterals.iter().all(|collateral| in_emode(&collateral.bank, category)),
+            ErrorCode::EModeCategoryMismatch
+        );
+    }
+    let health = calculate_account_health(
+        &ctx.accounts.signer.key(),
+        &ctx.accounts.user_global_state,
+        &known_assets,
+        health_accounts,
+        emode,
+        current_time,
+    )?;
+    // Free deposits of an isolated bank are left out of account health, but
+    // can back a loan isolated on that bank.
+    let mut max_borrow_value = health.borrow_limit;
+    if bank_collateral.isolated {
+        let free_shares = free_deposit_shares(user_collateral, ctx.accounts.user_collateral_receipt_account.amount);
+        let free_amount = calculate_deposit_amount(bank_collateral, free_shares, Rounding::Down)?;
+        let free_value = calculate_token_value(free_amount, bank_collateral.mint_decimals, &collateral_price, Rounding::Down)?;
+        max_borrow_value = max_borrow_value
+            .try_add(free_value.try_mul(Decimal::from_bps(risk_params(bank_collateral, emode).max_ltv))?)?;
+    }
+    let total_debt_value = health.debt_value.try_add(borrow_value)?;
+    debug_msg!("Existing debt value in USD: {}", health.debt_value);
+    debug_msg!("Total debt value after this borrow in USD: {}", total_debt_value);
+    debug_msg!("Max borrow value allowed in USD: {}", max_borrow_value);
+
     if total_debt_value > max_borrow_value {
-        msg!("ERROR: Borrow exceeds LTV limit. Total debt value {} > max borrow value {}", total_debt_value, max_borrow_value);
+        debug_msg!("ERROR: Borrow exceeds LTV limit. Total debt value {} > max borrow value {}", total_debt_value, max_borrow_value);
         return Err(ErrorCode::BorrowAmountTooLarge.into());
     }
-    msg!("LTV check passed: {} <= {}", total_debt_value, max_borrow_value);
-
-    let bank_borrow_total_assets = calculate_total_assets(bank_borrow);
-    msg!("Bank borrow total assets: {}", bank_borrow_total_assets);
-    let bank_borrow_total_borrowed_assets = calculate_borrowed_assets(bank_borrow);
-    msg!("Bank borrow total borrowed assets: {}", bank_borrow_total_borrowed_assets);
+    debug_msg!("LTV check passed: {} <= {}", total_debt_value, max_borrow_value);
+
+    // Isolated collateral is the only collateral of the account while it backs
+    // a loan, and only lends whitelisted mints up to the bank's debt ceiling.
+    let isolated_debt = if bank_collateral.isolated {
+        let mint_collateral_key = ctx.accounts.mint_collateral.key();
+        require!(
+            extra_collaterals.is_empty()
+                && health.collateral_mints.iter().all(|mint| *mint == mint_collateral_key),
+            ErrorCode::IsolatedCollateralMixed
+        );
+        require!(
+            bank_collateral.isolated_borrow_mints.contains(&ctx.accounts.mint_borrow.key()),
+            ErrorCode::IsolatedBorrowMintNotAllowed
+        );
+        let isolated_debt = to_usd_units(borrow_value)?;
+        let total_isolated_debt = bank_collateral.isolated_debt
+            .checked_add(isolated_debt)
+            .ok_or(ErrorCode::MathOverflow)?;
+        debug_msg!("Isolated debt after borrow: {} (ceiling {})", total_isolated_debt, bank_collateral.isolated_debt_ceiling);
+        require!(
+            total_isolated_debt <= bank_collateral.isolated_debt_ceiling,
+            ErrorCode::IsolatedDebtCeilingExceeded
+        );
+        isolated_debt
+    } else {
+        require!(
+            !health.has_isolated_collateral
+                && extra_collaterals.iter().all(|collateral| !collateral.bank.isolated),
+            ErrorCode::IsolatedCollateralMixed
+        );
+        0
+    };
 
-    let available_liquidity = bank_borrow_total_assets
-        .checked_sub(bank_borrow_total_borrowed_assets)
-        .ok_or(ErrorCode::MathOverflow)?;
-    msg!("Available liquidity in bank: {}", available_liquidity);
+    // Whatever is not lent out sits in the treasury, minus uncollected fees.
+    let available_liquidity = available_liquidity(bank_borrow, ctx.accounts.bank_borrow_token_account.amount);
+    debug_msg!("Available liquidity in bank: {}", available_liquidity);
     
-    if (amount as u128) > available_liquidity {
-        msg!("ERROR: Insufficient liquidity. Requested {} but only {} available", amount, available_liquidity);
+    if amount > available_liquidity {
+        debug_msg!("ERROR: Insufficient liquidity. Requested {} but only {} available", amount, available_liquidity);
         return Err(ErrorCode::InsufficientLiquidity.into());
     }
-    msg!("Liquidity check passed: {} <= {}", amount, available_liquidity);
-
-    // Calculate locked collateral shares using ceil to avoid rounding down.
-    let collateral_shares_to_lock = ((borrow_value * 
-        (10u128.pow(ctx.accounts.mint_collateral.decimals as u32) as f64) * 
-        (10u128.pow((-1 * collateral_price.exponent) as u32) as f64))
-        / (collateral_price.price as f64)).ceil() as u64;
-    msg!("Collateral shares to lock: {}", collateral_shares_to_lock);
-    msg!("User has {} deposited shares available", user_collateral.deposited_shares);
-    
-    if collateral_shares_to_lock > user_collateral.deposited_shares {
-        msg!("ERROR: Not enough collateral shares. Need {} but only have {}", 
-            collateral_shares_to_lock, user_collateral.deposited_shares);
+    debug_msg!("Liquidity check passed: {} <= {}", amount, available_liquidity);
+
+    // Lock enough collateral to back the borrow at `max_ltv` (the e-mode
+    // category's, if any), so a fresh position sits below the liquidation
+    // threshold. `mint_collateral` is drawn on first, then each extra
+    // collateral in order. Round up so the protocol never locks less
+    // collateral than required.
+    drop(known_assets);
+    let mut remaining_credit = borrow_value;
+    let free_collateral_shares = free_deposit_shares(user_collateral, ctx.accounts.user_collateral_receipt_account.amount);
+    debug_msg!("User has {} deposited shares available", free_collateral_shares);
+    let collateral_shares_to_lock = collateral_shares_for_credit(
+        bank_collateral,
+        &collateral_price,
+        risk_params(bank_collateral, emode).max_ltv,
+        free_collateral_shares,
+        &mut remaining_credit,
+        Rounding::Up,
+    )?;
+    debug_msg!("Collateral shares to lock: {}", collateral_shares_to_lock);
+    let mut extra_collateral_locked = Vec::with_capacity(extra_collaterals.len());
+    for collateral in extra_collaterals.iter() {
+        let shares = collateral_shares_for_credit(
+            &collateral.bank,
+            &collateral.price,
+            risk_params(&collateral.bank, emode).max_ltv,
+            free_deposit_shares(&collateral.user_state, collateral.receipt_account.amount),
+            &mut remaining_credit,
+            Rounding::Up,
+        )?;
+        debug_msg!("Extra collateral {}: locking {} shares", collateral.bank.mint_address, shares);
+        extra_collateral_locked.push(PositionCollateral { mint: collateral.bank.mint_address, shares });
+    }
+
+    if remaining_credit > Decimal::zero() {
+        debug_msg!("ERROR: Not enough free collateral. {} of borrow value left uncovered", remaining_credit);
         return Err(ErrorCode::InsufficientCollateral.into());
     }
 
-    let new_shares = if bank_borrow.total_borrowed_shares == 0 {
-        msg!("First borrow in the bank, setting borrowed shares equal to amount");
-        amount
-    } else {
-        msg!("Calculating borrowed shares based on existing shares and assets");
-        let total_borrowed = calculate_borrowed_assets(bank_borrow);
-        msg!("Total borrowed assets: {}", total_borrowed);
-        msg!("Total borrowed shares: {}", bank_borrow.total_borrowed_shares);
-        let shares = (amount as u128)
-            .checked_mul(bank_borrow.total_borrowed_shares as u128)
-            .and_then(|v| v.checked_div(total_borrowed))
-            .ok_or(ErrorCode::MathOverflow)? as u64;
-        msg!("Calculated new shares: {}", shares);
-        shares
-    };
-    msg!("Minting {} borrow shares for amount {}", new_shares, amount);
+    // Round up so the borrower owes at least what they received.
+    debug_msg!("Calculating borrowed shares at borrow index {}", Decimal::from_scaled_val(bank_borrow.borrow_index));
+    let new_shares = calculate_borrow_shares(bank_borrow, amount, Rounding::Up)?;
+    debug_msg!("Minting {} borrow shares for amount {}", new_shares, amount);
+
+    // Enforce the borrow cap on the bank's total debt after this borrow.
+    if bank_borrow.borrow_cap > 0 {
+        let borrowed_shares = bank_borrow.total_borrowed_shares
+            .checked_add(new_shares)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let total_borrowed = calculate_debt_amount(bank_borrow, borrowed_shares, Rounding::Up)?;
+        debug_msg!("Total borrowed after borrow: {} (cap {})", total_borrowed, bank_borrow.borrow_cap);
+        require!(total_borrowed <= bank_borrow.borrow_cap, ErrorCode::BorrowCapExceeded);
+    }
 
-    msg!("Executing token transfer from bank to user");
-    msg!("Bank token account: {}", ctx.accounts.bank_borrow_token_account.key());
-    msg!("User token account: {}", ctx.accounts.user_borrow_token_account.key());
+    debug_msg!("Executing token transfer from bank to user");
+    debug_msg!("Bank token account: {}", ctx.accounts.bank_borrow_token_account.key());
+    debug_msg!("User token account: {}", ctx.accounts.user_borrow_token_account.key());
     let mint_borrow_key = ctx.accounts.mint_borrow.key();
     let signer_seds: &[&[&[u8]]] = &[&[
         b"treasury",
@@ -310,85 +470,129 @@ pub fn process_borrow(ctx: Context<Borrow>, position_id: u64, amount: u64) -> Re
         },
         signer_seds,
     );
-    msg!("Transferring {} tokens with {} decimals", amount, ctx.accounts.mint_borrow.decimals);
+    debug_msg!("Transferring {} tokens with {} decimals", amount, ctx.accounts.mint_borrow.decimals);
     transfer_checked(transfer_ctx, amount, ctx.accounts.mint_borrow.decimals)?;
-    msg!("Token transfer successful");
+    debug_msg!("Token transfer successful");
 
-    msg!("Updating bank borrow state");
-    msg!("  Old total borrowed shares: {}", bank_borrow.total_borrowed_shares);
+    debug_msg!("Updating bank borrow state");
+    debug_msg!("  Old total borrowed shares: {}", bank_borrow.total_borrowed_shares);
     bank_borrow.total_borrowed_shares = bank_borrow.total_borrowed_shares.checked_add(new_shares).ok_or(ErrorCode::MathOverflow)?;
-    msg!("  New total borrowed shares: {}", bank_borrow.total_borrowed_shares);
+    debug_msg!("  New total borrowed shares: {}", bank_borrow.total_borrowed_shares);
     
-    msg!("Updating user borrow state");
-    msg!("  Old borrowed shares: {}", user_borrow.borrowed_shares);
+    debug_msg!("Updating user borrow state");
+    debug_msg!("  Old borrowed shares: {}", user_borrow.borrowed_shares);
     user_borrow.borrowed_shares = user_borrow.borrowed_shares.checked_add(new_shares).ok_or(ErrorCode::MathOverflow)?;
-    msg!("  New borrowed shares: {}", user_borrow.borrowed_shares);
+    debug_msg!("  New borrowed shares: {}", user_borrow.borrowed_shares);
     user_borrow.last_updated_borrowed = current_time;
-    msg!("  Updated last_updated_borrowed to: {}", user_borrow.last_updated_borrowed);
-
-    msg!("Updating bank collateral state");
-    msg!("  Old total collateral shares: {}", bank_collateral.total_collateral_shares);
-    msg!("  Old total deposited shares: {}", bank_collateral.total_deposited_shares);
-    bank_collateral.total_collateral_shares = bank_collateral.total_collateral_shares.checked_add(collateral_shares_to_lock).ok_or(ErrorCode::MathOverflow)?;
-    bank_collateral.total_deposited_shares = bank_collateral.total_deposited_shares.checked_sub(collateral_shares_to_lock).ok_or(ErrorCode::MathOverflow)?;
-    msg!("  New total collateral shares: {}", bank_collateral.total_collateral_shares);
-    msg!("  New total deposited shares: {}", bank_collateral.total_deposited_shares);
-
-    msg!("Updating user collateral state");
-    msg!("  Old deposited shares: {}", user_collateral.deposited_shares);
-    msg!("  Old collateral shares: {}", user_collateral.collateral_shares);
-    user_collateral.deposited_shares = user_collateral.deposited_shares.checked_sub(collateral_shares_to_lock).ok_or(ErrorCode::MathOverflow)?;
-    user_collateral.last_updated_deposited = current_time;
-    user_collateral.last_updated_collateral = current_time;
-    user_collateral.collateral_shares = user_collateral.collateral_shares.checked_add(collateral_shares_to_lock).ok_or(ErrorCode::MathOverflow)?;
-    msg!("  New deposited shares: {}", user_collateral.deposited_shares);
-    msg!("  New collateral shares: {}", user_collateral.collateral_shares);
-    msg!("  Updated timestamps to: {}", current_time);
-
-    msg!("Updating borrow position");
+    debug_msg!("  Updated last_updated_borrowed to: {}", user_borrow.last_updated_borrowed);
+
+    // Locked shares can no longer move, so their receipts are burned.
+    debug_msg!("Burning {} collateral receipt tokens", collateral_shares_to_lock);
+    burn(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Burn {
+                mint: ctx.accounts.receipt_mint_collateral.to_account_info(),
+                from: ctx.accounts.user_collateral_receipt_account.to_account_info(),
+                authority: ctx.accounts.signer.to_account_info(),
+            },
+        ),
+        collateral_shares_to_lock,
+    )?;
+
+    debug_msg!("Updating bank collateral state");
+    bank_collateral.isolated_debt = bank_collateral.isolated_debt.checked_add(isolated_debt).ok_or(ErrorCode::MathOverflow)?;
+    debug_msg!("  Old total collateral shares: {}", bank_collateral.total_collateral_shares);
+    debug_msg!("  Old total deposited shares: {}", bank_collateral.total_deposited_shares);
+    debug_msg!("  Old user deposited shares: {}", user_collateral.deposited_shares);
+    debug_msg!("  Old user collateral shares: {}", user_collateral.collateral_shares);
+    lock_collateral_shares(bank_collateral, user_collateral, collateral_shares_to_lock, current_time)?;
+    debug_msg!("  New total collateral shares: {}", bank_collateral.total_collateral_shares);
+    debug_msg!("  New total deposited shares: {}", bank_collateral.total_deposited_shares);
+    debug_msg!("  New user deposited shares: {}", user_collateral.deposited_shares);
+    debug_msg!("  New user collateral shares: {}", user_collateral.collateral_shares);
+
+    for (collateral, locked) in extra_collaterals.iter_mut().zip(extra_collateral_locked.iter()) {
+        if locked.shares > 0 {
+            collateral.lock(locked.shares, &ctx.accounts.signer.to_account_info(), &ctx.accounts.token_program, current_time)?;
+        }
+    }
+    extra_collateral_locked.retain(|locked| locked.shares > 0);
+
+    debug_msg!("Updating borrow position");
     let position = &mut ctx.accounts.borrow_position;
-    position.position_id = position_id;
-    position.owner = ctx.accounts.signer.key();
-    position.collateral_mint = ctx.accounts.mint_collateral.key();
-    position.borrow_mint = ctx.accounts.mint_borrow.key();
-    position.collateral_shares = collateral_shares_to_lock;
-    msg!("  Old borrowed shares in position: {}", position.borrowed_shares);
+    if extending {
+        add_position_collateral(position, ctx.accounts.mint_collateral.key(), collateral_shares_to_lock)?;
+        position.isolated_debt = position.isolated_debt.checked_add(isolated_debt).ok_or(ErrorCode::MathOverflow)?;
+    } else {
+        position.position_id = position_id;
+        position.owner = ctx.accounts.signer.key();
+        position.collateral_mint = ctx.accounts.mint_collateral.key();
+        position.borrow_mint = ctx.accounts.mint_borrow.key();
+        position.collaterals = vec![PositionCollateral {
+            mint: ctx.accounts.mint_collateral.key(),
+            shares: collateral_shares_to_lock,
+        }];
+        position.active = true;
+        position.isolated_debt = isolated_debt;
+    }
+    for locked in extra_collateral_locked.iter() {
+        add_position_collateral(position, locked.mint, locked.shares)?;
+    }
+    debug_msg!("  Old borrowed shares in position: {}", position.borrowed_shares);
     position.borrowed_shares = position.borrowed_shares.checked_add(new_shares).ok_or(ErrorCode::MathOverflow)?;
-    msg!("  New borrowed shares in position: {}", position.borrowed_shares);
+    debug_msg!("  New borrowed shares in position: {}", position.borrowed_shares);
     position.last_updated = current_time;
-    position.active = true;
-
-    msg!("Borrow position details:");
-    msg!("  Owner: {:?}", position.owner);
-    msg!("  Collateral mint: {:?}", position.collateral_mint);
-    msg!("  Borrow mint: {:?}", position.borrow_mint);
-    msg!("  Collateral shares: {:?}", position.collateral_shares);
-    msg!("  Borrowed shares: {:?}", position.borrowed_shares);
-    msg!("  Last updated: {:?}", position.last_updated);
-    msg!("  Active: {:?}", position.active);
-
-    msg!("Updating user global state");
+
+    debug_msg!("Borrow position details:");
+    debug_msg!("  Owner: {:?}", position.owner);
+    debug_msg!("  Collateral mint: {:?}", position.collateral_mint);
+    debug_msg!("  Borrow mint: {:?}", position.borrow_mint);
+    debug_msg!("  Collaterals: {:?}", position.collaterals);
+    debug_msg!("  Borrowed shares: {:?}", position.borrowed_shares);
+    debug_msg!("  Last updated: {:?}", position.last_updated);
+    debug_msg!("  Active: {:?}", position.active);
+
+    debug_msg!("Updating user global state");
     let global_state = &mut ctx.accounts.user_global_state;
-    msg!("  Current active positions: {:?}", global_state.active_positions);
+    debug_msg!("  Current active positions: {:?}", global_state.active_positions);
     if !global_state.active_positions.contains(&ctx.accounts.borrow_position.key()) {
         global_state.active_positions.push(ctx.accounts.borrow_position.key());
-        msg!("  Added position to active positions");
+        debug_msg!("  Added position to active positions");
     } else {
-        msg!("  Position already in active positions");
+        debug_msg!("  Position already in active positions");
+    }
+    if !extending {
+        global_state.positions += 1;
     }
-    global_state.positions += 1;
-    msg!("  Updated active positions: {:?}", global_state.active_positions);
-
-    msg!("Borrow successful");
-    msg!("Summary:");
-    msg!("  Borrowed amount: {}", amount);
-    msg!("  Borrowed shares: {}", new_shares);
-    msg!("  Locked collateral shares: {}", collateral_shares_to_lock);
-    msg!("  Borrow value in USD: {}", borrow_value);
-    msg!("  New total debt value in USD: {}", total_debt_value);
-    msg!("  Max allowed debt value in USD: {}", max_borrow_value);
-    msg!("  Current LTV: {}%", (total_debt_value / collateral_value) * 100.0);
-    msg!("  Max LTV: {}%", bank_collateral.max_ltv as f64 / 100.0);
+    debug_msg!("  Updated active positions: {:?}", global_state.active_positions);
+
+    emit_cpi!(Borrowed {
+        user: ctx.accounts.signer.key(),
+        position: ctx.accounts.borrow_position.key(),
+        bank_borrow: bank_borrow.key(),
+        bank_collateral: bank_collateral.key(),
+        amount,
+        borrow_shares: new_shares,
+        collateral_shares_locked: collateral_shares_to_lock,
+        extra_collateral_locked,
+        borrow_index: bank_borrow.borrow_index,
+        borrow_price: borrow_price.price,
+        borrow_price_exponent: borrow_price.exponent,
+        collateral_price: collateral_price.price,
+        collateral_price_exponent: collateral_price.exponent,
+        timestamp: current_time,
+    });
+
+    debug_msg!("Borrow successful");
+    debug_msg!("Summary:");
+    debug_msg!("  Borrowed amount: {}", amount);
+    debug_msg!("  Borrowed shares: {}", new_shares);
+    debug_msg!("  Locked collateral shares: {}", collateral_shares_to_lock);
+    debug_msg!("  Borrow value in USD: {}", borrow_value);
+    debug_msg!("  New total debt value in USD: {}", total_debt_value);
+    debug_msg!("  Max allowed debt value in USD: {}", max_borrow_value);
+    debug_msg!("  Max LTV: {} bps", bank_collateral.max_ltv);
     
     Ok(())
 }
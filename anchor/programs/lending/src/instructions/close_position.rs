@@ -0,0 +1,52 @@
+use anchor_lang::prelude::*;
+
+use crate::error::ErrorCode;
+use crate::state::{BorrowPosition, UserGlobalState};
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct ClosePosition<'info> {
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        mut,
+        close = signer,
+        seeds = [
+            b"position",
+            signer.key().as_ref(),
+            borrow_position.collateral_mint.as_ref(),
+            borrow_position.borrow_mint.as_ref(),
+            &position_id.to_le_bytes(),
+        ],
+        bump,
+    )]
+    pub borrow_position: Box<Account<'info, BorrowPosition>>,
+
+    #[account(
+        mut,
+        seeds = [b"user_global", signer.key().as_ref()],
+        bump,
+    )]
+    pub user_global_state: Box<Account<'info, UserGlobalState>>,
+}
+
+/// Closes position `position_id` once it holds neither debt nor collateral
+/// and refunds its rent to the signer. The position id can be reused after.
+pub fn process_close_position(ctx: Context<ClosePosition>, position_id: u64) -> Result<()> {
+    let position = &ctx.accounts.borrow_position;
+    debug_msg!("Closing position {}: {:?}", position_id, position.key());
+    debug_msg!("  Borrowed shares: {}", position.borrowed_shares);
+    debug_msg!("  Collaterals: {:?}", position.collaterals);
+    require!(
+        position.borrowed_shares == 0
+            && position.collaterals.iter().all(|collateral| collateral.shares == 0),
+        ErrorCode::AccountNotEmpty
+    );
+
+    let position_key = position.key();
+    let global_state = &mut ctx.accounts.user_global_state;
+    global_state.active_positions.retain(|pos| *pos != position_key);
+    debug_msg!("  Updated active positions: {:?}", global_state.active_positions);
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+use crate::error::ErrorCode;
+use crate::state::{UserGlobalState, UserTokenState};
+use crate::utils::reconcile_deposited_shares;
+
+#[derive(Accounts)]
+#[instruction(mint_address: Pubkey)]
+pub struct CloseUserTokenState<'info> {
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        mut,
+        close = signer,
+        seeds = [signer.key().as_ref(), mint_address.as_ref()],
+        bump,
+    )]
+    pub user_account: Box<Account<'info, UserTokenState>>,
+
+    #[account(
+        mut,
+        seeds = [b"user_global", signer.key().as_ref()],
+        bump,
+    )]
+    pub user_global_state: Box<Account<'info, UserGlobalState>>,
+
+    #[account(
+        seeds = [b"receipt", mint_address.as_ref()],
+        bump,
+    )]
+    pub receipt_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        associated_token::mint = receipt_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program,
+    )]
+    pub user_receipt_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+/// Closes the signer's token state for `mint_address` once it has no
+/// deposits, locked collateral or debt, drops the mint from
+/// `deposited_mints` and refunds the rent to the signer. Free deposits count
+/// only as far as the signer still holds their receipts; receipt tokens held
+/// elsewhere can still be redeemed without the state.
+pub fn process_close_user_token_state(ctx: Context<CloseUserTokenState>, mint_address: Pubkey) -> Result<()> {
+    reconcile_deposited_shares(&mut ctx.accounts.user_account, ctx.accounts.user_receipt_account.amount);
+    let user = &ctx.accounts.user_account;
+    debug_msg!("Closing user token state for mint {}", mint_address);
+    debug_msg!("  Deposited shares: {}", user.deposited_shares);
+    debug_msg!("  Collateral shares: {}", user.collateral_shares);
+    debug_msg!("  Borrowed shares: {}", user.borrowed_shares);
+    require!(
+        user.deposited_shares == 0 && user.collateral_shares == 0 && user.borrowed_shares == 0,
+        ErrorCode::AccountNotEmpty
+    );
+
+    let global_state = &mut ctx.accounts.user_global_state;
+    global_state.deposited_mints.retain(|mint| *mint != mint_address);
+    debug_msg!("  Updated deposited mints: {:?}", global_state.deposited_mints);
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface, TransferChecked, transfer_checked};
+
+use crate::state::{Bank, ProtocolConfig};
+use crate::error::ErrorCode;
+use crate::events::FeesCollected;
+use crate::utils::accrue_interest;
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct CollectFees<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = protocol_config.admin == authority.key() @ ErrorCode::Unauthorized,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        seeds = [mint.key().as_ref()],
+        bump,
+        constraint = !bank.flash_loan_active @ ErrorCode::BankLocked,
+    )]
+    pub bank: Box<Account<'info, Bank>>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = bank_token_account,
+        seeds = [b"treasury", mint.key().as_ref()],
+        bump,
+    )]
+    pub bank_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        constraint = fee_recipient_token_account.owner == bank.fee_recipient @ ErrorCode::InvalidFeeRecipient,
+    )]
+    pub fee_recipient_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+pub fn process_collect_fees(ctx: Context<CollectFees>) -> Result<()> {
+    debug_msg!("Collecting fees for bank: {}", ctx.accounts.bank.key());
+
+    // Book any reserve-factor interest owed up to now before collecting.
+    let current_time = Clock::get()?.unix_timestamp;
+    let treasury_balance = ctx.accounts.bank_token_account.amount;
+    if let Some(event) = accrue_interest(&mut ctx.accounts.bank, treasury_balance, current_time)? {
+        emit_cpi!(event);
+    }
+
+    // Reserve interest is booked as it accrues but only reaches the treasury
+    // once borrowers repay, so never collect more than is actually held.
+    let amount = ctx.accounts.bank.accumulated_fees.min(treasury_balance);
+    require!(amount > 0, ErrorCode::NoFeesToCollect);
+    debug_msg!("Accumulated fees: {}", ctx.accounts.bank.accumulated_fees);
+    debug_msg!("Treasury balance: {}", treasury_balance);
+
+    ctx.accounts.bank.accumulated_fees = ctx.accounts.bank.accumulated_fees
+        .checked_sub(amount)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    let mint_key = ctx.accounts.mint.key();
+    let signer_seeds: &[&[&[u8]]] = &[
+        &[
+            b"treasury",
+            mint_key.as_ref(),
+            &[ctx.bumps.bank_token_account],
+        ]
+    ];
+    let transfer_ctx = CpiContext::new_with_signer(
+        ctx.accounts.token_program.to_account_info(),
+        TransferChecked {
+            authority: ctx.accounts.bank_token_account.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            from: ctx.accounts.bank_token_account.to_account_info(),
+            to: ctx.accounts.fee_recipient_token_account.to_account_info(),
+        },
+        signer_seeds,
+    );
+    transfer_checked(transfer_ctx, amount, ctx.accounts.mint.decimals)?;
+
+    debug_msg!("Collected {} tokens to {}", amount, ctx.accounts.fee_recipient_token_account.key());
+    debug_msg!("Remaining accumulated fees: {}", ctx.accounts.bank.accumulated_fees);
+
+    emit_cpi!(FeesCollected {
+        bank: ctx.accounts.bank.key(),
+        mint: mint_key,
+        authority: ctx.accounts.authority.key(),
+        recipient_token_account: ctx.accounts.fee_recipient_token_account.key(),
+        amount,
+        accumulated_fees: ctx.accounts.bank.accumulated_fees,
+        timestamp: current_time,
+    });
+    Ok(())
+}
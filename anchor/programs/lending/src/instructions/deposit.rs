@@ -1,11 +1,17 @@
 use anchor_lang::prelude::*;
-use anchor_spl::{associated_token::AssociatedToken, token_interface::{Mint, TokenAccount, TokenInterface, TransferChecked, transfer_checked}};
+use anchor_spl::{associated_token::AssociatedToken, token_interface::{Mint, MintTo, TokenAccount, TokenInterface, TransferChecked, mint_to, transfer_checked}};
 use crate::state::Bank;
 use crate::error::ErrorCode;
+use crate::events::Deposited;
 use crate::state::UserTokenState;
 use crate::state::UserGlobalState;
+use crate::state::AmountMode;
+use crate::state::ProtocolConfig;
+use crate::constants::PAUSE_DEPOSIT;
 use crate::utils::*;
+use crate::math::{Decimal, Rounding};
 
+#[event_cpi]
 #[derive(Accounts)]
 pub struct Deposit<'info> {
     #[account(mut)]
@@ -17,6 +23,7 @@ pub struct Deposit<'info> {
         mut,
         seeds = [mint.key().as_ref()],
         bump,
+        constraint = !bank.flash_loan_active @ ErrorCode::BankLocked,
     )]
     pub bank: Account<'info, Bank>,
 
@@ -45,7 +52,29 @@ pub struct Deposit<'info> {
     )]
     pub user_token_account: InterfaceAccount<'info, TokenAccount>,
 
+    #[account(
+        mut,
+        seeds = [b"receipt", mint.key().as_ref()],
+        bump,
+    )]
+    pub receipt_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init_if_needed,
+        payer = signer,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program,
+    )]
+    pub user_receipt_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
     pub associated_token_program: Program<'info, AssociatedToken>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
     pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
 
@@ -57,18 +86,60 @@ pub struct Deposit<'info> {
     pub user_global_state: Account<'info, UserGlobalState>,
 }
 
-pub fn process_deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
-    // For tokens like SOL with 9 decimals, amount should be passed as 3*10^9 for 3 SOL
-    // This ensures proper decimal handling when interacting with token accounts
-    // Example: 3 SOL = 3_000_000_000 lamports
-    msg!("Processing deposit of {} tokens", amount);
-    msg!("User: {}", ctx.accounts.signer.key());
-    msg!("Mint: {}", ctx.accounts.mint.key());
-    msg!("Bank: {}", ctx.accounts.bank.key());
+pub fn process_deposit(ctx: Context<Deposit>, mode: AmountMode) -> Result<()> {
+    require_not_paused(&ctx.accounts.protocol_config, &ctx.accounts.bank, PAUSE_DEPOSIT)?;
+
+    debug_msg!("Processing deposit: {:?}", mode);
+    debug_msg!("User: {}", ctx.accounts.signer.key());
+    debug_msg!("Mint: {}", ctx.accounts.mint.key());
+    debug_msg!("Bank: {}", ctx.accounts.bank.key());
+
+    // Treasury balance before this deposit, so utilization reflects the
+    // period that is being accrued.
+    let liquidity = ctx.accounts.bank_token_account.amount;
+    let bank = &mut ctx.accounts.bank;
+    let user = &mut ctx.accounts.user_account;
+    
+    debug_msg!("Bank state before interest accrual:");
+    debug_msg!("  Total deposited shares: {}", bank.total_deposited_shares);
+    debug_msg!("  Last compound time: {}", bank.last_compound_time);
+    debug_msg!("  Interest accrual period: {}", bank.interest_accrual_period);
+    debug_msg!("  Supply index: {}", Decimal::from_scaled_val(bank.supply_index));
     
-    msg!("Initiating token transfer from user to bank");
-    msg!("User token account: {}", ctx.accounts.user_token_account.key());
-    msg!("Bank token account: {}", ctx.accounts.bank_token_account.key());
+    debug_msg!("User state before update:");
+    debug_msg!("  Deposited shares: {}", user.deposited_shares);
+    debug_msg!("  Last updated deposited: {}", user.last_updated_deposited);
+    
+    // Accrue interest before calculating shares
+    let current_time = Clock::get()?.unix_timestamp;
+    debug_msg!("Current timestamp: {}", current_time);
+    debug_msg!("Accruing interest for bank");
+    if let Some(event) = accrue_interest(bank, liquidity, current_time)? {
+        emit_cpi!(event);
+    }
+    debug_msg!("Interest accrual completed");
+    debug_msg!("Bank state after interest accrual:");
+    debug_msg!("  Supply index: {}", Decimal::from_scaled_val(bank.supply_index));
+    debug_msg!("  Last compound time: {}", bank.last_compound_time);
+
+    // Amounts are in base units, e.g. 3 SOL = 3_000_000_000 lamports.
+    let amount = match mode {
+        AmountMode::Tokens(amount) => amount,
+        // Gross up for the deposit fee and the mint's transfer fee so at
+        // least `shares` are credited.
+        AmountMode::Shares(shares) => {
+            let net_amount = calculate_deposit_amount(bank, shares, Rounding::Up)?;
+            let received = calculate_amount_before_fee(net_amount, bank.deposit_fee)?;
+            amount_with_transfer_fee(&ctx.accounts.mint.to_account_info(), received)?
+        }
+        AmountMode::Max => ctx.accounts.user_token_account.amount,
+    };
+    require!(amount > 0, ErrorCode::InvalidDepositAmount);
+    debug_msg!("Deposit amount: {}", amount);
+
+    debug_msg!("Initiating token transfer from user to bank");
+    debug_msg!("User token account: {}", ctx.accounts.user_token_account.key());
+    debug_msg!("Bank token account: {}", ctx.accounts.bank_token_account.key());
     
     let transfer_cpi_accounts = TransferChecked {
         authority: ctx.accounts.signer.to_account_info(),
@@ -77,141 +148,106 @@ pub fn process_deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
         to: ctx.accounts.bank_token_account.to_account_info(),
     };
     let cpi_program = ctx.accounts.token_program.to_account_info();
-    msg!("Executing transfer of {} tokens with {} decimals", amount, ctx.accounts.mint.decimals);
-    let _res = transfer_checked(
+    debug_msg!("Executing transfer of {} tokens with {} decimals", amount, ctx.accounts.mint.decimals);
+    transfer_checked(
         CpiContext::new(cpi_program, transfer_cpi_accounts),
-        amount, // amount should include decimals (e.g. 3*10^9 for 3 SOL)
+        amount,
         ctx.accounts.mint.decimals
-    );
-    msg!("Transfer completed successfully");
-
-    let bank = &mut ctx.accounts.bank;
-    let user = &mut ctx.accounts.user_account;
-    
-    msg!("Bank state before interest accrual:");
-    msg!("  Total deposited shares: {}", bank.total_deposited_shares);
-    msg!("  Last compound time: {}", bank.last_compound_time);
-    msg!("  Interest accrual period: {}", bank.interest_accrual_period);
-    msg!("  Deposit interest rate: {}", bank.deposit_interest_rate);
-    
-    msg!("User state before update:");
-    msg!("  Deposited shares: {}", user.deposited_shares);
-    msg!("  Last updated deposited: {}", user.last_updated_deposited);
-    
-    // Accrue interest before calculating shares
-    let current_time = Clock::get()?.unix_timestamp;
-    msg!("Current timestamp: {}", current_time);
-    msg!("Accruing interest for bank");
-    accrue_interest(bank, current_time)?;
-    msg!("Interest accrual completed");
-    msg!("Bank state after interest accrual:");
-    msg!("  Total deposited shares: {}", bank.total_deposited_shares);
-    msg!("  Last compound time: {}", bank.last_compound_time);
-
-    // Calculate shares using precise decimal math
+    )?;
+    debug_msg!("Transfer completed successfully");
+
+    // Mints with a transfer fee deliver less than `amount`, so only what the
+    // treasury actually received is credited.
+    ctx.accounts.bank_token_account.reload()?;
+    let received = ctx.accounts.bank_token_account.amount
+        .checked_sub(liquidity)
+        .ok_or(ErrorCode::MathOverflow)?;
+    require!(received > 0, ErrorCode::InvalidDepositAmount);
+    debug_msg!("Treasury received: {}", received);
+
+    // The deposit fee stays in the treasury as protocol revenue; only the
+    // remainder is credited to the depositor.
+    let fee = calculate_fee(received, bank.deposit_fee)?;
+    let net_amount = received.checked_sub(fee).ok_or(ErrorCode::MathOverflow)?;
+    bank.accumulated_fees = bank.accumulated_fees.checked_add(fee).ok_or(ErrorCode::MathOverflow)?;
+    debug_msg!("Deposit fee: {} ({} bps), net amount: {}", fee, bank.deposit_fee, net_amount);
+
+    // Calculate shares at the current supply index, rounding down so the
+    // depositor never receives shares worth more than they paid in.
     // amount is already in smallest units (e.g. lamports for SOL)
-    msg!("Calculating shares for deposit");
-    let deposited_shares = if bank.total_deposited_shares == 0 {
-        msg!("First deposit in the bank, setting deposited shares equal to amount");
-        amount
-    } else {
-        msg!("Calculating deposited shares based on existing shares and assets");
-        let total_assets = calculate_total_deposited_assets(bank);
-        msg!("Total deposited assets: {}", total_assets);
-        msg!("Total deposited shares: {}", bank.total_deposited_shares);
-        msg!("Calculating new shares: {} * {} / {}", amount, bank.total_deposited_shares, total_assets);
-        (amount as u128)
-            .checked_mul(bank.total_deposited_shares as u128)
-            .and_then(|v| v.checked_div(total_assets))
-            .ok_or(ErrorCode::MathOverflow)? as u64
-    };
-    msg!("Calculated deposited shares: {}", deposited_shares);
+    debug_msg!("Calculating shares for deposit at supply index {}", Decimal::from_scaled_val(bank.supply_index));
+    let deposited_shares = calculate_deposit_shares(bank, net_amount, Rounding::Down)?;
+    require!(deposited_shares > 0, ErrorCode::InvalidDepositAmount);
+    debug_msg!("Calculated deposited shares: {}", deposited_shares);
+
+    // Enforce the supply cap on everything supplied after this deposit.
+    if bank.supply_cap > 0 {
+        let supply_shares = total_supply_shares(bank)?
+            .checked_add(deposited_shares)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let total_supplied = calculate_deposit_amount(bank, supply_shares, Rounding::Up)?;
+        debug_msg!("Total supplied after deposit: {} (cap {})", total_supplied, bank.supply_cap);
+        require!(total_supplied <= bank.supply_cap, ErrorCode::SupplyCapExceeded);
+    }
+
+    // Hand out the new shares as receipt tokens, signed by the bank PDA.
+    debug_msg!("Minting {} receipt tokens to {}", deposited_shares, ctx.accounts.user_receipt_account.key());
+    let mint_key = ctx.accounts.mint.key();
+    let bank_seeds: &[&[&[u8]]] = &[&[mint_key.as_ref(), &[ctx.bumps.bank]]];
+    mint_to(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            MintTo {
+                mint: ctx.accounts.receipt_mint.to_account_info(),
+                to: ctx.accounts.user_receipt_account.to_account_info(),
+                authority: bank.to_account_info(),
+            },
+            bank_seeds,
+        ),
+        deposited_shares,
+    )?;
 
     // Update state with new shares
-    msg!("Updating bank state - adding {} shares", deposited_shares);
+    debug_msg!("Updating bank state - adding {} shares", deposited_shares);
     bank.total_deposited_shares = bank.total_deposited_shares.checked_add(deposited_shares).ok_or(ErrorCode::MathOverflow)?;
-    msg!("Updating user state - adding {} shares", deposited_shares);
+    debug_msg!("Updating user state - adding {} shares", deposited_shares);
     user.deposited_shares = user.deposited_shares.checked_add(deposited_shares).ok_or(ErrorCode::MathOverflow)?;
     user.last_updated_deposited = current_time;
-    msg!("Updated user timestamp to: {}", current_time);
+    debug_msg!("Updated user timestamp to: {}", current_time);
 
-    msg!("Bank total deposited shares: {}", bank.total_deposited_shares);
-    msg!("User deposited shares: {}", user.deposited_shares);
+    debug_msg!("Bank total deposited shares: {}", bank.total_deposited_shares);
+    debug_msg!("User deposited shares: {}", user.deposited_shares);
 
     // Update global state
-    msg!("Updating user global state");
+    debug_msg!("Updating user global state");
     let global_state = &mut ctx.accounts.user_global_state;
-    msg!("Current deposited mints: {:?}", global_state.deposited_mints);
+    debug_msg!("Current deposited mints: {:?}", global_state.deposited_mints);
     if !global_state.deposited_mints.contains(&ctx.accounts.mint.key()) {
         global_state.deposited_mints.push(ctx.accounts.mint.key());
-        msg!("Added mint to deposited mints");
+        debug_msg!("Added mint to deposited mints");
     } else {
-        msg!("Mint already in deposited mints");
+        debug_msg!("Mint already in deposited mints");
     }
-    msg!("Updated deposited mints: {:?}", global_state.deposited_mints);
-
-    msg!("Deposit successful");
-    msg!("Summary:");
-    msg!("  Deposited amount: {}", amount);
-    msg!("  Received shares: {}", deposited_shares);
-    msg!("  User total shares: {}", user.deposited_shares);
-    msg!("  Bank total shares: {}", bank.total_deposited_shares);
-    
-    Ok(())
-}
-
-// Helper function to calculate compound interest
-fn accrue_interest(bank: &mut Account<Bank>, current_time: i64) -> Result<()> {
-    msg!("Calculating interest accrual periods");
-    let periods = (current_time - bank.last_compound_time) / bank.interest_accrual_period;
-    msg!("Time since last compound: {} seconds", current_time - bank.last_compound_time);
-    msg!("Interest accrual periods: {}", periods);
+    debug_msg!("Updated deposited mints: {:?}", global_state.deposited_mints);
+
+    emit_cpi!(Deposited {
+        user: ctx.accounts.signer.key(),
+        bank: bank.key(),
+        mint: bank.mint_address,
+        amount: received,
+        fee,
+        shares: deposited_shares,
+        supply_index: bank.supply_index,
+        timestamp: current_time,
+    });
+
+    debug_msg!("Deposit successful");
+    debug_msg!("Summary:");
+    debug_msg!("  Deposited amount: {}", amount);
+    debug_msg!("  Fee: {}", fee);
+    debug_msg!("  Received shares: {}", deposited_shares);
+    debug_msg!("  User total shares: {}", user.deposited_shares);
+    debug_msg!("  Bank total shares: {}", bank.total_deposited_shares);
     
-    if periods > 0 {
-        // If the bank has no assets yet, skip compounding to avoid divide-by-zero / overflow
-        if bank.total_deposited_shares == 0 {
-            msg!("Bank has zero deposited shares – skip interest accrual for now");
-            bank.last_compound_time = current_time;
-            return Ok(());
-        }
-        msg!("Accruing interest for {} periods", periods);
-        // Compound interest formula: A = P*(1 + r/n)^(n*t)
-        let rate_factor = 1_000_000u128; // Precision factor
-        let rate_per_period = (bank.deposit_interest_rate as u128)
-            .checked_mul(rate_factor)
-            .and_then(|v| v.checked_div(1_000_000))
-            .ok_or(ErrorCode::MathOverflow)?;
-        msg!("Rate per period: {}", rate_per_period);
-
-        let total_assets = calculate_total_deposited_assets(bank);
-        msg!("Total assets before compounding: {}", total_assets);
-        if total_assets == 0 {
-            msg!("Total assets is zero – skipping compounding to avoid division by zero");
-            bank.last_compound_time += periods * bank.interest_accrual_period;
-            return Ok(());
-        }
-        let compounded = compound_interest(total_assets, rate_per_period, periods as u32)?;
-        msg!("Total assets after compounding: {}", compounded);
-        
-        msg!("Updating total deposited shares based on compounded interest");
-        msg!("Old total deposited shares: {}", bank.total_deposited_shares);
-        bank.total_deposited_shares = compounded
-            .checked_mul(bank.total_deposited_shares as u128)
-            .and_then(|v| v.checked_div(total_assets))
-            .ok_or(ErrorCode::MathOverflow)? as u64;
-        msg!("New total deposited shares: {}", bank.total_deposited_shares);
-        
-        bank.last_compound_time += periods * bank.interest_accrual_period;
-        msg!("Updated last compound time to: {}", bank.last_compound_time);
-    } else {
-        msg!("No interest accrual needed (0 periods)");
-    }
     Ok(())
 }
-
-fn calculate_total_deposited_assets(bank: &Bank) -> u128 {
-    // Implementation would use oracle prices and exchange rates
-    // Simplified for example:
-    msg!("Calculating total deposited assets (simplified implementation)");
-    bank.total_deposited_shares as u128
-}
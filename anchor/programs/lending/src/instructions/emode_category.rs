@@ -0,0 +1,93 @@
+use anchor_lang::prelude::*;
+
+use crate::error::ErrorCode;
+use crate::state::{EModeCategory, ProtocolConfig};
+use crate::utils::{validate_risk_params, RiskParams};
+
+#[derive(Accounts)]
+#[instruction(category_id: u8)]
+pub struct InitEModeCategory<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + EModeCategory::INIT_SPACE,
+        seeds = [b"emode".as_ref(), &[category_id]],
+        bump,
+    )]
+    pub emode_category: Box<Account<'info, EModeCategory>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateEModeCategory<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
+    #[account(
+        mut,
+        seeds = [b"emode".as_ref(), &[emode_category.category_id]],
+        bump = emode_category.bump,
+    )]
+    pub emode_category: Box<Account<'info, EModeCategory>>,
+}
+
+/// Creates e-mode category `category_id`. Id 0 is reserved for "no category".
+pub fn process_init_emode_category(
+    ctx: Context<InitEModeCategory>,
+    category_id: u8,
+    label: String,
+    max_ltv: u64,
+    liquidation_threshold: u64,
+    liquidation_bonus: u64,
+) -> Result<()> {
+    require!(category_id != 0, ErrorCode::InvalidEModeCategory);
+    let params = RiskParams { max_ltv, liquidation_threshold, liquidation_bonus };
+    validate_risk_params(&params)?;
+
+    let category = &mut ctx.accounts.emode_category;
+    category.category_id = category_id;
+    category.label = label;
+    category.max_ltv = max_ltv;
+    category.liquidation_threshold = liquidation_threshold;
+    category.liquidation_bonus = liquidation_bonus;
+    category.bump = ctx.bumps.emode_category;
+
+    debug_msg!("E-mode category {} ({}) created: {:?}", category_id, category.label, params);
+    Ok(())
+}
+
+/// Replaces a category's risk parameters. Users in the category are valued
+/// with the new parameters from their next interaction on.
+pub fn process_update_emode_category(
+    ctx: Context<UpdateEModeCategory>,
+    max_ltv: u64,
+    liquidation_threshold: u64,
+    liquidation_bonus: u64,
+) -> Result<()> {
+    let params = RiskParams { max_ltv, liquidation_threshold, liquidation_bonus };
+    validate_risk_params(&params)?;
+
+    let category = &mut ctx.accounts.emode_category;
+    debug_msg!("E-mode category {}: {:?} -> {:?}", category.category_id, RiskParams::from(&***category), params);
+    category.max_ltv = max_ltv;
+    category.liquidation_threshold = liquidation_threshold;
+    category.liquidation_bonus = liquidation_bonus;
+    Ok(())
+}
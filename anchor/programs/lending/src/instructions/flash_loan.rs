@@ -0,0 +1,197 @@
+use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+use anchor_lang::solana_program::sysvar::instructions::{
+    load_current_index_checked,
+    load_instruction_at_checked,
+};
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface, TransferChecked, transfer_checked};
+
+use crate::constants::PAUSE_BORROW;
+use crate::error::ErrorCode;
+use crate::state::{Bank, ProtocolConfig};
+use crate::utils::{amount_with_transfer_fee, available_liquidity, calculate_fee, require_not_paused};
+
+// Position of `bank` in the `FlashRepay` accounts, used when scanning the
+// transaction for the matching repayment.
+const FLASH_REPAY_BANK_INDEX: usize = 2;
+
+#[derive(Accounts)]
+pub struct FlashBorrow<'info> {
+    pub signer: Signer<'info>,
+
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        seeds = [mint.key().as_ref()],
+        bump,
+        constraint = !bank.flash_loan_active @ ErrorCode::BankLocked,
+    )]
+    pub bank: Box<Account<'info, Bank>>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = bank_token_account,
+        seeds = [b"treasury", mint.key().as_ref()],
+        bump,
+    )]
+    pub bank_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+    )]
+    pub destination_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    /// CHECK: address is checked against the instructions sysvar id.
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct FlashRepay<'info> {
+    pub signer: Signer<'info>,
+
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        seeds = [mint.key().as_ref()],
+        bump,
+    )]
+    pub bank: Box<Account<'info, Bank>>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = bank_token_account,
+        seeds = [b"treasury", mint.key().as_ref()],
+        bump,
+    )]
+    pub bank_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = signer,
+    )]
+    pub source_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+pub fn process_flash_borrow(ctx: Context<FlashBorrow>, amount: u64) -> Result<()> {
+    require_not_paused(&ctx.accounts.protocol_config, &ctx.accounts.bank, PAUSE_BORROW)?;
+
+    debug_msg!("Processing flash borrow of {} tokens", amount);
+    require!(amount > 0, ErrorCode::InvalidFlashLoanAmount);
+
+    let liquidity = available_liquidity(&ctx.accounts.bank, ctx.accounts.bank_token_account.amount);
+    debug_msg!("Available liquidity: {}", liquidity);
+    require!(amount <= liquidity, ErrorCode::InsufficientLiquidity);
+
+    // ---------------------------------------------------------------------
+    // 1. The loan must be taken by a top-level instruction, and a flash_repay
+    //    for the same bank must follow it in this transaction.
+    // ---------------------------------------------------------------------
+    let instructions = ctx.accounts.instructions.to_account_info();
+    let current_index = load_current_index_checked(&instructions)? as usize;
+    let current_ix = load_instruction_at_checked(current_index, &instructions)?;
+    require_keys_eq!(current_ix.program_id, crate::ID, ErrorCode::FlashLoanCpiNotAllowed);
+
+    let bank_key = ctx.accounts.bank.key();
+    let mut index = current_index + 1;
+    let mut repay_found = false;
+    while let Ok(ix) = load_instruction_at_checked(index, &instructions) {
+        if ix.program_id == crate::ID
+            && ix.data.starts_with(crate::instruction::FlashRepay::DISCRIMINATOR)
+            && ix.accounts.get(FLASH_REPAY_BANK_INDEX).map(|meta| meta.pubkey) == Some(bank_key)
+        {
+            repay_found = true;
+            break;
+        }
+        index += 1;
+    }
+    require!(repay_found, ErrorCode::MissingFlashRepay);
+    debug_msg!("Matching flash_repay found at instruction {}", index);
+
+    // ---------------------------------------------------------------------
+    // 2. Lock the bank until the repayment lands, then release the funds.
+    // ---------------------------------------------------------------------
+    ctx.accounts.bank.flash_loan_active = true;
+    ctx.accounts.bank.flash_loan_amount = amount;
+
+    let mint_key = ctx.accounts.mint.key();
+    let signer_seeds: &[&[&[u8]]] = &[
+        &[
+            b"treasury",
+            mint_key.as_ref(),
+            &[ctx.bumps.bank_token_account],
+        ]
+    ];
+    let transfer_ctx = CpiContext::new_with_signer(
+        ctx.accounts.token_program.to_account_info(),
+        TransferChecked {
+            authority: ctx.accounts.bank_token_account.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            from: ctx.accounts.bank_token_account.to_account_info(),
+            to: ctx.accounts.destination_token_account.to_account_info(),
+        },
+        signer_seeds,
+    );
+    transfer_checked(transfer_ctx, amount, ctx.accounts.mint.decimals)?;
+
+    debug_msg!("Flash borrow of {} tokens sent to {}", amount, ctx.accounts.destination_token_account.key());
+    Ok(())
+}
+
+pub fn process_flash_repay(ctx: Context<FlashRepay>) -> Result<()> {
+    require!(ctx.accounts.bank.flash_loan_active, ErrorCode::FlashLoanNotActive);
+
+    let amount = ctx.accounts.bank.flash_loan_amount;
+    let fee = calculate_fee(amount, ctx.accounts.bank.flash_loan_fee)?;
+    let total = amount.checked_add(fee).ok_or(ErrorCode::MathOverflow)?;
+    debug_msg!("Repaying flash loan of {} tokens plus {} fee", amount, fee);
+
+    // Gross up for any transfer fee on the mint; the treasury must end up
+    // with the principal plus the flash loan fee.
+    let balance_before = ctx.accounts.bank_token_account.amount;
+    let transfer_amount = amount_with_transfer_fee(&ctx.accounts.mint.to_account_info(), total)?;
+    let transfer_ctx = CpiContext::new(
+        ctx.accounts.token_program.to_account_info(),
+        TransferChecked {
+            authority: ctx.accounts.signer.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            from: ctx.accounts.source_token_account.to_account_info(),
+            to: ctx.accounts.bank_token_account.to_account_info(),
+        },
+    );
+    transfer_checked(transfer_ctx, transfer_amount, ctx.accounts.mint.decimals)?;
+
+    ctx.accounts.bank_token_account.reload()?;
+    let received = ctx.accounts.bank_token_account.amount
+        .checked_sub(balance_before)
+        .ok_or(ErrorCode::MathOverflow)?;
+    debug_msg!("Treasury received: {}", received);
+    require!(received >= total, ErrorCode::FlashLoanUnderpaid);
+
+    // Anything received above the principal is protocol revenue.
+    let bank = &mut ctx.accounts.bank;
+    let fee_received = received - amount;
+    bank.accumulated_fees = bank.accumulated_fees.checked_add(fee_received).ok_or(ErrorCode::MathOverflow)?;
+    bank.flash_loan_active = false;
+    bank.flash_loan_amount = 0;
+
+    debug_msg!("Flash loan repaid, bank unlocked");
+    debug_msg!("Accumulated fees: {}", bank.accumulated_fees);
+    Ok(())
+}
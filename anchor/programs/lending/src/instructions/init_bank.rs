@@ -1,16 +1,33 @@
 use anchor_lang::prelude::*;
 use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
 use crate::state::*;
+use pyth_solana_receiver_sdk::price_update::get_feed_id_from_hex;
+use crate::constants::{DEFAULT_FLASH_LOAN_FEE, DEFAULT_ORACLE_MAX_CONFIDENCE, MAXIMUM_AGE};
+use crate::error::ErrorCode;
+use crate::events::BankInitialized;
+use crate::math::WAD;
+use crate::utils::{sync_mint_interest_config, validate_bank_config, validate_mint_extensions};
 
+#[event_cpi]
 #[derive(Accounts)]
 pub struct InitBank<'info> {
     #[account(mut)]
     pub signer: Signer<'info>,
 
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = protocol_config.admin == signer.key() @ ErrorCode::Unauthorized,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
     pub mint: InterfaceAccount<'info, Mint>,
+
+    // Registry entry for the feed this bank is priced with
+    pub pyth_network_feed_id: Account<'info, PythNetworkFeedId>,
  
     #[account(
-        init_if_needed,
+        init,
         space = 8 + Bank::INIT_SPACE,
         payer = signer,
         seeds = [mint.key().as_ref()],
@@ -19,7 +36,7 @@ pub struct InitBank<'info> {
     pub bank: Account<'info, Bank>,
 
     #[account(
-        init_if_needed,
+        init,
         token::mint = mint,
         token::authority = bank_token_account,
         payer = signer,
@@ -28,47 +45,114 @@ pub struct InitBank<'info> {
     )]
     pub bank_token_account: InterfaceAccount<'info,TokenAccount>,
 
+    #[account(
+        init,
+        payer = signer,
+        mint::decimals = mint.decimals,
+        mint::authority = bank,
+        mint::token_program = token_program,
+        seeds = [b"receipt", mint.key().as_ref()],
+        bump,
+    )]
+    pub receipt_mint: Box<InterfaceAccount<'info, Mint>>,
+
     pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn process_init_bank(
     ctx: Context<InitBank>,
     liquidation_threshold: u64,
     liquidation_bonus: u64,
     liquidation_close_factor: u64,
     max_ltv: u64,
-    deposit_interest_rate: u64,
-    borrow_interest_rate: u64,
+    base_borrow_rate: u64,
+    optimal_utilization: u64,
+    borrow_rate_slope1: u64,
+    borrow_rate_slope2: u64,
+    reserve_factor: u64,
     name: String,
     description: String,
     deposit_fee: u64,
     withdrawal_fee: u64,
     min_deposit: u64,
     interest_accrual_period: i64,
+    supply_cap: u64,
+    borrow_cap: u64,
 ) -> Result<()> {
+    let mint_info = ctx.accounts.mint.to_account_info();
+    validate_mint_extensions(&mint_info)?;
+
     let bank = &mut ctx.accounts.bank;
     let now = Clock::get()?.unix_timestamp;
+    sync_mint_interest_config(bank, &mint_info)?;
     
     bank.authority = ctx.accounts.signer.key();
     bank.mint_address = ctx.accounts.mint.key();
+    bank.mint_decimals = ctx.accounts.mint.decimals;
+    bank.receipt_mint = ctx.accounts.receipt_mint.key();
+    bank.oracle_feed_id = get_feed_id_from_hex(ctx.accounts.pyth_network_feed_id.feed_id.as_str())
+        .map_err(|_| ErrorCode::InvalidPriceFeed)?;
+    bank.oracle_owner = pyth_solana_receiver_sdk::ID;
+    bank.oracle_max_age = MAXIMUM_AGE;
+    bank.oracle_max_confidence = DEFAULT_ORACLE_MAX_CONFIDENCE;
+    bank.oracle_min_verification = OracleVerification::Full;
     bank.liquidation_threshold = liquidation_threshold;
     bank.liquidation_bonus = liquidation_bonus;
     bank.liquidation_close_factor = liquidation_close_factor;
     bank.max_ltv = max_ltv;
-    bank.deposit_interest_rate = deposit_interest_rate;
-    bank.borrow_interest_rate = borrow_interest_rate;
+    bank.base_borrow_rate = base_borrow_rate;
+    bank.optimal_utilization = optimal_utilization;
+    bank.borrow_rate_slope1 = borrow_rate_slope1;
+    bank.borrow_rate_slope2 = borrow_rate_slope2;
+    // Nothing is borrowed yet, so the curve sits at its base rate.
+    bank.borrow_interest_rate = base_borrow_rate;
+    bank.deposit_interest_rate = 0;
     bank.name = name;
     bank.description = description;
     bank.deposit_fee = deposit_fee;
     bank.withdrawal_fee = withdrawal_fee;
     bank.min_deposit = min_deposit;
+    bank.supply_cap = supply_cap;
+    bank.borrow_cap = borrow_cap;
+    bank.reserve_factor = reserve_factor;
+    bank.accumulated_fees = 0;
+    bank.fee_recipient = ctx.accounts.protocol_config.fee_recipient;
+    bank.flash_loan_fee = DEFAULT_FLASH_LOAN_FEE;
+    bank.flash_loan_active = false;
+    bank.flash_loan_amount = 0;
+    bank.paused_operations = 0;
+    bank.isolated = false;
+    bank.isolated_borrow_mints = vec![];
+    bank.isolated_debt_ceiling = 0;
+    bank.isolated_debt = 0;
+    bank.emode_category = 0;
     bank.interest_accrual_period = interest_accrual_period;
     bank.last_compound_time = now;
     
     bank.total_deposited_shares = 0;
     bank.total_borrowed_shares = 0;
     bank.total_collateral_shares = 0;
-    
+    bank.supply_index = WAD as u128;
+    bank.borrow_index = WAD as u128;
+
+    validate_bank_config(bank)?;
+
+    emit_cpi!(BankInitialized {
+        bank: bank.key(),
+        mint: bank.mint_address,
+        authority: bank.authority,
+        receipt_mint: bank.receipt_mint,
+        oracle_feed_id: bank.oracle_feed_id,
+        liquidation_threshold: bank.liquidation_threshold,
+        liquidation_bonus: bank.liquidation_bonus,
+        max_ltv: bank.max_ltv,
+        deposit_fee: bank.deposit_fee,
+        withdrawal_fee: bank.withdrawal_fee,
+        supply_cap: bank.supply_cap,
+        borrow_cap: bank.borrow_cap,
+        timestamp: now,
+    });
     Ok(())
 }
\ No newline at end of file
@@ -26,8 +26,8 @@ pub struct InitBorrowPosition<'info> {
     pub system_program: Program<'info, System>,
 }
 
-pub fn process_init_borrow_position(ctx: Context<InitBorrowPosition>, mint_collateral: Pubkey, mint_borrow: Pubkey, position_id: u64) -> Result<()> {
-    msg!("Initializing borrow position");
+pub fn process_init_borrow_position(ctx: Context<InitBorrowPosition>, _mint_collateral: Pubkey, _mint_borrow: Pubkey, position_id: u64) -> Result<()> {
+    debug_msg!("Initializing borrow position");
     let position = &mut ctx.accounts.borrow_position;
     position.position_id = position_id;
     Ok(())
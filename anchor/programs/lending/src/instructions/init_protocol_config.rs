@@ -0,0 +1,46 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::bpf_loader_upgradeable;
+use crate::error::ErrorCode;
+use crate::state::ProtocolConfig;
+
+#[derive(Accounts)]
+pub struct InitProtocolConfig<'info> {
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        init,
+        payer = signer,
+        space = 8 + ProtocolConfig::INIT_SPACE,
+        seeds = [b"protocol_config"],
+        bump,
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    // Only the program's upgrade authority can claim the first admin role
+    #[account(
+        seeds = [crate::ID.as_ref()],
+        bump,
+        seeds::program = bpf_loader_upgradeable::ID,
+        constraint = program_data.upgrade_authority_address == Some(signer.key()) @ ErrorCode::Unauthorized,
+    )]
+    pub program_data: Account<'info, ProgramData>,
+
+    pub system_program: Program<'info, System>,
+}
+
+pub fn process_init_protocol_config(ctx: Context<InitProtocolConfig>, guardian: Pubkey, fee_recipient: Pubkey) -> Result<()> {
+    let protocol_config = &mut ctx.accounts.protocol_config;
+    protocol_config.admin = ctx.accounts.signer.key();
+    protocol_config.pending_admin = None;
+    protocol_config.guardian = guardian;
+    protocol_config.fee_recipient = fee_recipient;
+    protocol_config.paused_operations = 0;
+    protocol_config.bump = ctx.bumps.protocol_config;
+
+    debug_msg!("Protocol config initialized");
+    debug_msg!("Admin: {}", protocol_config.admin);
+    debug_msg!("Guardian: {}", protocol_config.guardian);
+    debug_msg!("Fee recipient: {}", protocol_config.fee_recipient);
+    Ok(())
+}
@@ -39,7 +39,9 @@ pub fn process_init_user_token_state(ctx: Context<InitUserTokenState>, mint_addr
 
     let global_state = &mut ctx.accounts.user_global_state;
     global_state.user = ctx.accounts.signer.key();
-    global_state.deposited_mints.push(mint_address);
+    if !global_state.deposited_mints.contains(&mint_address) {
+        global_state.deposited_mints.push(mint_address);
+    }
     global_state.bump = ctx.bumps.user_global_state;
 
     Ok(())
@@ -1,132 +1,471 @@
-// use anchor_lang::prelude::*;
-// use anchor_spl::{associated_token::AssociatedToken, token_interface::{Mint, TokenInterface, TokenAccount, TransferChecked, transfer_checked}};
-// use pyth_solana_receiver_sdk::price_update::{get_feed_id_from_hex, PriceUpdateV2};
-
-// use crate::{error::{ErrorCode}, constants::{MAXIMUM_AGE, SOL_USD_PRICE_FEED_ID, USDC_USD_PRICE_FEED_ID}, state::{Bank, User}};
-
-// #[derive(Accounts)]
-// pub struct Liquidate<'info> {
-//     #[account(mut)]
-//     pub signer: Signer<'info>,
-
-//     pub borrow_mint: InterfaceAccount<'info, Mint>,
-//     pub collateral_mint: InterfaceAccount<'info, Mint>,
-//     pub price_update: Account<'info, PriceUpdateV2>,
-
-//     #[account(
-//         mut,
-//         seeds = [borrow_mint.key().as_ref()],
-//         bump,
-//     )]
-//     pub borrow_bank: Account<'info, Bank>,
-//     #[account(
-//         mut, 
-//         seeds = [b"treasury", borrow_mint.key().as_ref()],
-//         bump, 
-//     )]  
-//     pub borrowed_bank_token_account: InterfaceAccount<'info, TokenAccount>,
-
-//     #[account(
-//         mut,
-//         seeds = [collateral_mint.key().as_ref()],
-//         bump,
-//     )]
-//     pub collateral_bank: Account<'info, Bank>,
-    
-//     #[account(
-//         mut, 
-//         seeds = [b"treasury", collateral_mint.key().as_ref()],
-//         bump, 
-//     )]  
-//     pub collateral_bank_token_account: InterfaceAccount<'info, TokenAccount>,
-
-//     #[account(
-//         mut,
-//         seeds = [signer.key().as_ref()],
-//         bump,
-//     )]
-//     pub user_account: Account<'info, User>,
-
-//     #[account(
-//         mut,
-//         associated_token::mint = borrow_mint,
-//         associated_token::authority = signer,
-//         associated_token::token_program = token_program,
-//     )]
-//     pub user_borrow_token_account: InterfaceAccount<'info, TokenAccount>,
-
-//     #[account(
-//         mut,
-//         associated_token::mint = collateral_mint,
-//         associated_token::authority = signer,
-//         associated_token::token_program = token_program,
-//     )]
-//     pub user_collateral_token_account: InterfaceAccount<'info, TokenAccount>,
-
-//     pub associated_token_program: Program<'info, AssociatedToken>,
-//     pub token_program: Interface<'info, TokenInterface>,
-//     pub system_program: Program<'info, System>,
-// }
-
-
-// pub fn process_liquidate(ctx: Context<Liquidate>) -> Result<()> {
-//     let user = &mut ctx.accounts.user_account;
-//     let borrow_bank = &mut ctx.accounts.borrow_bank;
-//     let collateral_bank = &mut ctx.accounts.collateral_bank;
-//     let price_update = &mut ctx.accounts.price_update;
-    
-//     let sol_usd_price_feed_id = get_feed_id_from_hex(SOL_USD_PRICE_FEED_ID)?;
-//     let sol_price = price_update.get_price_no_older_than(&Clock::get()?, MAXIMUM_AGE, &sol_usd_price_feed_id)?;
-
-//     let usdc_usd_price_feed_id = get_feed_id_from_hex(USDC_USD_PRICE_FEED_ID)?;
-//     let usdc_price = price_update.get_price_no_older_than(&Clock::get()?, MAXIMUM_AGE, &usdc_usd_price_feed_id)?;
-
-    
-//     let total_collateral = (sol_price.price as u64 * user.deposited_sol) + (usdc_price.price as u64 * user.deposited_usdc);
-//     let total_borrowed = (sol_price.price as u64 * user.borrowed_sol)    + (usdc_price.price as u64 * user.borrowed_usdc);    
-    
-//     let health_factor = (total_collateral * borrow_bank.liquidation_threshold) / total_borrowed;
-
-//     if health_factor >= 1 {
-//         return Err(ErrorCode::HealthyAccount.into());
-//     }
-
-    
-//     let transfer_to_bank = TransferChecked {
-//         from: ctx.accounts.user_borrow_token_account.to_account_info(),
-//         mint: ctx.accounts.borrow_mint.to_account_info(),
-//         to: ctx.accounts.borrowed_bank_token_account.to_account_info(),
-//         authority: ctx.accounts.signer.to_account_info(),
-//     };
-
-//     let cpi_program = ctx.accounts.token_program.to_account_info();
-//     let cpi_ctx_to_bank = CpiContext::new(cpi_program.clone(), transfer_to_bank);
-//     let decimals = ctx.accounts.borrow_mint.decimals;
-
-//     transfer_checked(cpi_ctx_to_bank, total_borrowed, decimals)?;
-//     let liquidation_amount = total_borrowed * collateral_bank.liquidation_close_factor;
-
-//     // Transfer liquidation value and bonus to liquidator
-//     let liquidation_bonus = (liquidation_amount * collateral_bank.liquidation_bonus) + liquidation_amount;
-    
-//     let transfer_to_liquidator = TransferChecked {
-//         from: ctx.accounts.collateral_bank_token_account.to_account_info(),
-//         mint: ctx.accounts.collateral_mint.to_account_info(),
-//         to: ctx.accounts.user_collateral_token_account.to_account_info(),
-//         authority: ctx.accounts.collateral_bank_token_account.to_account_info(),
-//     };
-
-//     let mint_key = ctx.accounts.collateral_mint.key();
-//     let signer_seeds: &[&[&[u8]]] = &[
-//         &[
-//             b"treasury",
-//             mint_key.as_ref(),
-//             &[ctx.bumps.collateral_bank_token_account],
-//         ],
-//     ];
-//     let cpi_ctx_to_liquidator = CpiContext::new(cpi_program.clone(), transfer_to_liquidator).with_signer(signer_seeds);
-//     let collateral_decimals = ctx.accounts.collateral_mint.decimals;   
-//     transfer_checked(cpi_ctx_to_liquidator, liquidation_bonus, collateral_decimals)?;
-
-//     Ok(())
-// }
\ No newline at end of file
+use anchor_lang::prelude::*;
+use anchor_spl::{associated_token::AssociatedToken, token_interface::{Mint, MintTo, TokenInterface, TokenAccount, TransferChecked, mint_to, transfer_checked}};
+use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
+
+use crate::constants::{BPS_DENOMINATOR, PAUSE_LIQUIDATE};
+use crate::math::{Decimal, Rounding};
+use crate::state::{Bank, UserTokenState, BorrowPosition, UserGlobalState, ProtocolConfig, EModeCategory};
+use crate::error::ErrorCode;
+use crate::events::Liquidated;
+use crate::utils::*;
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct Liquidate<'info> {
+    #[account(mut)]
+    pub liquidator: Signer<'info>,
+
+    /// CHECK: only used to derive the borrower's PDAs; checked against `borrow_position.owner`.
+    pub borrower: UncheckedAccount<'info>,
+
+    pub mint_borrow: Box<InterfaceAccount<'info, Mint>>,
+
+    // Both banks are written, so they must be distinct accounts
+    #[account(constraint = mint_collateral.key() != mint_borrow.key() @ ErrorCode::SameBorrowAndCollateralMint)]
+    pub mint_collateral: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        seeds = [mint_borrow.key().as_ref()],
+        bump,
+        constraint = !bank_borrow.flash_loan_active @ ErrorCode::BankLocked,
+    )]
+    pub bank_borrow: Box<Account<'info, Bank>>,
+
+    #[account(
+        mut,
+        token::mint = mint_borrow,
+        token::authority = bank_borrow_token_account,
+        seeds = [b"treasury", mint_borrow.key().as_ref()],
+        bump,
+    )]
+    pub bank_borrow_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [mint_collateral.key().as_ref()],
+        bump,
+        constraint = !bank_collateral.flash_loan_active @ ErrorCode::BankLocked,
+    )]
+    pub bank_collateral: Box<Account<'info, Bank>>,
+
+    #[account(
+        mut,
+        token::mint = mint_collateral,
+        token::authority = bank_collateral_token_account,
+        seeds = [b"treasury", mint_collateral.key().as_ref()],
+        bump,
+    )]
+    pub bank_collateral_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [borrower.key().as_ref(), mint_borrow.key().as_ref()],
+        bump,
+    )]
+    pub user_borrow_account: Box<Account<'info, UserTokenState>>,
+
+    #[account(
+        mut,
+        seeds = [borrower.key().as_ref(), mint_collateral.key().as_ref()],
+        bump,
+    )]
+    pub user_collateral_account: Box<Account<'info, UserTokenState>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"position",
+            borrower.key().as_ref(),
+            borrow_position.collateral_mint.as_ref(),
+            mint_borrow.key().as_ref(),
+            &position_id.to_le_bytes(),
+        ],
+        bump,
+        constraint = borrow_position.owner == borrower.key(),
+    )]
+    pub borrow_position: Box<Account<'info, BorrowPosition>>,
+
+    #[account(
+        mut,
+        seeds = [b"user_global", borrower.key().as_ref()],
+        bump,
+    )]
+    pub user_global_state: Box<Account<'info, UserGlobalState>>,
+
+    #[account(
+        seeds = [b"emode".as_ref(), &[user_global_state.emode_category]],
+        bump = emode_category.bump,
+    )]
+    pub emode_category: Option<Box<Account<'info, EModeCategory>>>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint_borrow,
+        associated_token::authority = liquidator,
+        associated_token::token_program = token_program,
+    )]
+    pub liquidator_borrow_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init_if_needed,
+        payer = liquidator,
+        associated_token::mint = mint_collateral,
+        associated_token::authority = liquidator,
+        associated_token::token_program = token_program,
+    )]
+    pub liquidator_collateral_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        seeds = [b"receipt", mint_borrow.key().as_ref()],
+        bump,
+    )]
+    pub receipt_mint_borrow: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init_if_needed,
+        payer = liquidator,
+        associated_token::mint = receipt_mint_borrow,
+        associated_token::authority = borrower,
+        associated_token::token_program = token_program,
+    )]
+    pub borrower_borrow_receipt_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"receipt", mint_collateral.key().as_ref()],
+        bump,
+    )]
+    pub receipt_mint_collateral: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init_if_needed,
+        payer = liquidator,
+        associated_token::mint = receipt_mint_collateral,
+        associated_token::authority = borrower,
+        associated_token::token_program = token_program,
+    )]
+    pub borrower_collateral_receipt_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub price_update_borrow_token: Box<Account<'info, PriceUpdateV2>>,
+    pub price_update_collateral_token: Box<Account<'info, PriceUpdateV2>>,
+
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Repays up to `liquidation_close_factor` of an unhealthy position's debt on
+/// behalf of its owner. `amount` is denominated in borrow token units; the
+/// liquidator receives the equivalent collateral plus `liquidation_bonus`,
+/// seized from whichever of the position's collaterals is `mint_collateral`.
+///
+/// The borrower's account as a whole must be unhealthy, and so must the
+/// position against the collateral it locks. `remaining_accounts` must hold
+/// the pair `[bank, price_update]` for each of the position's other
+/// collaterals with locked shares, in position order, followed by the
+/// accounts `calculate_account_health` reads.
+pub fn process_liquidate<'info>(
+    ctx: Context<'_, '_, 'info, 'info, Liquidate<'info>>,
+    _position_id: u64,
+    amount: u64,
+) -> Result<()> {
+    require_not_paused(&ctx.accounts.protocol_config, &ctx.accounts.bank_borrow, PAUSE_LIQUIDATE)?;
+    require_not_paused(&ctx.accounts.protocol_config, &ctx.accounts.bank_collateral, PAUSE_LIQUIDATE)?;
+    require!(ctx.accounts.borrow_position.active, ErrorCode::AlreadyRepayed);
+    require!(amount > 0, ErrorCode::InvalidLiquidationAmount);
+
+    debug_msg!("Starting liquidation for amount: {}", amount);
+    debug_msg!("Liquidator: {}", ctx.accounts.liquidator.key());
+    debug_msg!("Borrower: {}", ctx.accounts.borrower.key());
+    debug_msg!("Position: {}", ctx.accounts.borrow_position.key());
+
+    let current_time = Clock::get()?.unix_timestamp;
+
+    // Treasury balances drive each bank's utilization when accruing interest.
+    let borrow_liquidity = ctx.accounts.bank_borrow_token_account.amount;
+    let collateral_liquidity = ctx.accounts.bank_collateral_token_account.amount;
+    let bank_borrow = &mut ctx.accounts.bank_borrow;
+    let bank_collateral = &mut ctx.accounts.bank_collateral;
+    let user_borrow = &mut ctx.accounts.user_borrow_account;
+    let user_collateral = &mut ctx.accounts.user_collateral_account;
+    let position = &mut ctx.accounts.borrow_position;
+
+    debug_msg!("Accruing interest for borrow bank");
+    if let Some(event) = accrue_interest(bank_borrow, borrow_liquidity, current_time)? {
+        emit_cpi!(event);
+    }
+    debug_msg!("Accruing interest for collateral bank");
+    if let Some(event) = accrue_interest(bank_collateral, collateral_liquidity, current_time)? {
+        emit_cpi!(event);
+    }
+    sync_mint_interest_config(bank_borrow, &ctx.accounts.mint_borrow.to_account_info())?;
+    sync_mint_interest_config(bank_collateral, &ctx.accounts.mint_collateral.to_account_info())?;
+
+    // ------------------------------------------------------------------
+    // 1. Value both sides of the position.
+    // ------------------------------------------------------------------
+    let debt_amount = calculate_debt_amount(bank_borrow, position.borrowed_shares, Rounding::Up)?;
+    let position_collateral = position_collateral_shares(position, &bank_collateral.mint_address);
+    require!(position_collateral > 0, ErrorCode::InsufficientCollateral);
+    let collateral_amount = calculate_deposit_amount(bank_collateral, position_collateral, Rounding::Down)?;
+    debug_msg!("Position debt in token units: {}", debt_amount);
+    debug_msg!("Position collateral in token units: {}", collateral_amount);
+
+    let borrow_price = get_validated_price(
+        &ctx.accounts.price_update_borrow_token,
+        bank_borrow,
+    )?;
+    debug_msg!("Borrow token price: {} with exponent {}", borrow_price.price, borrow_price.exponent);
+    let collateral_price = get_validated_price(
+        &ctx.accounts.price_update_collateral_token,
+        bank_collateral,
+    )?;
+    debug_msg!("Collateral price: {} with exponent {}", collateral_price.price, collateral_price.exponent);
+
+    let debt_value = calculate_token_value(
+        debt_amount,
+        ctx.accounts.mint_borrow.decimals,
+        &borrow_price,
+        Rounding::Up,
+    )?;
+    let collateral_value = calculate_token_value(
+        collateral_amount,
+        ctx.accounts.mint_collateral.decimals,
+        &collateral_price,
+        Rounding::Down,
+    )?;
+    debug_msg!("Debt value in USD: {}", debt_value);
+    debug_msg!("Collateral value in USD: {}", collateral_value);
+
+    // ------------------------------------------------------------------
+    // 2. Positions can only be liquidated once the borrower's whole account
+    //    is unhealthy: all debt exceeds the threshold-weighted value of all
+    //    of their collateral. Only the position's own collateral can be
+    //    seized, so the position must also be unhealthy against it.
+    // ------------------------------------------------------------------
+    let pair_count = position.collaterals
+        .iter()
+        .filter(|collateral| collateral.shares > 0 && collateral.mint != bank_collateral.mint_address)
+        .count();
+    require!(ctx.remaining_accounts.len() >= pair_count * 2, ErrorCode::InvalidCollateralAccounts);
+    let (collateral_accounts, health_accounts) = ctx.remaining_accounts.split_at(pair_count * 2);
+
+    let known_assets = [
+        HealthAsset {
+            bank: bank_borrow,
+            user_state: user_borrow,
+            receipt_balance: ctx.accounts.borrower_borrow_receipt_account.amount,
+            price: borrow_price,
+        },
+        HealthAsset {
+            bank: bank_collateral,
+            user_state: user_collateral,
+            receipt_balance: ctx.accounts.borrower_collateral_receipt_account.amount,
+            price: collateral_price,
+        },
+    ];
+    let emode = user_emode(&ctx.accounts.user_global_state, &ctx.accounts.emode_category)?;
+    let health = calculate_account_health(
+        &ctx.accounts.borrower.key(),
+        &ctx.accounts.user_global_state,
+        &known_assets,
+        health_accounts,
+        emode,
+        current_time,
+    )?;
+    if !health.is_liquidatable() {
+        debug_msg!("ERROR: Account is healthy. Debt value {} <= {}", health.debt_value, health.liquidation_limit);
+        return Err(ErrorCode::HealthyAccount.into());
+    }
+
+    let liquidation_limit = position_liquidation_limit(
+        position,
+        &[(&**bank_collateral, collateral_price)],
+        collateral_accounts,
+        emode,
+    )?;
+    if debt_value <= liquidation_limit {
+        debug_msg!("ERROR: Position is healthy. Debt value {} <= {}", debt_value, liquidation_limit);
+        return Err(ErrorCode::HealthyAccount.into());
+    }
+
+    // ------------------------------------------------------------------
+    // 3. Cap the repayment at the borrow bank's close factor and collect it
+    //    from the liquidator, grossed up for any transfer fee on the mint.
+    //    Only what the treasury actually received is credited.
+    // ------------------------------------------------------------------
+    let max_repay_amount = ((debt_amount as u128)
+        .checked_mul(bank_borrow.liquidation_close_factor as u128)
+        .and_then(|v| v.checked_div(BPS_DENOMINATOR as u128))
+        .ok_or(ErrorCode::MathOverflow)?) as u64;
+    let repay_amount = amount.min(max_repay_amount);
+    debug_msg!("Max repayable under close factor: {}", max_repay_amount);
+    debug_msg!("Repay amount: {}", repay_amount);
+    require!(repay_amount > 0, ErrorCode::InvalidLiquidationAmount);
+
+    let transfer_amount = amount_with_transfer_fee(&ctx.accounts.mint_borrow.to_account_info(), repay_amount)?;
+    debug_msg!("Transferring {} borrow tokens from liquidator to bank", transfer_amount);
+    let transfer_ctx = CpiContext::new(
+        ctx.accounts.token_program.to_account_info(),
+        TransferChecked {
+            authority: ctx.accounts.liquidator.to_account_info(),
+            from: ctx.accounts.liquidator_borrow_token_account.to_account_info(),
+            mint: ctx.accounts.mint_borrow.to_account_info(),
+            to: ctx.accounts.bank_borrow_token_account.to_account_info(),
+        },
+    );
+    transfer_checked(transfer_ctx, transfer_amount, ctx.accounts.mint_borrow.decimals)?;
+
+    ctx.accounts.bank_borrow_token_account.reload()?;
+    let received = ctx.accounts.bank_borrow_token_account.amount
+        .checked_sub(borrow_liquidity)
+        .ok_or(ErrorCode::MathOverflow)?;
+    let repaid_amount = received.min(repay_amount);
+    debug_msg!("Treasury received {} tokens, crediting {}", received, repaid_amount);
+
+    // ------------------------------------------------------------------
+    // 4. Size the collateral seized for what was repaid, and pay it out.
+    // ------------------------------------------------------------------
+    let repay_value = calculate_token_value(
+        repaid_amount,
+        ctx.accounts.mint_borrow.decimals,
+        &borrow_price,
+        Rounding::Down,
+    )?;
+    let bonus_multiplier = Decimal::from_bps(
+        BPS_DENOMINATOR
+            .checked_add(risk_params(bank_collateral, emode).liquidation_bonus)
+            .ok_or(ErrorCode::MathOverflow)?,
+    );
+    let seize_value = repay_value.try_mul(bonus_multiplier)?;
+    let seize_amount = calculate_token_amount(
+        seize_value,
+        ctx.accounts.mint_collateral.decimals,
+        &collateral_price,
+        Rounding::Down,
+    )?
+    .min(collateral_amount);
+    debug_msg!("Repay value in USD: {}", repay_value);
+    debug_msg!("Seized value in USD (incl. bonus): {}", seize_value);
+    debug_msg!("Collateral to seize: {}", seize_amount);
+
+    // Round down so the liquidator only clears debt they actually paid for.
+    let shares_to_burn = calculate_borrow_shares(bank_borrow, repaid_amount, Rounding::Down)?
+        .min(position.borrowed_shares);
+    require!(shares_to_burn > 0, ErrorCode::InvalidLiquidationAmount);
+    let seize_shares = calculate_deposit_shares(bank_collateral, seize_amount, Rounding::Up)?
+        .min(position_collateral);
+    debug_msg!("Borrow shares to burn: {}", shares_to_burn);
+    debug_msg!("Collateral shares to seize: {}", seize_shares);
+
+    debug_msg!("Transferring {} collateral tokens from bank to liquidator", seize_amount);
+    let mint_collateral_key = ctx.accounts.mint_collateral.key();
+    let signer_seeds: &[&[&[u8]]] = &[&[
+        b"treasury",
+        mint_collateral_key.as_ref(),
+        &[ctx.bumps.bank_collateral_token_account],
+    ]];
+    let transfer_ctx = CpiContext::new_with_signer(
+        ctx.accounts.token_program.to_account_info(),
+        TransferChecked {
+            authority: ctx.accounts.bank_collateral_token_account.to_account_info(),
+            from: ctx.accounts.bank_collateral_token_account.to_account_info(),
+            mint: ctx.accounts.mint_collateral.to_account_info(),
+            to: ctx.accounts.liquidator_collateral_token_account.to_account_info(),
+        },
+        signer_seeds,
+    );
+    transfer_checked(transfer_ctx, seize_amount, ctx.accounts.mint_collateral.decimals)?;
+
+    // ------------------------------------------------------------------
+    // 5. Update shares on the position, the borrower's token states and banks.
+    // ------------------------------------------------------------------
+    bank_borrow.total_borrowed_shares = bank_borrow.total_borrowed_shares
+        .checked_sub(shares_to_burn)
+        .ok_or(ErrorCode::MathOverflow)?;
+    user_borrow.borrowed_shares = user_borrow.borrowed_shares
+        .checked_sub(shares_to_burn)
+        .ok_or(ErrorCode::MathOverflow)?;
+    user_borrow.last_updated_borrowed = current_time;
+    release_isolated_debt(bank_collateral, position, shares_to_burn)?;
+    position.borrowed_shares = position.borrowed_shares
+        .checked_sub(shares_to_burn)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    bank_collateral.total_collateral_shares = bank_collateral.total_collateral_shares
+        .checked_sub(seize_shares)
+        .ok_or(ErrorCode::MathOverflow)?;
+    user_collateral.collateral_shares = user_collateral.collateral_shares
+        .checked_sub(seize_shares)
+        .ok_or(ErrorCode::MathOverflow)?;
+    user_collateral.last_updated_collateral = current_time;
+    remove_position_collateral(position, &bank_collateral.mint_address, seize_shares)?;
+    position.last_updated = current_time;
+
+    if position.borrowed_shares == 0 {
+        debug_msg!("Position debt fully cleared, releasing remaining collateral");
+        let remaining_collateral = position_collateral_shares(position, &bank_collateral.mint_address);
+        unlock_collateral_shares(bank_collateral, user_collateral, remaining_collateral, current_time)?;
+        remove_position_collateral(position, &bank_collateral.mint_address, remaining_collateral)?;
+
+        // The freed shares go back to the borrower as receipts.
+        let bank_seeds: &[&[&[u8]]] = &[&[mint_collateral_key.as_ref(), &[ctx.bumps.bank_collateral]]];
+        mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                MintTo {
+                    mint: ctx.accounts.receipt_mint_collateral.to_account_info(),
+                    to: ctx.accounts.borrower_collateral_receipt_account.to_account_info(),
+                    authority: bank_collateral.to_account_info(),
+                },
+                bank_seeds,
+            ),
+            remaining_collateral,
+        )?;
+
+        // Other collaterals stay locked until the owner releases them.
+        if position.collaterals.iter().all(|collateral| collateral.shares == 0) {
+            debug_msg!("No collateral left, deactivating position");
+            position.active = false;
+            let position_key = position.key();
+            ctx.accounts.user_global_state.active_positions.retain(|pos| pos != &position_key);
+        }
+    }
+
+    emit_cpi!(Liquidated {
+        liquidator: ctx.accounts.liquidator.key(),
+        borrower: ctx.accounts.borrower.key(),
+        position: position.key(),
+        bank_borrow: bank_borrow.key(),
+        bank_collateral: bank_collateral.key(),
+        repay_amount: repaid_amount,
+        seize_amount,
+        borrow_shares_burned: shares_to_burn,
+        collateral_shares_seized: seize_shares,
+        borrow_index: bank_borrow.borrow_index,
+        supply_index: bank_collateral.supply_index,
+        borrow_price: borrow_price.price,
+        borrow_price_exponent: borrow_price.exponent,
+        collateral_price: collateral_price.price,
+        collateral_price_exponent: collateral_price.exponent,
+        timestamp: current_time,
+    });
+
+    debug_msg!("Liquidation successful");
+    debug_msg!("Summary:");
+    debug_msg!("  Repaid amount: {}", repaid_amount);
+    debug_msg!("  Burned borrow shares: {}", shares_to_burn);
+    debug_msg!("  Seized collateral: {} ({} shares)", seize_amount, seize_shares);
+    debug_msg!("  Remaining position borrowed shares: {}", position.borrowed_shares);
+    debug_msg!("  Remaining position collaterals: {:?}", position.collaterals);
+
+    Ok(())
+}
@@ -12,9 +12,37 @@ pub mod borrow;
 pub use borrow::*;
 pub mod repay;
 pub use repay::*;
-// pub mod liquidate;
-// pub use liquidate::*;
+pub mod liquidate;
+pub use liquidate::*;
 pub mod store_symbol_feed_id;
 pub use store_symbol_feed_id::*;
 pub mod init_borrow_position;
-pub use init_borrow_position::*;
\ No newline at end of file
+pub use init_borrow_position::*;
+pub mod collect_fees;
+pub use collect_fees::*;
+pub mod update_bank_config;
+pub use update_bank_config::*;
+pub mod flash_loan;
+pub use flash_loan::*;
+pub mod init_protocol_config;
+pub use init_protocol_config::*;
+pub mod set_pause;
+pub use set_pause::*;
+pub mod update_protocol_config;
+pub use update_protocol_config::*;
+pub mod transfer_admin;
+pub use transfer_admin::*;
+pub mod redeem;
+pub use redeem::*;
+pub mod emode_category;
+pub use emode_category::*;
+pub mod set_user_emode;
+pub use set_user_emode::*;
+pub mod add_collateral;
+pub use add_collateral::*;
+pub mod remove_collateral;
+pub use remove_collateral::*;
+pub mod close_position;
+pub use close_position::*;
+pub mod close_user_token_state;
+pub use close_user_token_state::*;
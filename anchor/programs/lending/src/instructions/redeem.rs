@@ -0,0 +1,154 @@
+use anchor_lang::prelude::*;
+use anchor_spl::associated_token::AssociatedToken;
+use anchor_spl::token_interface::{Burn, Mint, TokenAccount, TokenInterface, TransferChecked, burn, transfer_checked};
+
+use crate::constants::PAUSE_WITHDRAW;
+use crate::error::ErrorCode;
+use crate::events::Withdrawn;
+use crate::math::{Decimal, Rounding};
+use crate::state::{Bank, ProtocolConfig};
+use crate::utils::*;
+
+#[event_cpi]
+#[derive(Accounts)]
+pub struct Redeem<'info> {
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        seeds = [mint.key().as_ref()],
+        bump,
+        constraint = !bank.flash_loan_active @ ErrorCode::BankLocked,
+    )]
+    pub bank: Box<Account<'info, Bank>>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = bank_token_account,
+        seeds = [b"treasury", mint.key().as_ref()],
+        bump,
+    )]
+    pub bank_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"receipt", mint.key().as_ref()],
+        bump,
+    )]
+    pub receipt_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        token::mint = receipt_mint,
+        token::authority = signer,
+    )]
+    pub receipt_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init_if_needed,
+        payer = signer,
+        associated_token::mint = mint,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program,
+    )]
+    pub destination_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+}
+
+/// Burns `receipt_amount` receipt tokens from any wallet and pays out the
+/// underlying deposit, minus the withdrawal fee. Unlike `withdraw` this needs
+/// no `UserTokenState`; receipts are free shares, so no health check applies.
+pub fn process_redeem(ctx: Context<Redeem>, receipt_amount: u64) -> Result<()> {
+    require_not_paused(&ctx.accounts.protocol_config, &ctx.accounts.bank, PAUSE_WITHDRAW)?;
+
+    debug_msg!("Processing redemption of {} receipt tokens", receipt_amount);
+    debug_msg!("Holder: {}", ctx.accounts.signer.key());
+
+    let current_time = Clock::get()?.unix_timestamp;
+    let liquidity = ctx.accounts.bank_token_account.amount;
+    if let Some(event) = accrue_interest(&mut ctx.accounts.bank, liquidity, current_time)? {
+        emit_cpi!(event);
+    }
+    debug_msg!("Bank supply index: {}", Decimal::from_scaled_val(ctx.accounts.bank.supply_index));
+
+    // Each receipt is one deposit share; round the payout down.
+    let amount = calculate_deposit_amount(&ctx.accounts.bank, receipt_amount, Rounding::Down)?;
+    require!(receipt_amount > 0 && amount > 0, ErrorCode::InvalidWithdrawAmount);
+    debug_msg!("Receipts are worth {} tokens", amount);
+
+    burn(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Burn {
+                mint: ctx.accounts.receipt_mint.to_account_info(),
+                from: ctx.accounts.receipt_token_account.to_account_info(),
+                authority: ctx.accounts.signer.to_account_info(),
+            },
+        ),
+        receipt_amount,
+    )?;
+    ctx.accounts.bank.total_deposited_shares = ctx.accounts.bank.total_deposited_shares
+        .checked_sub(receipt_amount)
+        .ok_or(ErrorCode::MathOverflow)?;
+    debug_msg!("New bank total deposited shares: {}", ctx.accounts.bank.total_deposited_shares);
+
+    let fee = calculate_fee(amount, ctx.accounts.bank.withdrawal_fee)?;
+    let net_amount = amount.checked_sub(fee).ok_or(ErrorCode::MathOverflow)?;
+    debug_msg!("Withdrawal fee: {} ({} bps), net amount: {}", fee, ctx.accounts.bank.withdrawal_fee, net_amount);
+
+    let liquidity = available_liquidity(&ctx.accounts.bank, ctx.accounts.bank_token_account.amount);
+    if net_amount > liquidity {
+        debug_msg!("Insufficient liquidity: requested {} but only {} available", net_amount, liquidity);
+        return Err(ErrorCode::InsufficientLiquidity.into());
+    }
+    ctx.accounts.bank.accumulated_fees = ctx.accounts.bank.accumulated_fees
+        .checked_add(fee)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    let mint_key = ctx.accounts.mint.key();
+    let signer_seeds: &[&[&[u8]]] = &[
+        &[
+            b"treasury",
+            mint_key.as_ref(),
+            &[ctx.bumps.bank_token_account],
+        ]
+    ];
+    let transfer_ctx = CpiContext::new_with_signer(
+        ctx.accounts.token_program.to_account_info(),
+        TransferChecked {
+            authority: ctx.accounts.bank_token_account.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            from: ctx.accounts.bank_token_account.to_account_info(),
+            to: ctx.accounts.destination_token_account.to_account_info(),
+        },
+        signer_seeds,
+    );
+    transfer_checked(transfer_ctx, net_amount, ctx.accounts.mint.decimals)?;
+
+    emit_cpi!(Withdrawn {
+        user: ctx.accounts.signer.key(),
+        bank: ctx.accounts.bank.key(),
+        mint: ctx.accounts.mint.key(),
+        amount,
+        fee,
+        shares: receipt_amount,
+        supply_index: ctx.accounts.bank.supply_index,
+        timestamp: current_time,
+    });
+
+    debug_msg!("Redeemed {} receipt tokens for {} tokens", receipt_amount, net_amount);
+    Ok(())
+}
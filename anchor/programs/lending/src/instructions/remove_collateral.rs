@@ -0,0 +1,172 @@
+use anchor_lang::prelude::*;
+use anchor_spl::associated_token::AssociatedToken;
+use anchor_spl::token_interface::{mint_to, Mint, MintTo, TokenAccount, TokenInterface};
+use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
+
+use crate::constants::{PAUSE_BORROW, PAUSE_WITHDRAW};
+use crate::error::ErrorCode;
+use crate::events::CollateralRemoved;
+use crate::state::{AmountMode, Bank, BorrowPosition, EModeCategory, ProtocolConfig, UserGlobalState, UserTokenState};
+use crate::math::Rounding;
+use crate::utils::*;
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct RemoveCollateral<'info> {
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        seeds = [mint.key().as_ref()],
+        bump,
+        constraint = !bank.flash_loan_active @ ErrorCode::BankLocked,
+    )]
+    pub bank: Box<Account<'info, Bank>>,
+
+    #[account(
+        mut,
+        seeds = [signer.key().as_ref(), mint.key().as_ref()],
+        bump,
+    )]
+    pub user_token_state: Box<Account<'info, UserTokenState>>,
+
+    #[account(
+        mut,
+        seeds = [b"receipt", mint.key().as_ref()],
+        bump,
+    )]
+    pub receipt_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init_if_needed,
+        payer = signer,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program,
+    )]
+    pub user_receipt_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"position",
+            signer.key().as_ref(),
+            borrow_position.collateral_mint.as_ref(),
+            borrow_position.borrow_mint.as_ref(),
+            &position_id.to_le_bytes(),
+        ],
+        bump,
+        constraint = borrow_position.active @ ErrorCode::AlreadyRepayed,
+    )]
+    pub borrow_position: Box<Account<'info, BorrowPosition>>,
+
+    #[account(
+        seeds = [borrow_position.borrow_mint.as_ref()],
+        bump,
+    )]
+    pub bank_borrow: Box<Account<'info, Bank>>,
+
+    pub price_update: Box<Account<'info, PriceUpdateV2>>,
+    pub price_update_borrow_token: Box<Account<'info, PriceUpdateV2>>,
+
+    #[account(
+        mut,
+        seeds = [b"user_global", signer.key().as_ref()],
+        bump,
+    )]
+    pub user_global_state: Box<Account<'info, UserGlobalState>>,
+
+    #[account(
+        seeds = [b"emode".as_ref(), &[user_global_state.emode_category]],
+        bump = emode_category.bump,
+    )]
+    pub emode_category: Option<Box<Account<'info, EModeCategory>>>,
+
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Releases locked shares of `mint` from an open position back to the
+/// signer's free deposits, as long as the position's debt stays within the
+/// `max_ltv`-weighted value of what is left locked. `Max` releases all of it.
+///
+/// `remaining_accounts` hold `[bank, price_update]` for each of the
+/// position's other collaterals, in position order.
+pub fn process_remove_collateral<'info>(
+    ctx: Context<'_, '_, 'info, 'info, RemoveCollateral<'info>>,
+    _position_id: u64,
+    mode: AmountMode,
+) -> Result<()> {
+    // Released shares can be withdrawn, and leave the position more leveraged.
+    require_not_paused(&ctx.accounts.protocol_config, &ctx.accounts.bank, PAUSE_WITHDRAW | PAUSE_BORROW)?;
+    let current_time = Clock::get()?.unix_timestamp;
+    let bank = &mut ctx.accounts.bank;
+    let user_state = &mut ctx.accounts.user_token_state;
+    let position = &mut ctx.accounts.borrow_position;
+
+    let locked_shares = position_collateral_shares(position, &bank.mint_address);
+    let shares = match mode {
+        AmountMode::Tokens(amount) => calculate_deposit_shares(bank, amount, Rounding::Up)?,
+        AmountMode::Shares(shares) => shares,
+        AmountMode::Max => locked_shares,
+    };
+    debug_msg!("Removing {} of {} locked shares of {} from position {}", shares, locked_shares, bank.mint_address, position.key());
+    require!(shares > 0, ErrorCode::InvalidAmountMode);
+    require!(shares <= locked_shares, ErrorCode::InsufficientCollateral);
+
+    remove_position_collateral(position, &bank.mint_address, shares)?;
+    unlock_collateral_shares(bank, user_state, shares, current_time)?;
+    position.last_updated = current_time;
+
+    // Value what is left at current prices against the position's debt.
+    if position.borrowed_shares > 0 {
+        let emode = user_emode(&ctx.accounts.user_global_state, &ctx.accounts.emode_category)?;
+        let price = get_validated_price(&ctx.accounts.price_update, bank)?;
+        let borrow_price = get_validated_price(&ctx.accounts.price_update_borrow_token, &ctx.accounts.bank_borrow)?;
+        let borrow_limit = position_borrow_limit(position, &[(&**bank, price)], ctx.remaining_accounts, emode)?;
+        let debt_value = debt_value(&ctx.accounts.bank_borrow, position.borrowed_shares, &borrow_price, current_time)?;
+        debug_msg!("Position debt {} against borrow limit {} after removal", debt_value, borrow_limit);
+        require!(debt_value <= borrow_limit, ErrorCode::InsufficientCollateral);
+    } else if position.collaterals.iter().all(|collateral| collateral.shares == 0) {
+        debug_msg!("No debt or collateral left, deactivating position");
+        position.active = false;
+        let position_key = position.key();
+        ctx.accounts.user_global_state.active_positions.retain(|pos| pos != &position_key);
+    }
+
+    // Released shares are free again, so they are handed back as receipts.
+    let mint_key = ctx.accounts.mint.key();
+    let bank_seeds: &[&[&[u8]]] = &[&[mint_key.as_ref(), &[ctx.bumps.bank]]];
+    mint_to(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            MintTo {
+                mint: ctx.accounts.receipt_mint.to_account_info(),
+                to: ctx.accounts.user_receipt_account.to_account_info(),
+                authority: bank.to_account_info(),
+            },
+            bank_seeds,
+        ),
+        shares,
+    )?;
+
+    emit_cpi!(CollateralRemoved {
+        user: ctx.accounts.signer.key(),
+        position: position.key(),
+        bank: bank.key(),
+        shares,
+        timestamp: current_time,
+    });
+    Ok(())
+}
@@ -1,12 +1,14 @@
-use std::ops::{Div, Mul};
-
 use anchor_lang::prelude::*;
-use anchor_spl::{associated_token::AssociatedToken, token_interface::{TokenInterface, Mint, TokenAccount, TransferChecked, transfer_checked}};
+use anchor_spl::{associated_token::AssociatedToken, token_interface::{TokenInterface, Mint, MintTo, TokenAccount, TransferChecked, mint_to, transfer_checked}};
 use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
-use crate::state::{Bank, PythNetworkFeedId, UserTokenState, BorrowPosition, UserGlobalState};
+use crate::state::{AmountMode, Bank, UserTokenState, BorrowPosition, UserGlobalState, ProtocolConfig, EModeCategory, PositionCollateral};
+use crate::constants::PAUSE_REPAY;
 use crate::error::{ErrorCode};
+use crate::events::Repaid;
+use crate::math::{Decimal, Rounding};
 use crate::utils::*;
 
+#[event_cpi]
 #[derive(Accounts)]
 #[instruction(position_id: u64)]
 pub struct Repay<'info> {
@@ -14,12 +16,16 @@ pub struct Repay<'info> {
     pub signer: Signer<'info>,
 
     pub mint_borrow: Box<InterfaceAccount<'info, Mint>>,
+
+    // Both banks and user token states are written, so they must be distinct accounts
+    #[account(constraint = mint_collateral.key() != mint_borrow.key() @ ErrorCode::SameBorrowAndCollateralMint)]
     pub mint_collateral: Box<InterfaceAccount<'info, Mint>>,
 
     #[account(
         mut, 
         seeds = [mint_borrow.key().as_ref()],
         bump,
+        constraint = !bank_borrow.flash_loan_active @ ErrorCode::BankLocked,
     )]  
     pub bank_borrow: Box<Account<'info, Bank>>,
 
@@ -52,6 +58,7 @@ pub struct Repay<'info> {
         mut, 
         seeds = [mint_collateral.key().as_ref()],
         bump,
+        constraint = !bank_collateral.flash_loan_active @ ErrorCode::BankLocked,
     )]  
     pub bank_collateral: Box<Account<'info, Bank>>,
 
@@ -80,12 +87,32 @@ pub struct Repay<'info> {
     )]
     pub user_collateral_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
 
+    #[account(
+        mut,
+        seeds = [b"receipt", mint_collateral.key().as_ref()],
+        bump,
+    )]
+    pub receipt_mint_collateral: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init_if_needed,
+        payer = signer,
+        associated_token::mint = receipt_mint_collateral,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program,
+    )]
+    pub user_collateral_receipt_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
     pub price_update_borrow_token: Box<Account<'info, PriceUpdateV2>>,
-    pub pyth_network_feed_id_borrow_token: Box<Account<'info, PythNetworkFeedId>>,
     pub price_update_collateral_token: Box<Account<'info, PriceUpdateV2>>,
-    pub pyth_network_feed_id_collateral_token: Box<Account<'info, PythNetworkFeedId>>,
 
     pub associated_token_program: Program<'info, AssociatedToken>,
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
     pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
 
@@ -108,209 +135,308 @@ pub struct Repay<'info> {
         bump,
     )]
     pub user_global_state: Account<'info, UserGlobalState>,
+
+    #[account(
+        seeds = [b"emode".as_ref(), &[user_global_state.emode_category]],
+        bump = emode_category.bump,
+    )]
+    pub emode_category: Option<Box<Account<'info, EModeCategory>>>,
 }
 
-pub fn process_repay(ctx: Context<Repay>, _position_id: u64, amount: u64) -> Result<()> {
+/// Repays debt on position `position_id` and unlocks each of its collaterals
+/// in proportion to the debt repaid: `mint_collateral` and the position's
+/// other collaterals, whose accounts are passed in `remaining_accounts` in
+/// position order (see `load_extra_collaterals`).
+pub fn process_repay<'info>(
+    ctx: Context<'_, '_, 'info, 'info, Repay<'info>>,
+    _position_id: u64,
+    mode: AmountMode,
+) -> Result<()> {
+    require_not_paused(&ctx.accounts.protocol_config, &ctx.accounts.bank_borrow, PAUSE_REPAY)?;
     require!(ctx.accounts.borrow_position.active, ErrorCode::AlreadyRepayed);
 
-    msg!("Starting repay process for amount: {}", amount);
-    msg!("User: {}", ctx.accounts.signer.key());
-    msg!("Borrow mint: {}", ctx.accounts.mint_borrow.key());
-    msg!("Collateral mint: {}", ctx.accounts.mint_collateral.key());
+    debug_msg!("Starting repay process: {:?}", mode);
+    debug_msg!("User: {}", ctx.accounts.signer.key());
+    debug_msg!("Borrow mint: {}", ctx.accounts.mint_borrow.key());
+    debug_msg!("Collateral mint: {}", ctx.accounts.mint_collateral.key());
     
     let current_time = Clock::get()?.unix_timestamp;
-    msg!("Current timestamp: {}", current_time);
+    debug_msg!("Current timestamp: {}", current_time);
     
+    // Treasury balances drive each bank's utilization when accruing interest.
+    let borrow_liquidity = ctx.accounts.bank_borrow_token_account.amount;
+    let collateral_liquidity = ctx.accounts.bank_collateral_token_account.amount;
     let bank_borrow = &mut ctx.accounts.bank_borrow;
     let bank_collateral = &mut ctx.accounts.bank_collateral;
     let user_borrow = &mut ctx.accounts.user_borrow_account;
     let user_collateral = &mut ctx.accounts.user_collateral_account;
 
-    msg!("Bank borrow state before interest accrual:");
-    msg!("  Total borrowed shares: {}", bank_borrow.total_borrowed_shares);
-    msg!("  Last compound time: {}", bank_borrow.last_compound_time);
+    debug_msg!("Bank borrow state before interest accrual:");
+    debug_msg!("  Total borrowed shares: {}", bank_borrow.total_borrowed_shares);
+    debug_msg!("  Last compound time: {}", bank_borrow.last_compound_time);
     
-    msg!("Bank collateral state before interest accrual:");
-    msg!("  Total deposited shares: {}", bank_collateral.total_deposited_shares);
-    msg!("  Total collateral shares: {}", bank_collateral.total_collateral_shares);
-    msg!("  Last compound time: {}", bank_collateral.last_compound_time);
-
-    msg!("Accruing interest for borrow bank");
-    accrue_interest(bank_borrow, current_time)?;
-    msg!("Accruing interest for collateral bank");
-    accrue_interest(bank_collateral, current_time)?;
-
-    msg!("Bank borrow state after interest accrual:");
-    msg!("  Total borrowed shares: {}", bank_borrow.total_borrowed_shares);
-    msg!("  Last compound time: {}", bank_borrow.last_compound_time);
+    debug_msg!("Bank collateral state before interest accrual:");
+    debug_msg!("  Total deposited shares: {}", bank_collateral.total_deposited_shares);
+    debug_msg!("  Total collateral shares: {}", bank_collateral.total_collateral_shares);
+    debug_msg!("  Last compound time: {}", bank_collateral.last_compound_time);
+
+    debug_msg!("Accruing interest for borrow bank");
+    if let Some(event) = accrue_interest(bank_borrow, borrow_liquidity, current_time)? {
+        emit_cpi!(event);
+    }
+    debug_msg!("Accruing interest for collateral bank");
+    if let Some(event) = accrue_interest(bank_collateral, collateral_liquidity, current_time)? {
+        emit_cpi!(event);
+    }
+    sync_mint_interest_config(bank_borrow, &ctx.accounts.mint_borrow.to_account_info())?;
+    sync_mint_interest_config(bank_collateral, &ctx.accounts.mint_collateral.to_account_info())?;
+
+    debug_msg!("Bank borrow state after interest accrual:");
+    debug_msg!("  Total borrowed shares: {}", bank_borrow.total_borrowed_shares);
+    debug_msg!("  Last compound time: {}", bank_borrow.last_compound_time);
     
-    msg!("Bank collateral state after interest accrual:");
-    msg!("  Total deposited shares: {}", bank_collateral.total_deposited_shares);
-    msg!("  Total collateral shares: {}", bank_collateral.total_collateral_shares);
-    msg!("  Last compound time: {}", bank_collateral.last_compound_time);
+    debug_msg!("Bank collateral state after interest accrual:");
+    debug_msg!("  Total deposited shares: {}", bank_collateral.total_deposited_shares);
+    debug_msg!("  Total collateral shares: {}", bank_collateral.total_collateral_shares);
+    debug_msg!("  Last compound time: {}", bank_collateral.last_compound_time);
 
-    msg!("User borrow state:");
-    msg!("  Borrowed shares: {}", user_borrow.borrowed_shares);
-    msg!("  Last updated borrowed: {}", user_borrow.last_updated_borrowed);
+    debug_msg!("User borrow state:");
+    debug_msg!("  Borrowed shares: {}", user_borrow.borrowed_shares);
+    debug_msg!("  Last updated borrowed: {}", user_borrow.last_updated_borrowed);
 
-    msg!("User collateral state:");
-    msg!("  Deposited shares: {}", user_collateral.deposited_shares);
-    msg!("  Collateral shares: {}", user_collateral.collateral_shares);
-    msg!("  Last updated deposited: {}", user_collateral.last_updated_deposited);
-    msg!("  Last updated collateral: {}", user_collateral.last_updated_collateral);
+    debug_msg!("User collateral state:");
+    debug_msg!("  Deposited shares: {}", user_collateral.deposited_shares);
+    debug_msg!("  Collateral shares: {}", user_collateral.collateral_shares);
+    debug_msg!("  Last updated deposited: {}", user_collateral.last_updated_deposited);
+    debug_msg!("  Last updated collateral: {}", user_collateral.last_updated_collateral);
 
     // ------------------------------------------------------------------
-    // The client passes `amount` as the **borrow shares** it wants to repay
-    // (see tests which call `repay(position.borrowedShares)`).
-    // Convert those shares to the equivalent token amount for the token
-    // transfer and USD calculations.
+    // Resolve the request into the borrow shares to burn. Token amounts
+    // round shares down and are capped at the position's debt, so paying
+    // more than is owed simply repays the position in full.
     // ------------------------------------------------------------------
+    let position_shares = ctx.accounts.borrow_position.borrowed_shares;
+    let shares_to_burn = match mode {
+        AmountMode::Tokens(amount) => {
+            calculate_borrow_shares(bank_borrow, amount, Rounding::Down)?.min(position_shares)
+        }
+        AmountMode::Shares(shares) => shares,
+        AmountMode::Max => position_shares,
+    };
+    require!(shares_to_burn > 0, ErrorCode::InvalidRepayAmount);
 
-    let shares_to_burn = amount; // interpret param as shares
-
-    msg!("Calculating token amount that corresponds to {} borrow shares", shares_to_burn);
-    let total_borrowed_assets = calculate_borrowed_assets(bank_borrow);
-    msg!("Total borrowed assets in bank (tokens): {}", total_borrowed_assets);
+    debug_msg!("Calculating token amount that corresponds to {} borrow shares", shares_to_burn);
+    debug_msg!("Borrow index: {}", Decimal::from_scaled_val(bank_borrow.borrow_index));
 
-    let token_amount = if bank_borrow.total_borrowed_shares == 0 {
-        0u64
-    } else {
-        ((shares_to_burn as u128)
-            .checked_mul(total_borrowed_assets)
-            .and_then(|v| v.checked_div(bank_borrow.total_borrowed_shares as u128))
-            .ok_or(ErrorCode::MathOverflow)?) as u64
-    };
-    msg!("Token amount to transfer back: {}", token_amount);
+    // Round up so the borrower never repays less than the shares are worth.
+    let token_amount = calculate_debt_amount(bank_borrow, shares_to_burn, Rounding::Up)?;
+    debug_msg!("Token amount to transfer back: {}", token_amount);
 
-    // Validate the user is not over-repaying in shares
-    if shares_to_burn > user_borrow.borrowed_shares {
-        msg!("ERROR: Attempting to repay more borrow shares than owed: {} > {}", shares_to_burn, user_borrow.borrowed_shares);
+    // The position's own debt bounds the repayment, not the user's total in
+    // this mint, which may be spread over several positions.
+    if shares_to_burn > position_shares {
+        debug_msg!("ERROR: Attempting to repay more borrow shares than the position owes: {} > {}", shares_to_burn, position_shares);
         return Err(ErrorCode::OverRepayRequest.into());
     }
 
+    // Gross up for any transfer fee on the mint, then burn debt only for
+    // what the treasury actually received.
+    let transfer_amount = amount_with_transfer_fee(&ctx.accounts.mint_borrow.to_account_info(), token_amount)?;
+    debug_msg!("Transferring {} tokens from user to bank", transfer_amount);
+    let transfer_ctx = CpiContext::new(
+        ctx.accounts.token_program.to_account_info(),
+        TransferChecked {
+            authority: ctx.accounts.signer.to_account_info(),
+            from: ctx.accounts.user_borrow_token_account.to_account_info(),
+            mint: ctx.accounts.mint_borrow.to_account_info(),
+            to: ctx.accounts.bank_borrow_token_account.to_account_info(),
+        }
+    );
+    transfer_checked(transfer_ctx, transfer_amount, ctx.accounts.mint_borrow.decimals)?;
+    debug_msg!("Token transfer successful");
+
+    ctx.accounts.bank_borrow_token_account.reload()?;
+    let received = ctx.accounts.bank_borrow_token_account.amount
+        .checked_sub(borrow_liquidity)
+        .ok_or(ErrorCode::MathOverflow)?;
+    let shares_to_burn = calculate_borrow_shares(bank_borrow, received, Rounding::Down)?.min(shares_to_burn);
+    require!(shares_to_burn > 0, ErrorCode::InvalidRepayAmount);
+    let repaid_amount = received.min(token_amount);
+    let fully_repaid = shares_to_burn == position_shares;
+    debug_msg!("Treasury received {} tokens, burning {} borrow shares", received, shares_to_burn);
+
     // ------------------------------------------------------------------
-    // For stats / collateral unlock we still need the USD value of the
-    // *token* amount computed above.
+    // For stats we still need the USD value of the *token* amount
+    // computed above.
     // ------------------------------------------------------------------
 
-    msg!("Getting borrow token price from Pyth oracle");
+    debug_msg!("Getting borrow token price from Pyth oracle");
     let borrow_price = get_validated_price(
         &ctx.accounts.price_update_borrow_token,
-        &ctx.accounts.pyth_network_feed_id_borrow_token
+        bank_borrow,
+    )?;
+    let repay_amount_usd = calculate_token_value(
+        repaid_amount,
+        ctx.accounts.mint_borrow.decimals,
+        &borrow_price,
+        Rounding::Down,
     )?;
-    let repay_amount_usd = (token_amount as f64)
-        .mul(borrow_price.price as f64)
-        .div(10u128.pow((-1 * borrow_price.exponent) as u32) as f64)
-        .div(10u128.pow(ctx.accounts.mint_borrow.decimals as u32) as f64);
-    msg!("Repay amount in USD: {}", repay_amount_usd);
-
-    msg!("Getting collateral token price from Pyth oracle");
-    msg!("Collateral price feed account: {}", ctx.accounts.price_update_collateral_token.key());
-    msg!("Collateral Pyth network feed ID: {}", ctx.accounts.pyth_network_feed_id_collateral_token.key());
+    debug_msg!("Repay amount in USD: {}", repay_amount_usd);
+
+    debug_msg!("Getting collateral token price from Pyth oracle");
+    debug_msg!("Collateral price feed account: {}", ctx.accounts.price_update_collateral_token.key());
     let collateral_price = get_validated_price(
         &ctx.accounts.price_update_collateral_token,
-        &ctx.accounts.pyth_network_feed_id_collateral_token
+        bank_collateral,
     )?;
-    msg!("Collateral price: {} with exponent {}", collateral_price.price, collateral_price.exponent);
-    
-    msg!("Calculating collateral shares to unlock");
-    // Use ceil instead of floor so that full repayment unlocks exactly the locked collateral.
-    let calculated_shares = ((repay_amount_usd * 
-        (10u128.pow(ctx.accounts.mint_collateral.decimals as u32) as f64) * 
-        (10u128.pow((-1 * collateral_price.exponent) as u32) as f64))
-        / (collateral_price.price as f64)).ceil() as u64;
-    msg!("Raw calculated collateral shares to unlock: {}", calculated_shares);
-
-    let mut collateral_shares_to_unlock = calculated_shares.min(ctx.accounts.borrow_position.collateral_shares)
-                                                      .min(user_collateral.collateral_shares);
-    msg!("Adjusted collateral shares to unlock: {}", collateral_shares_to_unlock);
-    msg!("Position collateral shares: {}", ctx.accounts.borrow_position.collateral_shares);
-    msg!("User collateral shares: {}", user_collateral.collateral_shares);
+    debug_msg!("Collateral price: {} with exponent {}", collateral_price.price, collateral_price.exponent);
     
-    msg!("Transferring {} tokens from user to bank", token_amount);
-    let transfer_ctx = CpiContext::new(
-        ctx.accounts.token_program.to_account_info(),
-        TransferChecked {
-            authority: ctx.accounts.signer.to_account_info(),
-            from: ctx.accounts.user_borrow_token_account.to_account_info(),
-            mint: ctx.accounts.mint_borrow.to_account_info(),
-            to: ctx.accounts.bank_borrow_token_account.to_account_info(),
-        }
-    );
-    transfer_checked(transfer_ctx, token_amount, ctx.accounts.mint_borrow.decimals)?;
-    msg!("Token transfer successful");
+    debug_msg!("Calculating collateral shares to unlock");
+    // Each collateral is released in the proportion of the position's debt
+    // repaid, rounded down, so a partial repay never leaves the rest of the
+    // debt less backed than before whatever prices did since the borrow.
+    // Once the position's debt is gone all of its collateral is released.
+    let borrowed_shares = ctx.accounts.borrow_position.borrowed_shares;
+    let position_collaterals = ctx.accounts.borrow_position.collaterals.clone();
+    let (mut extra_collaterals, _) = load_extra_collaterals(
+        &ctx.accounts.signer.key(),
+        ctx.remaining_accounts,
+        position_collaterals.len().saturating_sub(1),
+    )?;
+    for (collateral, entry) in extra_collaterals.iter().zip(position_collaterals.iter().skip(1)) {
+        require_keys_eq!(collateral.bank.mint_address, entry.mint, ErrorCode::InvalidCollateralAccounts);
+    }
+
+    let position_shares = position_collateral_shares(&ctx.accounts.borrow_position, &bank_collateral.mint_address);
+    debug_msg!("Position collateral shares: {}", position_shares);
+    debug_msg!("User collateral shares: {}", user_collateral.collateral_shares);
+    let collateral_shares_to_unlock = if fully_repaid {
+        position_shares.min(user_collateral.collateral_shares)
+    } else {
+        pro_rata_collateral_shares(position_shares.min(user_collateral.collateral_shares), shares_to_burn, borrowed_shares)?
+    };
+    debug_msg!("Collateral shares to unlock: {}", collateral_shares_to_unlock);
+    let mut extra_collateral_unlocked = Vec::with_capacity(extra_collaterals.len());
+    for (collateral, entry) in extra_collaterals.iter().zip(position_collaterals.iter().skip(1)) {
+        let locked_shares = entry.shares.min(collateral.user_state.collateral_shares);
+        let shares = if fully_repaid {
+            locked_shares
+        } else {
+            pro_rata_collateral_shares(locked_shares, shares_to_burn, borrowed_shares)?
+        };
+        debug_msg!("Extra collateral {}: unlocking {} shares", entry.mint, shares);
+        extra_collateral_unlocked.push(PositionCollateral { mint: entry.mint, shares });
+    }
+
 
-    msg!("Updating bank borrow state");
-    msg!("  Old total borrowed shares: {}", bank_borrow.total_borrowed_shares);
+    debug_msg!("Updating bank borrow state");
+    debug_msg!("  Old total borrowed shares: {}", bank_borrow.total_borrowed_shares);
     bank_borrow.total_borrowed_shares = bank_borrow.total_borrowed_shares
         .checked_sub(shares_to_burn)
         .ok_or(ErrorCode::MathOverflow)?;
-    msg!("  New total borrowed shares: {}", bank_borrow.total_borrowed_shares);
+    debug_msg!("  New total borrowed shares: {}", bank_borrow.total_borrowed_shares);
     
-    msg!("Updating user borrow state");
-    msg!("  Old borrowed shares: {}", user_borrow.borrowed_shares);
+    debug_msg!("Updating user borrow state");
+    debug_msg!("  Old borrowed shares: {}", user_borrow.borrowed_shares);
     user_borrow.borrowed_shares = user_borrow.borrowed_shares
         .checked_sub(shares_to_burn)
         .ok_or(ErrorCode::MathOverflow)?;
     user_borrow.last_updated_borrowed = current_time;
-    msg!("  New borrowed shares: {}", user_borrow.borrowed_shares);
-    msg!("  Updated last_updated_borrowed to: {}", user_borrow.last_updated_borrowed);
+    debug_msg!("  New borrowed shares: {}", user_borrow.borrowed_shares);
+    debug_msg!("  Updated last_updated_borrowed to: {}", user_borrow.last_updated_borrowed);
 
     let position = &mut ctx.accounts.borrow_position;
-    msg!("Updating borrow position");
-    msg!("  Current position state:");
-    msg!("    Collateral shares: {}", position.collateral_shares);
-    msg!("    Borrowed shares: {}", position.borrowed_shares);
-    msg!("    Active: {}", position.active);
-    msg!("    Last updated: {}", position.last_updated);
-
-    if user_borrow.borrowed_shares == 0 {
-        msg!("User has fully repaid the loan, deactivating position");
+    debug_msg!("Updating borrow position");
+    debug_msg!("  Current position state:");
+    debug_msg!("    Collaterals: {:?}", position.collaterals);
+    debug_msg!("    Borrowed shares: {}", position.borrowed_shares);
+    debug_msg!("    Active: {}", position.active);
+    debug_msg!("    Last updated: {}", position.last_updated);
+
+    release_isolated_debt(bank_collateral, position, shares_to_burn)?;
+    position.borrowed_shares = position.borrowed_shares
+        .checked_sub(shares_to_burn)
+        .ok_or(ErrorCode::MathOverflow)?;
+    if fully_repaid {
+        debug_msg!("Position fully repaid, deactivating it");
         position.active = false;
-        position.borrowed_shares = 0;
         let global_state = &mut ctx.accounts.user_global_state;
-        msg!("  Current active positions: {:?}", global_state.active_positions);
+        debug_msg!("  Current active positions: {:?}", global_state.active_positions);
         global_state.active_positions.retain(|pos| pos != &position.key());
-        msg!("  Updated active positions: {:?}", global_state.active_positions);
+        debug_msg!("  Updated active positions: {:?}", global_state.active_positions);
     } else {
-        msg!("Partial repayment, position remains active");
-        msg!("  Remaining borrowed shares: {}", user_borrow.borrowed_shares);
+        debug_msg!("Partial repayment, position remains active");
+        debug_msg!("  Remaining borrowed shares: {}", position.borrowed_shares);
     }
     
-    msg!("Unlocking collateral shares: {}", collateral_shares_to_unlock);
-    position.collateral_shares = position.collateral_shares.checked_sub(collateral_shares_to_unlock).ok_or(ErrorCode::MathOverflow)?;
+    debug_msg!("Unlocking collateral shares: {}", collateral_shares_to_unlock);
+    remove_position_collateral(position, &bank_collateral.mint_address, collateral_shares_to_unlock)?;
+    for (collateral, unlocked) in extra_collaterals.iter_mut().zip(extra_collateral_unlocked.iter()) {
+        if unlocked.shares > 0 {
+            remove_position_collateral(position, &unlocked.mint, unlocked.shares)?;
+            collateral.unlock(unlocked.shares, &ctx.accounts.token_program, current_time)?;
+        }
+    }
+    extra_collateral_unlocked.retain(|unlocked| unlocked.shares > 0);
     position.last_updated = current_time;
-    msg!("  Updated position collateral shares: {}", position.collateral_shares);
-    msg!("  Updated position last_updated: {}", position.last_updated);
-
-    msg!("Updating bank collateral state");
-    msg!("  Old total collateral shares: {}", bank_collateral.total_collateral_shares);
-    msg!("  Old total deposited shares: {}", bank_collateral.total_deposited_shares);
-    bank_collateral.total_collateral_shares = bank_collateral.total_collateral_shares.checked_sub(collateral_shares_to_unlock).ok_or(ErrorCode::MathOverflow)?;
-    bank_collateral.total_deposited_shares = bank_collateral.total_deposited_shares.checked_add(collateral_shares_to_unlock).ok_or(ErrorCode::MathOverflow)?;
-    msg!("  New total collateral shares: {}", bank_collateral.total_collateral_shares);
-    msg!("  New total deposited shares: {}", bank_collateral.total_deposited_shares);
-
-    msg!("Updating user collateral state");
-    msg!("  Old collateral shares: {}", user_collateral.collateral_shares);
-    msg!("  Old deposited shares: {}", user_collateral.deposited_shares);
-    user_collateral.collateral_shares = user_collateral.collateral_shares.checked_sub(collateral_shares_to_unlock).ok_or(ErrorCode::MathOverflow)?;
-    user_collateral.last_updated_collateral = current_time;
-    user_collateral.deposited_shares = user_collateral.deposited_shares.checked_add(collateral_shares_to_unlock).ok_or(ErrorCode::MathOverflow)?;
-    user_collateral.last_updated_deposited = current_time;
-    msg!("  New collateral shares: {}", user_collateral.collateral_shares);
-    msg!("  New deposited shares: {}", user_collateral.deposited_shares);
-    msg!("  Updated timestamps to: {}", current_time);
-
-    msg!("Repay successful");
-    msg!("Summary:");
-    msg!("  Repaid shares: {}", shares_to_burn);
-    msg!("  Token amount transferred: {}", token_amount);
-    msg!("  Unlocked collateral shares: {}", collateral_shares_to_unlock);
-    msg!("  Repay value in USD: {}", repay_amount_usd);
-    msg!("  Remaining borrowed shares: {}", user_borrow.borrowed_shares);
-    msg!("  Remaining collateral shares: {}", user_collateral.collateral_shares);
-    msg!("  Returned to deposited shares: {}", collateral_shares_to_unlock);
+    debug_msg!("  Updated position collaterals: {:?}", position.collaterals);
+    debug_msg!("  Updated position last_updated: {}", position.last_updated);
+
+    debug_msg!("Updating bank collateral state");
+    debug_msg!("  Old total collateral shares: {}", bank_collateral.total_collateral_shares);
+    debug_msg!("  Old total deposited shares: {}", bank_collateral.total_deposited_shares);
+    debug_msg!("  Old user collateral shares: {}", user_collateral.collateral_shares);
+    debug_msg!("  Old user deposited shares: {}", user_collateral.deposited_shares);
+    unlock_collateral_shares(bank_collateral, user_collateral, collateral_shares_to_unlock, current_time)?;
+    debug_msg!("  New total collateral shares: {}", bank_collateral.total_collateral_shares);
+    debug_msg!("  New total deposited shares: {}", bank_collateral.total_deposited_shares);
+    debug_msg!("  New user collateral shares: {}", user_collateral.collateral_shares);
+    debug_msg!("  New user deposited shares: {}", user_collateral.deposited_shares);
+
+    // Unlocked shares are free again, so they are handed back as receipts.
+    debug_msg!("Minting {} collateral receipt tokens", collateral_shares_to_unlock);
+    let mint_collateral_key = ctx.accounts.mint_collateral.key();
+    let bank_seeds: &[&[&[u8]]] = &[&[mint_collateral_key.as_ref(), &[ctx.bumps.bank_collateral]]];
+    mint_to(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            MintTo {
+                mint: ctx.accounts.receipt_mint_collateral.to_account_info(),
+                to: ctx.accounts.user_collateral_receipt_account.to_account_info(),
+                authority: bank_collateral.to_account_info(),
+            },
+            bank_seeds,
+        ),
+        collateral_shares_to_unlock,
+    )?;
+
+    emit_cpi!(Repaid {
+        user: ctx.accounts.signer.key(),
+        position: ctx.accounts.borrow_position.key(),
+        bank_borrow: bank_borrow.key(),
+        bank_collateral: bank_collateral.key(),
+        amount: received,
+        borrow_shares: shares_to_burn,
+        collateral_shares_unlocked: collateral_shares_to_unlock,
+        extra_collateral_unlocked,
+        borrow_index: bank_borrow.borrow_index,
+        borrow_price: borrow_price.price,
+        borrow_price_exponent: borrow_price.exponent,
+        collateral_price: collateral_price.price,
+        collateral_price_exponent: collateral_price.exponent,
+        timestamp: current_time,
+    });
+
+    debug_msg!("Repay successful");
+    debug_msg!("Summary:");
+    debug_msg!("  Repaid shares: {}", shares_to_burn);
+    debug_msg!("  Token amount received: {}", received);
+    debug_msg!("  Unlocked collateral shares: {}", collateral_shares_to_unlock);
+    debug_msg!("  Repay value in USD: {}", repay_amount_usd);
+    debug_msg!("  Remaining position borrowed shares: {}", ctx.accounts.borrow_position.borrowed_shares);
+    debug_msg!("  Remaining borrowed shares: {}", user_borrow.borrowed_shares);
+    debug_msg!("  Remaining collateral shares: {}", user_collateral.collateral_shares);
+    debug_msg!("  Returned to deposited shares: {}", collateral_shares_to_unlock);
 
     Ok(())
 }
@@ -0,0 +1,67 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::Mint;
+
+use crate::constants::PAUSE_ALL;
+use crate::error::ErrorCode;
+use crate::state::{Bank, ProtocolConfig};
+
+#[derive(Accounts)]
+pub struct SetProtocolPause<'info> {
+    #[account(
+        constraint = signer.key() == protocol_config.admin
+            || signer.key() == protocol_config.guardian @ ErrorCode::Unauthorized,
+    )]
+    pub signer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+}
+
+#[derive(Accounts)]
+pub struct SetBankPause<'info> {
+    #[account(
+        constraint = signer.key() == protocol_config.admin
+            || signer.key() == protocol_config.guardian @ ErrorCode::Unauthorized,
+    )]
+    pub signer: Signer<'info>,
+
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        seeds = [mint.key().as_ref()],
+        bump,
+    )]
+    pub bank: Box<Account<'info, Bank>>,
+
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+}
+
+/// Replaces the protocol-wide pause mask. Flags can be set independently, so
+/// e.g. repay and liquidate may stay open while borrowing is frozen.
+pub fn process_set_protocol_pause(ctx: Context<SetProtocolPause>, paused_operations: u8) -> Result<()> {
+    require!(paused_operations & !PAUSE_ALL == 0, ErrorCode::InvalidPauseFlags);
+
+    let protocol_config = &mut ctx.accounts.protocol_config;
+    debug_msg!("Protocol paused operations: {:#07b} -> {:#07b}", protocol_config.paused_operations, paused_operations);
+    protocol_config.paused_operations = paused_operations;
+    Ok(())
+}
+
+/// Replaces the pause mask of a single bank.
+pub fn process_set_bank_pause(ctx: Context<SetBankPause>, paused_operations: u8) -> Result<()> {
+    require!(paused_operations & !PAUSE_ALL == 0, ErrorCode::InvalidPauseFlags);
+
+    let bank = &mut ctx.accounts.bank;
+    debug_msg!("Bank {} paused operations: {:#07b} -> {:#07b}", bank.mint_address, bank.paused_operations, paused_operations);
+    bank.paused_operations = paused_operations;
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use anchor_lang::prelude::*;
+use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
+
+use crate::error::ErrorCode;
+use crate::state::{Bank, BorrowPosition, EModeCategory, UserGlobalState};
+use crate::utils::{calculate_account_health, debt_value, get_validated_price, position_borrow_limit};
+
+#[derive(Accounts)]
+#[instruction(category_id: u8)]
+pub struct SetUserEMode<'info> {
+    pub signer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_global", signer.key().as_ref()],
+        bump,
+    )]
+    pub user_global_state: Box<Account<'info, UserGlobalState>>,
+
+    // Required unless leaving e-mode (`category_id` 0)
+    #[account(
+        seeds = [b"emode".as_ref(), &[category_id]],
+        bump = emode_category.bump,
+    )]
+    pub emode_category: Option<Box<Account<'info, EModeCategory>>>,
+}
+
+/// Opts the signer into e-mode category `category_id`, or out of e-mode with 0.
+///
+/// Every bank the user has collateral locked in or debt in must belong to
+/// the new category, and the account and each of its positions must still be
+/// within their borrow limits under the new parameters. `remaining_accounts`
+/// are the account health accounts described on `calculate_account_health`;
+/// the positions are valued with the banks and prices listed there.
+pub fn process_set_user_emode<'info>(
+    ctx: Context<'_, '_, 'info, 'info, SetUserEMode<'info>>,
+    category_id: u8,
+) -> Result<()> {
+    let emode = if category_id == 0 {
+        None
+    } else {
+        Some(&***ctx.accounts.emode_category.as_ref().ok_or(ErrorCode::InvalidEModeCategory)?)
+    };
+
+    if !ctx.accounts.user_global_state.active_positions.is_empty() {
+        let current_time = Clock::get()?.unix_timestamp;
+        let health = calculate_account_health(
+            &ctx.accounts.signer.key(),
+            &ctx.accounts.user_global_state,
+            &[],
+            ctx.remaining_accounts,
+            emode,
+            current_time,
+        )?;
+        require!(!health.has_positions_outside_emode, ErrorCode::EModeCategoryMismatch);
+        debug_msg!("Debt value {} against borrow limit {} in category {}", health.debt_value, health.borrow_limit, category_id);
+        require!(health.debt_value <= health.borrow_limit, ErrorCode::BorrowAmountTooLarge);
+
+        // Leaving or changing a category can lower the weights of one
+        // position's collateral while others keep the account healthy.
+        let positions_len = ctx.accounts.user_global_state.active_positions.len();
+        let (position_infos, asset_infos) = ctx.remaining_accounts.split_at(positions_len);
+        let mut mints = ctx.accounts.user_global_state.deposited_mints.clone();
+        mints.sort();
+        mints.dedup();
+        let mut banks = Vec::with_capacity(mints.len());
+        for tuple in asset_infos.chunks_exact(4).take(mints.len()) {
+            let bank = Account::<Bank>::try_from(&tuple[0])?;
+            let price_update = Account::<PriceUpdateV2>::try_from(&tuple[3])?;
+            let price = get_validated_price(&price_update, &bank)?;
+            banks.push((bank, price));
+        }
+        let known_banks: Vec<(&Bank, _)> = banks.iter().map(|(bank, price)| (&**bank, *price)).collect();
+
+        for info in position_infos {
+            let position = Account::<BorrowPosition>::try_from(info)?;
+            if position.borrowed_shares == 0 {
+                continue;
+            }
+            let (bank_borrow, borrow_price) = known_banks
+                .iter()
+                .find(|(bank, _)| bank.mint_address == position.borrow_mint)
+                .ok_or(ErrorCode::InvalidHealthAccounts)?;
+            let debt_value = debt_value(bank_borrow, position.borrowed_shares, borrow_price, current_time)?;
+            let borrow_limit = position_borrow_limit(&position, &known_banks, &[], emode)?;
+            debug_msg!("Position {} debt {} against borrow limit {}", info.key(), debt_value, borrow_limit);
+            require!(debt_value <= borrow_limit, ErrorCode::InsufficientCollateral);
+        }
+    }
+
+    let global_state = &mut ctx.accounts.user_global_state;
+    debug_msg!("E-mode category: {} -> {}", global_state.emode_category, category_id);
+    global_state.emode_category = category_id;
+    Ok(())
+}
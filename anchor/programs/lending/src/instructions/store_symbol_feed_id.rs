@@ -1,6 +1,7 @@
 use anchor_lang::prelude::*;
 
-use crate::state::PythNetworkFeedId;
+use crate::error::ErrorCode;
+use crate::state::{ProtocolConfig, PythNetworkFeedId};
 
 #[derive(Accounts)]
 #[instruction(symbol: String)]
@@ -8,6 +9,13 @@ pub struct StoreSymbolFeedId<'info> {
     #[account(mut)]
     pub signer: Signer<'info>,
 
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = protocol_config.admin == signer.key() @ ErrorCode::Unauthorized,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
     #[account(
         init,
         payer = signer,
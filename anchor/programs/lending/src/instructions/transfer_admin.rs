@@ -0,0 +1,49 @@
+use anchor_lang::prelude::*;
+
+use crate::error::ErrorCode;
+use crate::state::ProtocolConfig;
+
+#[derive(Accounts)]
+pub struct ProposeAdmin<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    pub pending_admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = protocol_config.pending_admin == Some(pending_admin.key()) @ ErrorCode::Unauthorized,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+}
+
+/// First step of an admin handover: record the new admin, who must then call
+/// `accept_admin`. Proposing again replaces any earlier proposal.
+pub fn process_propose_admin(ctx: Context<ProposeAdmin>, new_admin: Pubkey) -> Result<()> {
+    let protocol_config = &mut ctx.accounts.protocol_config;
+    protocol_config.pending_admin = Some(new_admin);
+
+    debug_msg!("Admin {} proposed {} as new admin", protocol_config.admin, new_admin);
+    Ok(())
+}
+
+/// Second step of an admin handover, signed by the proposed admin.
+pub fn process_accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+    let protocol_config = &mut ctx.accounts.protocol_config;
+    debug_msg!("Admin: {} -> {}", protocol_config.admin, ctx.accounts.pending_admin.key());
+    protocol_config.admin = ctx.accounts.pending_admin.key();
+    protocol_config.pending_admin = None;
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount};
+
+use crate::error::ErrorCode;
+use crate::events::ConfigUpdated;
+use crate::state::{Bank, EModeCategory, OracleVerification, ProtocolConfig};
+use crate::utils::{accrue_interest, validate_bank_config};
+
+/// Parameters to change on a bank. Fields left as `None` keep their current value.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct BankConfigUpdate {
+    pub liquidation_threshold: Option<u64>,
+    pub liquidation_bonus: Option<u64>,
+    pub liquidation_close_factor: Option<u64>,
+    pub max_ltv: Option<u64>,
+    pub base_borrow_rate: Option<u64>,
+    pub optimal_utilization: Option<u64>,
+    pub borrow_rate_slope1: Option<u64>,
+    pub borrow_rate_slope2: Option<u64>,
+    pub reserve_factor: Option<u64>,
+    pub deposit_fee: Option<u64>,
+    pub withdrawal_fee: Option<u64>,
+    pub min_deposit: Option<u64>,
+    pub supply_cap: Option<u64>,
+    pub borrow_cap: Option<u64>,
+    pub interest_accrual_period: Option<i64>,
+    pub fee_recipient: Option<Pubkey>,
+    pub flash_loan_fee: Option<u64>,
+    pub oracle_feed_id: Option<[u8; 32]>,
+    pub oracle_owner: Option<Pubkey>,
+    pub oracle_max_age: Option<u64>,
+    pub oracle_max_confidence: Option<u64>,
+    pub oracle_min_verification: Option<OracleVerification>,
+    pub isolated: Option<bool>,
+    pub isolated_borrow_mints: Option<Vec<Pubkey>>,
+    pub isolated_debt_ceiling: Option<u64>,
+    pub emode_category: Option<u8>,
+}
+
+#[event_cpi]
+#[derive(Accounts)]
+#[instruction(update: BankConfigUpdate)]
+pub struct UpdateBankConfig<'info> {
+    pub authority: Signer<'info>,
+
+    // Banks follow the protocol admin, so a handover moves control of all of them
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        constraint = protocol_config.admin == authority.key() @ ErrorCode::Unauthorized,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        seeds = [mint.key().as_ref()],
+        bump,
+        constraint = !bank.flash_loan_active @ ErrorCode::BankLocked,
+    )]
+    pub bank: Box<Account<'info, Bank>>,
+
+    #[account(
+        token::mint = mint,
+        token::authority = bank_token_account,
+        seeds = [b"treasury", mint.key().as_ref()],
+        bump,
+    )]
+    pub bank_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    // Required when moving the bank into an e-mode category (nonzero `emode_category`)
+    #[account(
+        seeds = [b"emode".as_ref(), &[update.emode_category.unwrap_or_default()]],
+        bump = emode_category.bump,
+    )]
+    pub emode_category: Option<Box<Account<'info, EModeCategory>>>,
+}
+
+pub fn process_update_bank_config(ctx: Context<UpdateBankConfig>, update: BankConfigUpdate) -> Result<()> {
+    let liquidity = ctx.accounts.bank_token_account.amount;
+    let bank = &mut ctx.accounts.bank;
+
+    // Settle interest under the old parameters before they change.
+    let current_time = Clock::get()?.unix_timestamp;
+    if let Some(event) = accrue_interest(bank, liquidity, current_time)? {
+        emit_cpi!(event);
+    }
+
+    debug_msg!("Bank config before update:");
+    log_bank_config(bank);
+
+    let event = ConfigUpdated {
+        bank: bank.key(),
+        mint: bank.mint_address,
+        authority: ctx.accounts.authority.key(),
+        previous: previous_values(bank, &update),
+        update: update.clone(),
+        timestamp: current_time,
+    };
+
+    if let Some(value) = update.liquidation_threshold {
+        bank.liquidation_threshold = value;
+    }
+    if let Some(value) = update.liquidation_bonus {
+        bank.liquidation_bonus = value;
+    }
+    if let Some(value) = update.liquidation_close_factor {
+        bank.liquidation_close_factor = value;
+    }
+    if let Some(value) = update.max_ltv {
+        bank.max_ltv = value;
+    }
+    if let Some(value) = update.base_borrow_rate {
+        bank.base_borrow_rate = value;
+    }
+    if let Some(value) = update.optimal_utilization {
+        bank.optimal_utilization = value;
+    }
+    if let Some(value) = update.borrow_rate_slope1 {
+        bank.borrow_rate_slope1 = value;
+    }
+    if let Some(value) = update.borrow_rate_slope2 {
+        bank.borrow_rate_slope2 = value;
+    }
+    if let Some(value) = update.reserve_factor {
+        bank.reserve_factor = value;
+    }
+    if let Some(value) = update.deposit_fee {
+        bank.deposit_fee = value;
+    }
+    if let Some(value) = update.withdrawal_fee {
+        bank.withdrawal_fee = value;
+    }
+    if let Some(value) = update.min_deposit {
+        bank.min_deposit = value;
+    }
+    if let Some(value) = update.supply_cap {
+        bank.supply_cap = value;
+    }
+    if let Some(value) = update.borrow_cap {
+        bank.borrow_cap = value;
+    }
+    if let Some(value) = update.interest_accrual_period {
+        bank.interest_accrual_period = value;
+    }
+    if let Some(value) = update.fee_recipient {
+        bank.fee_recipient = value;
+    }
+    if let Some(value) = update.flash_loan_fee {
+        bank.flash_loan_fee = value;
+    }
+    if let Some(value) = update.oracle_feed_id {
+        bank.oracle_feed_id = value;
+    }
+    if let Some(value) = update.oracle_owner {
+        bank.oracle_owner = value;
+    }
+    if let Some(value) = update.oracle_max_age {
+        bank.oracle_max_age = value;
+    }
+    if let Some(value) = update.oracle_max_confidence {
+        bank.oracle_max_confidence = value;
+    }
+    if let Some(value) = update.oracle_min_verification {
+        bank.oracle_min_verification = value;
+    }
+    if let Some(value) = update.isolated {
+        // Positions backed by this bank were opened under the current
+        // isolation rules, so they only change once none lock its deposits.
+        require!(
+            value == bank.isolated || bank.total_collateral_shares == 0,
+            ErrorCode::IsolationLocked
+        );
+        bank.isolated = value;
+    }
+    if let Some(value) = update.isolated_borrow_mints {
+        bank.isolated_borrow_mints = value;
+    }
+    if let Some(value) = update.isolated_debt_ceiling {
+        bank.isolated_debt_ceiling = value;
+    }
+    if let Some(value) = update.emode_category {
+        require!(
+            value == 0 || ctx.accounts.emode_category.is_some(),
+            ErrorCode::InvalidEModeCategory
+        );
+        bank.emode_category = value;
+    }
+
+    validate_bank_config(bank)?;
+
+    debug_msg!("Bank config after update:");
+    log_bank_config(bank);
+
+    emit_cpi!(event);
+    Ok(())
+}
+
+/// The bank's current value for every field `update` sets, so the event
+/// records what each change replaced.
+fn previous_values(bank: &Bank, update: &BankConfigUpdate) -> BankConfigUpdate {
+    BankConfigUpdate {
+        liquidation_threshold: update.liquidation_threshold.map(|_| bank.liquidation_threshold),
+        liquidation_bonus: update.liquidation_bonus.map(|_| bank.liquidation_bonus),
+        liquidation_close_factor: update.liquidation_close_factor.map(|_| bank.liquidation_close_factor),
+        max_ltv: update.max_ltv.map(|_| bank.max_ltv),
+        base_borrow_rate: update.base_borrow_rate.map(|_| bank.base_borrow_rate),
+        optimal_utilization: update.optimal_utilization.map(|_| bank.optimal_utilization),
+        borrow_rate_slope1: update.borrow_rate_slope1.map(|_| bank.borrow_rate_slope1),
+        borrow_rate_slope2: update.borrow_rate_slope2.map(|_| bank.borrow_rate_slope2),
+        reserve_factor: update.reserve_factor.map(|_| bank.reserve_factor),
+        deposit_fee: update.deposit_fee.map(|_| bank.deposit_fee),
+        withdrawal_fee: update.withdrawal_fee.map(|_| bank.withdrawal_fee),
+        min_deposit: update.min_deposit.map(|_| bank.min_deposit),
+        supply_cap: update.supply_cap.map(|_| bank.supply_cap),
+        borrow_cap: update.borrow_cap.map(|_| bank.borrow_cap),
+        interest_accrual_period: update.interest_accrual_period.map(|_| bank.interest_accrual_period),
+        fee_recipient: update.fee_recipient.map(|_| bank.fee_recipient),
+        flash_loan_fee: update.flash_loan_fee.map(|_| bank.flash_loan_fee),
+        oracle_feed_id: update.oracle_feed_id.map(|_| bank.oracle_feed_id),
+        oracle_owner: update.oracle_owner.map(|_| bank.oracle_owner),
+        oracle_max_age: update.oracle_max_age.map(|_| bank.oracle_max_age),
+        oracle_max_confidence: update.oracle_max_confidence.map(|_| bank.oracle_max_confidence),
+        oracle_min_verification: update.oracle_min_verification.map(|_| bank.oracle_min_verification),
+        isolated: update.isolated.map(|_| bank.isolated),
+        isolated_borrow_mints: update.isolated_borrow_mints.as_ref().map(|_| bank.isolated_borrow_mints.clone()),
+        isolated_debt_ceiling: update.isolated_debt_ceiling.map(|_| bank.isolated_debt_ceiling),
+        emode_category: update.emode_category.map(|_| bank.emode_category),
+    }
+}
+
+fn log_bank_config(bank: &Bank) {
+    debug_msg!("  Liquidation threshold: {}", bank.liquidation_threshold);
+    debug_msg!("  Liquidation bonus: {}", bank.liquidation_bonus);
+    debug_msg!("  Liquidation close factor: {}", bank.liquidation_close_factor);
+    debug_msg!("  Max LTV: {}", bank.max_ltv);
+    debug_msg!("  Base borrow rate: {}", bank.base_borrow_rate);
+    debug_msg!("  Optimal utilization: {}", bank.optimal_utilization);
+    debug_msg!("  Borrow rate slope 1: {}", bank.borrow_rate_slope1);
+    debug_msg!("  Borrow rate slope 2: {}", bank.borrow_rate_slope2);
+    debug_msg!("  Reserve factor: {}", bank.reserve_factor);
+    debug_msg!("  Deposit fee: {}", bank.deposit_fee);
+    debug_msg!("  Withdrawal fee: {}", bank.withdrawal_fee);
+    debug_msg!("  Min deposit: {}", bank.min_deposit);
+    debug_msg!("  Supply cap: {}", bank.supply_cap);
+    debug_msg!("  Borrow cap: {}", bank.borrow_cap);
+    debug_msg!("  Interest accrual period: {}", bank.interest_accrual_period);
+    debug_msg!("  Fee recipient: {}", bank.fee_recipient);
+    debug_msg!("  Flash loan fee: {}", bank.flash_loan_fee);
+    debug_msg!("  Oracle feed ID: {:?}", bank.oracle_feed_id);
+    debug_msg!("  Oracle owner: {}", bank.oracle_owner);
+    debug_msg!("  Oracle max age: {}", bank.oracle_max_age);
+    debug_msg!("  Oracle max confidence: {}", bank.oracle_max_confidence);
+    debug_msg!("  Oracle min verification: {:?}", bank.oracle_min_verification);
+    debug_msg!("  Isolated: {}", bank.isolated);
+    debug_msg!("  Isolated borrow mints: {:?}", bank.isolated_borrow_mints);
+    debug_msg!("  Isolated debt ceiling: {}", bank.isolated_debt_ceiling);
+    debug_msg!("  E-mode category: {}", bank.emode_category);
+}
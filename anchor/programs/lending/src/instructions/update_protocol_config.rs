@@ -0,0 +1,37 @@
+use anchor_lang::prelude::*;
+
+use crate::state::ProtocolConfig;
+
+/// Protocol settings to change. Fields left as `None` keep their current value.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct ProtocolConfigUpdate {
+    pub guardian: Option<Pubkey>,
+    pub fee_recipient: Option<Pubkey>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateProtocolConfig<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+}
+
+pub fn process_update_protocol_config(ctx: Context<UpdateProtocolConfig>, update: ProtocolConfigUpdate) -> Result<()> {
+    let protocol_config = &mut ctx.accounts.protocol_config;
+
+    if let Some(value) = update.guardian {
+        debug_msg!("Guardian: {} -> {}", protocol_config.guardian, value);
+        protocol_config.guardian = value;
+    }
+    if let Some(value) = update.fee_recipient {
+        debug_msg!("Fee recipient: {} -> {}", protocol_config.fee_recipient, value);
+        protocol_config.fee_recipient = value;
+    }
+    Ok(())
+}
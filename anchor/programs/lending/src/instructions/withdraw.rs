@@ -1,10 +1,16 @@
 use anchor_lang::prelude::*;
 use anchor_spl::associated_token::AssociatedToken;
-use anchor_spl::token_interface::{TokenInterface, Mint, TokenAccount, TransferChecked, transfer_checked};
+use anchor_spl::token_interface::{TokenInterface, Mint, TokenAccount, TransferChecked, Burn, burn, transfer_checked};
+use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
 
 use crate::state::*;
+use crate::constants::PAUSE_WITHDRAW;
 use crate::error::ErrorCode;
+use crate::events::Withdrawn;
+use crate::math::{Decimal, Rounding};
+use crate::utils::*;
 
+#[event_cpi]
 #[derive(Accounts)]
 pub struct Withdraw<'info> {
     #[account(mut)]
@@ -16,6 +22,7 @@ pub struct Withdraw<'info> {
         mut,
         seeds = [mint.key().as_ref()],
         bump,
+        constraint = !bank.flash_loan_active @ ErrorCode::BankLocked,
     )]
     pub bank: Box<Account<'info, Bank>>,
 
@@ -44,66 +51,176 @@ pub struct Withdraw<'info> {
     )]
     pub user_associated_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
 
+    #[account(
+        mut,
+        seeds = [b"receipt", mint.key().as_ref()],
+        bump,
+    )]
+    pub receipt_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program,
+    )]
+    pub user_receipt_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        seeds = [b"user_global", signer.key().as_ref()],
+        bump,
+    )]
+    pub user_global_state: Box<Account<'info, UserGlobalState>>,
+
+    #[account(
+        seeds = [b"emode".as_ref(), &[user_global_state.emode_category]],
+        bump = emode_category.bump,
+    )]
+    pub emode_category: Option<Box<Account<'info, EModeCategory>>>,
+
+    pub price_update: Box<Account<'info, PriceUpdateV2>>,
+
+    #[account(
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+    )]
+    pub protocol_config: Box<Account<'info, ProtocolConfig>>,
+
     pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
     pub associated_token_program: Program<'info, AssociatedToken>,
 }
 
-pub fn process_withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
-    msg!("Processing withdrawal of {} tokens", amount);
-    msg!("User deposited shares (free): {}", ctx.accounts.user_token_state.deposited_shares);
-    msg!("User collateral shares (locked): {}", ctx.accounts.user_token_state.collateral_shares);
+pub fn process_withdraw<'info>(ctx: Context<'_, '_, 'info, 'info, Withdraw<'info>>, mode: AmountMode) -> Result<()> {
+    require_not_paused(&ctx.accounts.protocol_config, &ctx.accounts.bank, PAUSE_WITHDRAW)?;
+
+    debug_msg!("Processing withdrawal: {:?}", mode);
+    debug_msg!("User deposited shares (free): {}", ctx.accounts.user_token_state.deposited_shares);
+    debug_msg!("User collateral shares (locked): {}", ctx.accounts.user_token_state.collateral_shares);
 
     // ---------------------------------------------------------------------
-    // 1. Convert the requested token *amount* into the equivalent number of
-    //    *shares* using the current bank share-to-asset ratio.
+    // 1. Accrue interest, then resolve the request into a token amount and
+    //    the shares burned for it at the current supply index. Token
+    //    requests round shares up and share requests round tokens down, so
+    //    the user never withdraws more than their shares are worth.
     // ---------------------------------------------------------------------
-    let bank_total_assets = crate::utils::calculate_total_assets(&ctx.accounts.bank);
-    msg!("Bank total deposited assets (tokens): {}", bank_total_assets);
-    msg!("Bank total deposited shares: {}", ctx.accounts.bank.total_deposited_shares);
-
-    let shares_to_withdraw = if ctx.accounts.bank.total_deposited_shares == 0 {
-        // Should never happen because we have already verified the user holds
-        // shares, but handle the edge case defensively.
-        msg!("Bank has zero deposit shares – treating tokens and shares 1:1");
-        amount
-    } else {
-        // shares = amount * totalShares / totalAssets
-        ((amount as u128)
-            .checked_mul(ctx.accounts.bank.total_deposited_shares as u128)
-            .and_then(|v| v.checked_div(bank_total_assets))
-            .ok_or(ErrorCode::MathOverflow)?) as u64
+    let current_time = Clock::get()?.unix_timestamp;
+    let liquidity = ctx.accounts.bank_token_account.amount;
+    if let Some(event) = accrue_interest(&mut ctx.accounts.bank, liquidity, current_time)? {
+        emit_cpi!(event);
+    }
+    sync_mint_interest_config(&mut ctx.accounts.bank, &ctx.accounts.mint.to_account_info())?;
+    debug_msg!("Bank supply index: {}", Decimal::from_scaled_val(ctx.accounts.bank.supply_index));
+    debug_msg!("Bank total deposited shares: {}", ctx.accounts.bank.total_deposited_shares);
+    reconcile_deposited_shares(&mut ctx.accounts.user_token_state, ctx.accounts.user_receipt_account.amount);
+
+    let (amount, shares_to_withdraw) = match mode {
+        AmountMode::Tokens(amount) => {
+            (amount, calculate_deposit_shares(&ctx.accounts.bank, amount, Rounding::Up)?)
+        }
+        AmountMode::Shares(shares) => {
+            (calculate_deposit_amount(&ctx.accounts.bank, shares, Rounding::Down)?, shares)
+        }
+        AmountMode::Max => {
+            let shares = free_deposit_shares(&ctx.accounts.user_token_state, ctx.accounts.user_receipt_account.amount);
+            (calculate_deposit_amount(&ctx.accounts.bank, shares, Rounding::Down)?, shares)
+        }
     };
+    require!(amount > 0 && shares_to_withdraw > 0, ErrorCode::InvalidWithdrawAmount);
 
-    msg!("Shares equivalent for {} tokens: {}", amount, shares_to_withdraw);
+    debug_msg!("Shares equivalent for {} tokens: {}", amount, shares_to_withdraw);
 
     // ---------------------------------------------------------------------
-    // 2. Ensure the user actually has enough FREE shares to burn.
+    // 2. Ensure the user actually has enough FREE shares, and the receipts
+    //    for them, to burn.
     // ---------------------------------------------------------------------
-    let available_shares = ctx.accounts.user_token_state.deposited_shares;
-    msg!("Available shares for withdrawal: {}", available_shares);
+    let available_shares = free_deposit_shares(&ctx.accounts.user_token_state, ctx.accounts.user_receipt_account.amount);
+    debug_msg!("Available shares for withdrawal: {}", available_shares);
 
     if shares_to_withdraw > available_shares {
-        msg!("Insufficient funds: requested {} tokens ({} shares) but only {} shares available", amount, shares_to_withdraw, available_shares);
+        debug_msg!("Insufficient funds: requested {} tokens ({} shares) but only {} shares available", amount, shares_to_withdraw, available_shares);
         return Err(ErrorCode::InsufficientFunds.into());
     }
 
-    msg!("Updating user state - reducing deposited shares by {}", shares_to_withdraw);
+    // ---------------------------------------------------------------------
+    // 3. Deposits back every open loan, so the rest of the account must
+    //    still cover the user's debt at each bank's (or e-mode's) max LTV.
+    // ---------------------------------------------------------------------
+    if !ctx.accounts.user_global_state.active_positions.is_empty() {
+        let price = get_validated_price(&ctx.accounts.price_update, &ctx.accounts.bank)?;
+        let known_assets = [HealthAsset {
+            bank: &ctx.accounts.bank,
+            user_state: &ctx.accounts.user_token_state,
+            receipt_balance: ctx.accounts.user_receipt_account.amount,
+            price,
+        }];
+        let emode = user_emode(&ctx.accounts.user_global_state, &ctx.accounts.emode_category)?;
+        let health = calculate_account_health(
+            &ctx.accounts.signer.key(),
+            &ctx.accounts.user_global_state,
+            &known_assets,
+            ctx.remaining_accounts,
+            emode,
+            current_time,
+        )?;
+        let withdraw_value = calculate_token_value(amount, ctx.accounts.mint.decimals, &price, Rounding::Up)?;
+        let borrow_limit = health.borrow_limit
+            .try_sub(withdraw_value.try_mul(Decimal::from_bps(risk_params(&ctx.accounts.bank, emode).max_ltv))?)
+            .unwrap_or_default();
+        debug_msg!("Borrow limit after withdrawal in USD: {}", borrow_limit);
+        if health.debt_value > borrow_limit {
+            debug_msg!("Withdrawal would leave debt {} above borrow limit {}", health.debt_value, borrow_limit);
+            return Err(ErrorCode::WithdrawAmountExceedsCollateralValue.into());
+        }
+    }
+
+    debug_msg!("Burning {} receipt tokens", shares_to_withdraw);
+    burn(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Burn {
+                mint: ctx.accounts.receipt_mint.to_account_info(),
+                from: ctx.accounts.user_receipt_account.to_account_info(),
+                authority: ctx.accounts.signer.to_account_info(),
+            },
+        ),
+        shares_to_withdraw,
+    )?;
+
+    debug_msg!("Updating user state - reducing deposited shares by {}", shares_to_withdraw);
     ctx.accounts.user_token_state.deposited_shares = ctx.accounts.user_token_state.deposited_shares
         .checked_sub(shares_to_withdraw)
         .ok_or(ErrorCode::MathOverflow)?;
-    msg!("New user deposited shares: {}", ctx.accounts.user_token_state.deposited_shares);
+    ctx.accounts.user_token_state.last_updated_deposited = current_time;
+    debug_msg!("New user deposited shares: {}", ctx.accounts.user_token_state.deposited_shares);
 
-    msg!("Updating bank state - reducing total deposited shares by {}", shares_to_withdraw);
-    msg!("Current bank total deposited shares: {}", ctx.accounts.bank.total_deposited_shares);
+    debug_msg!("Updating bank state - reducing total deposited shares by {}", shares_to_withdraw);
+    debug_msg!("Current bank total deposited shares: {}", ctx.accounts.bank.total_deposited_shares);
     ctx.accounts.bank.total_deposited_shares = ctx.accounts.bank.total_deposited_shares
         .checked_sub(shares_to_withdraw)
         .ok_or(ErrorCode::MathOverflow)?;
-    msg!("New bank total deposited shares: {}", ctx.accounts.bank.total_deposited_shares);
+    debug_msg!("New bank total deposited shares: {}", ctx.accounts.bank.total_deposited_shares);
+
+    // ---------------------------------------------------------------------
+    // 4. Charge the withdrawal fee; it stays in the treasury as protocol
+    //    revenue and the user receives the remainder.
+    // ---------------------------------------------------------------------
+    let fee = calculate_fee(amount, ctx.accounts.bank.withdrawal_fee)?;
+    let net_amount = amount.checked_sub(fee).ok_or(ErrorCode::MathOverflow)?;
+    debug_msg!("Withdrawal fee: {} ({} bps), net amount: {}", fee, ctx.accounts.bank.withdrawal_fee, net_amount);
 
-    msg!("Initiating token transfer from bank to user");
-    msg!("Bank token account: {}", ctx.accounts.bank_token_account.key());
-    msg!("User token account: {}", ctx.accounts.user_associated_token_account.key());
+    let liquidity = available_liquidity(&ctx.accounts.bank, ctx.accounts.bank_token_account.amount);
+    if net_amount > liquidity {
+        debug_msg!("Insufficient liquidity: requested {} but only {} available", net_amount, liquidity);
+        return Err(ErrorCode::InsufficientLiquidity.into());
+    }
+    ctx.accounts.bank.accumulated_fees = ctx.accounts.bank.accumulated_fees
+        .checked_add(fee)
+        .ok_or(ErrorCode::MathOverflow)?;
+
+    debug_msg!("Initiating token transfer from bank to user");
+    debug_msg!("Bank token account: {}", ctx.accounts.bank_token_account.key());
+    debug_msg!("User token account: {}", ctx.accounts.user_associated_token_account.key());
     
     let mint_key = ctx.accounts.mint.key();
     let signer_seeds : &[&[&[u8]]] = &[
@@ -123,10 +240,21 @@ pub fn process_withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
         },
         signer_seeds
     );
-    msg!("Executing transfer of {} tokens with {} decimals", amount, ctx.accounts.mint.decimals);
-    transfer_checked(transfer_ctx, amount, ctx.accounts.mint.decimals)?;
-    msg!("Transfer completed successfully");
+    debug_msg!("Executing transfer of {} tokens with {} decimals", net_amount, ctx.accounts.mint.decimals);
+    transfer_checked(transfer_ctx, net_amount, ctx.accounts.mint.decimals)?;
+    debug_msg!("Transfer completed successfully");
+
+    emit_cpi!(Withdrawn {
+        user: ctx.accounts.signer.key(),
+        bank: ctx.accounts.bank.key(),
+        mint: ctx.accounts.mint.key(),
+        amount,
+        fee,
+        shares: shares_to_withdraw,
+        supply_index: ctx.accounts.bank.supply_index,
+        timestamp: current_time,
+    });
 
-    msg!("Withdrawal process completed");
+    debug_msg!("Withdrawal process completed");
     Ok(())
 }
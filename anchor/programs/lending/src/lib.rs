@@ -1,10 +1,15 @@
 use anchor_lang::prelude::*;
 use instructions::*;
+use state::AmountMode;
+#[macro_use]
+mod logging;
 mod instructions;
 mod state;
 mod error;
 mod utils;
 mod constants;
+mod math;
+mod events;
 
 declare_id!("FYkahL7zxyc3cS9wwA8b45JUNKoHSw6R4Ln5t7GXU5oD");
 
@@ -14,69 +19,173 @@ pub mod lending {
   use super::*;
 
   pub fn store_symbol_feed_id(ctx: Context<StoreSymbolFeedId>, symbol: String, feed_id: String) -> Result<()> {
-    return process_store_symbol_feed_id(ctx, symbol, feed_id);
+    process_store_symbol_feed_id(ctx, symbol, feed_id)
+  }
+
+  pub fn init_protocol_config(ctx: Context<InitProtocolConfig>, guardian: Pubkey, fee_recipient: Pubkey) -> Result<()> {
+    process_init_protocol_config(ctx, guardian, fee_recipient)
+  }
+
+  pub fn update_protocol_config(ctx: Context<UpdateProtocolConfig>, update: ProtocolConfigUpdate) -> Result<()> {
+    process_update_protocol_config(ctx, update)
+  }
+
+  pub fn propose_admin(ctx: Context<ProposeAdmin>, new_admin: Pubkey) -> Result<()> {
+    process_propose_admin(ctx, new_admin)
+  }
+
+  pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+    process_accept_admin(ctx)
+  }
+
+  pub fn set_protocol_pause(ctx: Context<SetProtocolPause>, paused_operations: u8) -> Result<()> {
+    process_set_protocol_pause(ctx, paused_operations)
+  }
+
+  pub fn set_bank_pause(ctx: Context<SetBankPause>, paused_operations: u8) -> Result<()> {
+    process_set_bank_pause(ctx, paused_operations)
   }
 
   pub fn init_user(ctx: Context<InitUser>) -> Result<()> {
-    return process_init_user(ctx);
+    process_init_user(ctx)
   }
 
   pub fn init_user_token_state(ctx: Context<InitUserTokenState>, mint_address: Pubkey) -> Result<()> {
-    return process_init_user_token_state(ctx, mint_address);
+    process_init_user_token_state(ctx, mint_address)
   }
 
   pub fn init_borrow_position(ctx: Context<InitBorrowPosition>, mint_collateral: Pubkey, mint_borrow: Pubkey, position_id: u64) -> Result<()> {
-    return process_init_borrow_position(ctx, mint_collateral, mint_borrow, position_id);
+    process_init_borrow_position(ctx, mint_collateral, mint_borrow, position_id)
   }
 
+  #[allow(clippy::too_many_arguments)]
   pub fn init_bank(ctx: Context<InitBank>, 
     liquidation_threshold: u64,
     liquidation_bonus: u64,
     liquidation_close_factor: u64,
     max_ltv: u64,
-    deposit_interest_rate: u64,
-    borrow_interest_rate: u64,
+    base_borrow_rate: u64,
+    optimal_utilization: u64,
+    borrow_rate_slope1: u64,
+    borrow_rate_slope2: u64,
+    reserve_factor: u64,
     name: String,
     description: String,
     deposit_fee: u64,
     withdrawal_fee: u64,
     min_deposit: u64,
     interest_accrual_period: i64,
+    supply_cap: u64,
+    borrow_cap: u64,
   ) -> Result<()> {
-    return process_init_bank(
+    process_init_bank(
         ctx, 
         liquidation_threshold,
         liquidation_bonus,
         liquidation_close_factor,
         max_ltv,
-        deposit_interest_rate,
-        borrow_interest_rate,
+        base_borrow_rate,
+        optimal_utilization,
+        borrow_rate_slope1,
+        borrow_rate_slope2,
+        reserve_factor,
         name,
         description,
         deposit_fee,
         withdrawal_fee,
         min_deposit,
-        interest_accrual_period
-    );
+        interest_accrual_period,
+        supply_cap,
+        borrow_cap,
+    )
   }
 
-  pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
-    return process_deposit(ctx, amount);
+  pub fn update_bank_config(ctx: Context<UpdateBankConfig>, update: BankConfigUpdate) -> Result<()> {
+    process_update_bank_config(ctx, update)
+  }
+
+  pub fn init_emode_category(
+    ctx: Context<InitEModeCategory>,
+    category_id: u8,
+    label: String,
+    max_ltv: u64,
+    liquidation_threshold: u64,
+    liquidation_bonus: u64,
+  ) -> Result<()> {
+    process_init_emode_category(ctx, category_id, label, max_ltv, liquidation_threshold, liquidation_bonus)
+  }
+
+  pub fn update_emode_category(
+    ctx: Context<UpdateEModeCategory>,
+    max_ltv: u64,
+    liquidation_threshold: u64,
+    liquidation_bonus: u64,
+  ) -> Result<()> {
+    process_update_emode_category(ctx, max_ltv, liquidation_threshold, liquidation_bonus)
   }
 
-  pub fn borrow(ctx: Context<Borrow>, position_id: u64, amount: u64) -> Result<()> {
-    return process_borrow(ctx, position_id, amount);
+  pub fn set_user_emode<'info>(ctx: Context<'_, '_, 'info, 'info, SetUserEMode<'info>>, category_id: u8) -> Result<()> {
+    process_set_user_emode(ctx, category_id)
   }
 
-  pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
-    return process_withdraw(ctx,amount);
+  pub fn deposit(ctx: Context<Deposit>, mode: AmountMode) -> Result<()> {
+    process_deposit(ctx, mode)
   }
 
-  pub fn repay(ctx: Context<Repay>, position_id: u64, amount: u64) -> Result<()> {
-    return process_repay(ctx, position_id, amount);
+  pub fn redeem(ctx: Context<Redeem>, receipt_amount: u64) -> Result<()> {
+    process_redeem(ctx, receipt_amount)
   }
 
-  // pub fn liquidate(ctx: Context<Liquidate>) -> Result<()> {
-  //   return process_liquidate(ctx);
-  // }
+  pub fn borrow<'info>(
+    ctx: Context<'_, '_, 'info, 'info, Borrow<'info>>,
+    position_id: u64,
+    mode: AmountMode,
+    extra_collateral_count: u8,
+  ) -> Result<()> {
+    process_borrow(ctx, position_id, mode, extra_collateral_count)
+  }
+
+  pub fn withdraw<'info>(ctx: Context<'_, '_, 'info, 'info, Withdraw<'info>>, mode: AmountMode) -> Result<()> {
+    process_withdraw(ctx, mode)
+  }
+
+  pub fn repay<'info>(ctx: Context<'_, '_, 'info, 'info, Repay<'info>>, position_id: u64, mode: AmountMode) -> Result<()> {
+    process_repay(ctx, position_id, mode)
+  }
+
+  pub fn add_collateral(ctx: Context<AddCollateral>, position_id: u64, mode: AmountMode) -> Result<()> {
+    process_add_collateral(ctx, position_id, mode)
+  }
+
+  pub fn remove_collateral<'info>(
+    ctx: Context<'_, '_, 'info, 'info, RemoveCollateral<'info>>,
+    position_id: u64,
+    mode: AmountMode,
+  ) -> Result<()> {
+    process_remove_collateral(ctx, position_id, mode)
+  }
+
+  pub fn close_position(ctx: Context<ClosePosition>, position_id: u64) -> Result<()> {
+    process_close_position(ctx, position_id)
+  }
+
+  pub fn close_user_token_state(ctx: Context<CloseUserTokenState>, mint_address: Pubkey) -> Result<()> {
+    process_close_user_token_state(ctx, mint_address)
+  }
+
+  pub fn flash_borrow(ctx: Context<FlashBorrow>, amount: u64) -> Result<()> {
+    process_flash_borrow(ctx, amount)
+  }
+
+  pub fn flash_repay(ctx: Context<FlashRepay>) -> Result<()> {
+    process_flash_repay(ctx)
+  }
+
+  pub fn collect_fees(ctx: Context<CollectFees>) -> Result<()> {
+    process_collect_fees(ctx)
+  }
+
+  pub fn liquidate<'info>(ctx: Context<'_, '_, 'info, 'info, Liquidate<'info>>, position_id: u64, amount: u64) -> Result<()> {
+    process_liquidate(ctx, position_id, amount)
+  }
 }
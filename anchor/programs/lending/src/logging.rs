@@ -0,0 +1,18 @@
+/// Diagnostic logging, compiled in only with the `verbose-logs` feature.
+/// Without it the arguments are still type-checked but never formatted, so
+/// release builds log nothing beyond events and errors.
+#[cfg(feature = "verbose-logs")]
+macro_rules! debug_msg {
+    ($($arg:tt)*) => {
+        ::anchor_lang::prelude::msg!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "verbose-logs"))]
+macro_rules! debug_msg {
+    ($($arg:tt)*) => {
+        if false {
+            ::anchor_lang::prelude::msg!($($arg)*)
+        }
+    };
+}
@@ -0,0 +1,319 @@
+//! WAD-scaled fixed-point decimal used for every on-chain valuation.
+//!
+//! Values are stored as a `U192` scaled by `10^18`, which leaves enough
+//! headroom to multiply a full `u64` token amount by a full `i64` Pyth price
+//! before any rounding happens. Every operation is checked and returns
+//! `ErrorCode::MathOverflow` instead of panicking.
+use std::fmt;
+
+use anchor_lang::prelude::*;
+
+use crate::constants::BPS_DENOMINATOR;
+use crate::error::ErrorCode;
+
+// Kept out of the anchor prelude's scope, which shadows `Result`.
+#[allow(clippy::assign_op_pattern, clippy::manual_div_ceil)]
+mod uint_types {
+    use uint::construct_uint;
+
+    construct_uint! {
+        pub struct U192(3);
+    }
+}
+pub use uint_types::U192;
+
+/// Number of decimal places carried by a `Decimal`.
+pub const SCALE: usize = 18;
+/// `10^SCALE`, the scaled representation of `1`.
+pub const WAD: u64 = 1_000_000_000_000_000_000;
+/// Euler's number scaled by `WAD`, rounded down.
+const E_SCALED: u128 = 2_718_281_828_459_045_235;
+
+/// Direction to round in when precision has to be dropped. Callers pick the
+/// direction that favours the protocol: collateral down, debt up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Decimal(pub U192);
+
+impl Decimal {
+    pub fn zero() -> Self {
+        Self(U192::zero())
+    }
+
+    pub fn one() -> Self {
+        Self(U192::from(WAD))
+    }
+
+    /// Builds a decimal from a value that is already scaled by `WAD`.
+    pub fn from_scaled_val(scaled_val: u128) -> Self {
+        Self(U192::from(scaled_val))
+    }
+
+    /// Returns the raw `WAD`-scaled value, e.g. for storing in an account.
+    pub fn to_scaled_val(self) -> Result<u128> {
+        u128::try_from(self.0).map_err(|_| ErrorCode::MathOverflow.into())
+    }
+
+    /// Converts a basis-point ratio (`10_000` = 100%) into a decimal.
+    pub fn from_bps(bps: u64) -> Self {
+        Self(U192::from(bps) * U192::from(WAD) / U192::from(BPS_DENOMINATOR))
+    }
+
+    pub fn try_add(self, rhs: Self) -> Result<Self> {
+        self.0
+            .checked_add(rhs.0)
+            .map(Self)
+            .ok_or(ErrorCode::MathOverflow.into())
+    }
+
+    pub fn try_sub(self, rhs: Self) -> Result<Self> {
+        self.0
+            .checked_sub(rhs.0)
+            .map(Self)
+            .ok_or(ErrorCode::MathOverflow.into())
+    }
+
+    /// Multiplies two decimals, rounding the result down.
+    pub fn try_mul(self, rhs: Self) -> Result<Self> {
+        self.try_mul_rounded(rhs, Rounding::Down)
+    }
+
+    pub fn try_mul_rounded(self, rhs: Self, rounding: Rounding) -> Result<Self> {
+        let product = self.0.checked_mul(rhs.0).ok_or(ErrorCode::MathOverflow)?;
+        div_rounded(product, U192::from(WAD), rounding).map(Self)
+    }
+
+    /// Divides two decimals, rounding the result down.
+    pub fn try_div(self, rhs: Self) -> Result<Self> {
+        self.try_div_rounded(rhs, Rounding::Down)
+    }
+
+    pub fn try_div_rounded(self, rhs: Self, rounding: Rounding) -> Result<Self> {
+        let numerator = self.0.checked_mul(U192::from(WAD)).ok_or(ErrorCode::MathOverflow)?;
+        div_rounded(numerator, rhs.0, rounding).map(Self)
+    }
+
+    /// Divides by an integer, e.g. a raw oracle price.
+    pub fn try_div_u64(self, rhs: u64, rounding: Rounding) -> Result<Self> {
+        div_rounded(self.0, U192::from(rhs), rounding).map(Self)
+    }
+
+    /// Multiplies by `10^exponent`. A negative exponent divides and rounds in
+    /// the requested direction, so both signs of Pyth exponents are handled.
+    pub fn try_mul_pow10(self, exponent: i32, rounding: Rounding) -> Result<Self> {
+        let factor = ten_pow(exponent.unsigned_abs())?;
+        if exponent >= 0 {
+            self.0
+                .checked_mul(factor)
+                .map(Self)
+                .ok_or(ErrorCode::MathOverflow.into())
+        } else {
+            div_rounded(self.0, factor, rounding).map(Self)
+        }
+    }
+
+    /// Computes `self * 10^exponent / divisor`, rounding once. The division
+    /// comes first, so a large scale-up (e.g. to a high-decimal mint) only
+    /// overflows when the result itself does.
+    pub fn try_mul_pow10_div(self, exponent: i32, divisor: u64, rounding: Rounding) -> Result<Self> {
+        if exponent < 0 {
+            return self.try_div_u64(divisor, rounding)?.try_mul_pow10(exponent, rounding);
+        }
+        require!(divisor != 0, ErrorCode::MathOverflow);
+        let factor = ten_pow(exponent.unsigned_abs())?;
+        let divisor = U192::from(divisor);
+        // (q * d + r) * f / d == q * f + r * f / d, with r < d
+        let whole = (self.0 / divisor).checked_mul(factor).ok_or(ErrorCode::MathOverflow)?;
+        let remainder = (self.0 % divisor).checked_mul(factor).ok_or(ErrorCode::MathOverflow)?;
+        let fraction = div_rounded(remainder, divisor, rounding)?;
+        whole.checked_add(fraction).map(Self).ok_or(ErrorCode::MathOverflow.into())
+    }
+
+    /// Raises the decimal to an integer power by repeated squaring.
+    pub fn try_pow(self, mut exponent: u64) -> Result<Self> {
+        let mut base = self;
+        let mut result = Self::one();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = result.try_mul(base)?;
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = base.try_mul(base)?;
+            }
+        }
+        Ok(result)
+    }
+
+    /// `e^self`, rounded down. The integer part goes through `try_pow` on
+    /// `e`; the fractional part is summed as a Taylor series, whose terms
+    /// drop below the last digit within a few dozen steps.
+    pub fn try_exp(self) -> Result<Self> {
+        let whole = u64::try_from(self.0 / U192::from(WAD)).map_err(|_| ErrorCode::MathOverflow)?;
+        let fraction = Self(self.0 % U192::from(WAD));
+        let mut sum = Self::one();
+        let mut term = Self::one();
+        let mut n = 1;
+        while !term.0.is_zero() {
+            term = term.try_mul(fraction)?.try_div_u64(n, Rounding::Down)?;
+            sum = sum.try_add(term)?;
+            n += 1;
+        }
+        Self::from_scaled_val(E_SCALED).try_pow(whole)?.try_mul(sum)
+    }
+
+    pub fn try_to_u64(&self, rounding: Rounding) -> Result<u64> {
+        let value = div_rounded(self.0, U192::from(WAD), rounding)?;
+        u64::try_from(value).map_err(|_| ErrorCode::MathOverflow.into())
+    }
+}
+
+impl From<u64> for Decimal {
+    fn from(value: u64) -> Self {
+        Self(U192::from(value) * U192::from(WAD))
+    }
+}
+
+impl From<u128> for Decimal {
+    fn from(value: u128) -> Self {
+        Self(U192::from(value) * U192::from(WAD))
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut scaled = self.0.to_string();
+        if scaled.len() <= SCALE {
+            scaled.insert_str(0, &"0".repeat(SCALE - scaled.len() + 1));
+        }
+        scaled.insert(scaled.len() - SCALE, '.');
+        f.write_str(&scaled)
+    }
+}
+
+fn ten_pow(exponent: u32) -> Result<U192> {
+    U192::from(10u64)
+        .checked_pow(U192::from(exponent))
+        .ok_or(ErrorCode::MathOverflow.into())
+}
+
+fn div_rounded(numerator: U192, denominator: U192, rounding: Rounding) -> Result<U192> {
+    require!(!denominator.is_zero(), ErrorCode::MathOverflow);
+    let quotient = numerator / denominator;
+    match rounding {
+        Rounding::Down => Ok(quotient),
+        Rounding::Up => {
+            if (numerator % denominator).is_zero() {
+                Ok(quotient)
+            } else {
+                quotient.checked_add(U192::one()).ok_or(ErrorCode::MathOverflow.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(scaled: u128) -> Decimal {
+        Decimal::from_scaled_val(scaled)
+    }
+
+    #[test]
+    fn mul_and_div_round_in_the_requested_direction() {
+        let third = Decimal::one().try_div(Decimal::from(3u64)).unwrap();
+        assert_eq!(third, dec(333_333_333_333_333_333));
+        let third_up = Decimal::one().try_div_rounded(Decimal::from(3u64), Rounding::Up).unwrap();
+        assert_eq!(third_up, dec(333_333_333_333_333_334));
+
+        // 1e-18 * 0.5 is not representable: down drops it, up keeps one unit
+        let tiny = dec(1);
+        let half = dec(WAD as u128 / 2);
+        assert_eq!(tiny.try_mul(half).unwrap(), Decimal::zero());
+        assert_eq!(tiny.try_mul_rounded(half, Rounding::Up).unwrap(), dec(1));
+
+        assert_eq!(dec(1_500_000_000_000_000_000).try_to_u64(Rounding::Down).unwrap(), 1);
+        assert_eq!(dec(1_500_000_000_000_000_000).try_to_u64(Rounding::Up).unwrap(), 2);
+        assert_eq!(Decimal::from(7u64).try_to_u64(Rounding::Up).unwrap(), 7);
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert!(Decimal::one().try_div(Decimal::zero()).is_err());
+        assert!(Decimal::one().try_div_u64(0, Rounding::Down).is_err());
+        assert!(Decimal::one().try_mul_pow10_div(6, 0, Rounding::Down).is_err());
+    }
+
+    #[test]
+    fn pow_matches_repeated_multiplication() {
+        assert_eq!(Decimal::from(2u64).try_pow(0).unwrap(), Decimal::one());
+        assert_eq!(Decimal::from(2u64).try_pow(10).unwrap(), Decimal::from(1024u64));
+        // 1.1^3 = 1.331 exactly
+        let rate = dec(1_100_000_000_000_000_000);
+        assert_eq!(rate.try_pow(3).unwrap(), dec(1_331_000_000_000_000_000));
+        // 1.000001^1_000_000 ~ e, truncated at every step so slightly below it
+        let compounded = dec(1_000_001_000_000_000_000).try_pow(1_000_000).unwrap();
+        assert!(compounded > dec(2_718_280_000_000_000_000));
+        assert!(compounded < dec(2_718_282_000_000_000_000));
+        assert!(Decimal::from(1_000_000u64).try_pow(10).is_err());
+    }
+
+    // Within `tolerance` units of the last digit of the exact value
+    fn assert_close(actual: Decimal, expected: u128, tolerance: u128) {
+        let actual = actual.to_scaled_val().unwrap();
+        assert!(actual.abs_diff(expected) <= tolerance, "{} vs {}", actual, expected);
+    }
+
+    #[test]
+    fn exp_matches_known_values() {
+        assert_eq!(Decimal::zero().try_exp().unwrap(), Decimal::one());
+        assert_close(Decimal::one().try_exp().unwrap(), 2_718_281_828_459_045_235, 10);
+        // e^0.05 = 1.051271096376024039...
+        assert_close(dec(50_000_000_000_000_000).try_exp().unwrap(), 1_051_271_096_376_024_039, 10);
+        // e^2.5 = 12.182493960703473438...
+        assert_close(dec(2_500_000_000_000_000_000).try_exp().unwrap(), 12_182_493_960_703_473_438, 100);
+        // e^-0.05 = 0.951229424500714017...
+        let inverse = Decimal::one().try_div(dec(50_000_000_000_000_000).try_exp().unwrap()).unwrap();
+        assert_close(inverse, 951_229_424_500_714_017, 10);
+        assert!(Decimal::from(1_000u64).try_exp().is_err());
+    }
+
+    #[test]
+    fn mul_pow10_handles_both_signs() {
+        assert_eq!(Decimal::from(5u64).try_mul_pow10(2, Rounding::Down).unwrap(), Decimal::from(500u64));
+        assert_eq!(Decimal::from(5u64).try_mul_pow10(0, Rounding::Up).unwrap(), Decimal::from(5u64));
+        // 5 / 10^19 is below the last representable digit
+        assert_eq!(Decimal::from(5u64).try_mul_pow10(-19, Rounding::Down).unwrap(), Decimal::zero());
+        assert_eq!(Decimal::from(5u64).try_mul_pow10(-19, Rounding::Up).unwrap(), dec(1));
+        assert_eq!(Decimal::from(5u64).try_mul_pow10(-1, Rounding::Down).unwrap(), dec(500_000_000_000_000_000));
+        assert!(Decimal::from(5u64).try_mul_pow10(60, Rounding::Down).is_err());
+    }
+
+    #[test]
+    fn mul_pow10_div_is_exact_and_scales_past_18_decimals() {
+        // $100M into a 24-decimal mint at $1 (price 1e8, exponent -8):
+        // scaling before dividing would need 1e26 * 1e32 > U192::MAX
+        let value = Decimal::from(100_000_000u64);
+        assert!(value.try_mul_pow10(32, Rounding::Down).is_err());
+        let amount = value.try_mul_pow10_div(32, 100_000_000, Rounding::Down).unwrap();
+        assert_eq!(amount, Decimal::from(100_000_000_000_000_000_000_000_000_000_000u128));
+
+        // One rounding step on the combined result: 1 * 10^6 / 3
+        let down = Decimal::one().try_mul_pow10_div(6, 3, Rounding::Down).unwrap();
+        let up = Decimal::one().try_mul_pow10_div(6, 3, Rounding::Up).unwrap();
+        assert_eq!(down, dec(333_333_333_333_333_333_333_333));
+        assert_eq!(up, dec(333_333_333_333_333_333_333_334));
+
+        // Negative exponents divide twice, which rounds the same way as once
+        let down = Decimal::from(10u64).try_mul_pow10_div(-1, 3, Rounding::Down).unwrap();
+        let up = Decimal::from(10u64).try_mul_pow10_div(-1, 3, Rounding::Up).unwrap();
+        assert_eq!(down, dec(333_333_333_333_333_333));
+        assert_eq!(up, dec(333_333_333_333_333_334));
+    }
+}
@@ -1,5 +1,6 @@
 use anchor_lang::prelude::*;
-use crate::constants::{MAX_MINTS, MAX_BORROW_POSITIONS};
+use pyth_solana_receiver_sdk::price_update::VerificationLevel;
+use crate::constants::{MAX_MINTS, MAX_BORROW_POSITIONS, MAX_ISOLATED_BORROW_MINTS, MAX_POSITION_COLLATERALS};
 #[account]
 #[derive(InitSpace)]
 pub struct UserTokenState {
@@ -16,13 +17,37 @@ pub struct UserTokenState {
 #[account]
 #[derive(InitSpace)]
 pub struct Bank {
+    // Admin that created the bank; it is managed by whoever is `ProtocolConfig.admin`
     pub authority: Pubkey,
     pub mint_address: Pubkey,
+    pub mint_decimals: u8,
+    // Receipt (stToken) mint for free deposit shares; minted and burned by the bank PDA
+    pub receipt_mint: Pubkey,
+    // Interest-bearing config of a Token-2022 mint, refreshed whenever the mint is at hand
+    pub mint_interest_config: Option<MintInterestConfig>,
+    // Pyth feed this bank is priced with, and the program that must own its price accounts
+    pub oracle_feed_id: [u8; 32],
+    pub oracle_owner: Pubkey,
+    // Oracle quality gates: staleness in seconds, confidence / price in basis points
+    pub oracle_max_age: u64,
+    pub oracle_max_confidence: u64,
+    pub oracle_min_verification: OracleVerification,
+    // Free deposit shares, always equal to the receipt mint's supply
     pub total_deposited_shares: u64,
     pub total_collateral_shares: u64,
     pub total_borrowed_shares: u64,
+    // Current rates per accrual period in millionths, re-derived from utilization at every accrual
     pub deposit_interest_rate: u64,
     pub borrow_interest_rate: u64,
+    // Kinked borrow rate curve: base + slope1 up to optimal_utilization (bps), then slope2 above it
+    pub base_borrow_rate: u64,
+    pub optimal_utilization: u64,
+    pub borrow_rate_slope1: u64,
+    pub borrow_rate_slope2: u64,
+    // Tokens per deposit share, WAD-scaled (1e18 = 1.0); grows as borrowers pay interest
+    pub supply_index: u128,
+    // Tokens per borrow share, WAD-scaled (1e18 = 1.0); compounds at the curve's borrow rate
+    pub borrow_index: u128,
     pub last_compound_time: i64,
     pub interest_accrual_period: i64,
     pub liquidation_threshold: u64,
@@ -34,9 +59,51 @@ pub struct Bank {
     pub name: String,
     #[max_len(1000)]
     pub description: String,
+    // Fees in basis points of the deposited / withdrawn amount
     pub deposit_fee: u64,
     pub withdrawal_fee: u64,
     pub min_deposit: u64,
+    // Limits on total supplied / borrowed tokens; 0 means uncapped
+    pub supply_cap: u64,
+    pub borrow_cap: u64,
+    // Share of borrow interest kept by the protocol, in basis points
+    pub reserve_factor: u64,
+    // Protocol revenue held in the treasury; not lendable, withdrawn via collect_fees
+    pub accumulated_fees: u64,
+    pub fee_recipient: Pubkey,
+    // Fee on flash loans in basis points of the amount, kept by the protocol
+    pub flash_loan_fee: u64,
+    // Set between flash_borrow and flash_repay; locks the bank against other operations
+    pub flash_loan_active: bool,
+    pub flash_loan_amount: u64,
+    // Operations frozen on this bank only, as a mask of the PAUSE_* bits
+    pub paused_operations: u8,
+    // Isolated collateral cannot be mixed with other collateral in one account and
+    // only backs loans of `isolated_borrow_mints`, up to a debt ceiling shared by all
+    // borrowers. Ceiling and outstanding debt are in USD millionths
+    pub isolated: bool,
+    #[max_len(MAX_ISOLATED_BORROW_MINTS)]
+    pub isolated_borrow_mints: Vec<Pubkey>,
+    pub isolated_debt_ceiling: u64,
+    pub isolated_debt: u64,
+    // E-mode category this bank belongs to; 0 means none
+    pub emode_category: u8,
+}
+
+/// Protocol-wide settings, stored in a single PDA.
+#[account]
+#[derive(InitSpace)]
+pub struct ProtocolConfig {
+    pub admin: Pubkey,
+    // Proposed by the admin; becomes admin once it signs accept_admin
+    pub pending_admin: Option<Pubkey>,
+    // Emergency key allowed to pause operations alongside the admin
+    pub guardian: Pubkey,
+    // Default fee recipient for new banks
+    pub fee_recipient: Pubkey,
+    // Operations frozen on every bank, as a mask of the PAUSE_* bits
+    pub paused_operations: u8,
+    pub bump: u8,
 }
 
 #[account]
@@ -58,6 +125,24 @@ pub struct UserGlobalState {
     pub active_positions: Vec<Pubkey>,
     pub positions: u64,
     pub bump: u8,
+    // E-mode category the user opted into; 0 means none
+    pub emode_category: u8,
+}
+
+/// Risk parameters shared by a set of correlated banks, e.g. stablecoins or
+/// SOL and its liquid staking tokens. They replace each member bank's own
+/// parameters for users who opt into the category.
+#[account]
+#[derive(InitSpace)]
+pub struct EModeCategory {
+    pub category_id: u8,
+    #[max_len(32)]
+    pub label: String,
+    // In basis points, like the bank parameters they replace
+    pub max_ltv: u64,
+    pub liquidation_threshold: u64,
+    pub liquidation_bonus: u64,
+    pub bump: u8,
 }
 
 #[account]
@@ -65,10 +150,63 @@ pub struct UserGlobalState {
 pub struct BorrowPosition {
     pub position_id: u64,
     pub owner: Pubkey,
+    // Collateral the position was opened with; part of its address
     pub collateral_mint: Pubkey,
     pub borrow_mint: Pubkey,
-    pub collateral_shares: u64,
+    // Locked deposit shares per collateral mint, starting with `collateral_mint`
+    #[max_len(MAX_POSITION_COLLATERALS)]
+    pub collaterals: Vec<PositionCollateral>,
     pub borrowed_shares: u64,
     pub last_updated: i64,
     pub active: bool,
-}
\ No newline at end of file
+    // USD millionths charged against an isolated collateral bank's debt ceiling
+    pub isolated_debt: u64,
+}
+
+/// Deposit shares of one mint locked behind a borrow position.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub struct PositionCollateral {
+    pub mint: Pubkey,
+    pub shares: u64,
+}
+
+/// How a money-moving instruction interprets its amount argument.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AmountMode {
+    /// Token base units, e.g. lamports.
+    Tokens(u64),
+    /// Deposit or borrow shares of the bank.
+    Shares(u64),
+    /// Everything available: the wallet balance for deposits, all free
+    /// shares for withdrawals, the position's full debt for repayments.
+    Max,
+}
+
+/// Copy of a Token-2022 mint's `InterestBearingConfig`, kept on the bank so
+/// amounts can be valued without the mint account.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub struct MintInterestConfig {
+    pub initialization_timestamp: i64,
+    /// Annual rate in basis points, averaged up to `last_update_timestamp`.
+    pub pre_update_average_rate: i16,
+    pub last_update_timestamp: i64,
+    /// Annual rate in basis points since `last_update_timestamp`.
+    pub current_rate: i16,
+}
+
+/// Minimum Wormhole verification a Pyth price update must carry, mirroring
+/// the receiver's `VerificationLevel` so it can be stored on a bank.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub enum OracleVerification {
+    Partial { num_signatures: u8 },
+    Full,
+}
+
+impl From<OracleVerification> for VerificationLevel {
+    fn from(level: OracleVerification) -> Self {
+        match level {
+            OracleVerification::Partial { num_signatures } => VerificationLevel::Partial { num_signatures },
+            OracleVerification::Full => VerificationLevel::Full,
+        }
+    }
+}
@@ -1,101 +1,1214 @@
-use std::ops::{Add, Div, Mul};
 use anchor_lang::prelude::*;
 use anchor_lang::prelude::Account;
 use anchor_lang::prelude::Clock;
 use anchor_lang::prelude::Result;
-use pyth_solana_receiver_sdk::price_update::VerificationLevel;
-use pyth_solana_receiver_sdk::price_update::{get_feed_id_from_hex, PriceUpdateV2, Price};
-use crate::constants::MAXIMUM_AGE;
-use crate::state::Bank;
+use anchor_spl::associated_token::get_associated_token_address_with_program_id;
+use anchor_spl::token_interface::{burn, mint_to, Burn, Mint, MintTo, TokenAccount, TokenInterface};
+use anchor_spl::token_interface::spl_token_2022::{
+    self,
+    extension::{
+        default_account_state::DefaultAccountState,
+        interest_bearing_mint::InterestBearingConfig,
+        permanent_delegate::PermanentDelegate,
+        transfer_fee::TransferFeeConfig,
+        transfer_hook::TransferHook,
+        BaseStateWithExtensions,
+        StateWithExtensions,
+    },
+    state::{AccountState, Mint as MintState},
+};
+use pyth_solana_receiver_sdk::error::GetPriceError;
+use pyth_solana_receiver_sdk::price_update::{PriceUpdateV2, Price};
+use crate::constants::{BPS_DENOMINATOR, MAX_COMPOUND_PERIODS, MAX_ISOLATED_BORROW_MINTS, MAX_POSITION_COLLATERALS, RATE_PRECISION, USD_PRECISION};
+use crate::math::{Decimal, Rounding};
+use crate::state::{Bank, BorrowPosition, EModeCategory, MintInterestConfig, PositionCollateral, ProtocolConfig, UserGlobalState, UserTokenState};
 use crate::error::ErrorCode;
-use crate::state::PythNetworkFeedId;
-
-pub fn accrue_interest(bank: &mut Account<Bank>, current_time: i64) -> Result<()> {
-    let periods = (current_time - bank.last_compound_time) / bank.interest_accrual_period;
-    
-    if periods > 0 {
-        // Rate factor of 1_000_000 (1e6) used for fixed-point decimal math
-        // Interest rates are stored as integers (e.g. 5_000 = 0.5%)
-        // This factor allows precise interest calculations while avoiding floating point
-        let rate_factor = 1_000_000u128; // 1e6 precision for interest rate math
-        let rate_per_period = (bank.borrow_interest_rate as u128)
-            .checked_mul(rate_factor)
-            .and_then(|v| v.checked_div(1_000_000)) // Divides by rate_factor (1e6) to normalize the interest rate calculation
+use crate::events::InterestAccrued;
+
+/// Brings the bank's supply and borrow indices up to `current_time`.
+///
+/// This is the only place interest is compounded. The borrow rate comes from
+/// the bank's utilization curve, evaluated against `liquidity` (the treasury
+/// balance), and debt grows by it for every elapsed period. The reserve
+/// factor's cut of the interest it generates is booked to
+/// `accumulated_fees` in whole tokens; the rest is spread over every deposit
+/// share (free and locked as collateral).
+///
+/// After a long idle gap only as many periods as `compound_index` can apply
+/// without overflowing are compounded, and `last_compound_time` moves forward
+/// by just those, so the remainder is picked up by later calls.
+///
+/// Returns an `InterestAccrued` event for the caller to emit when any period
+/// was compounded.
+pub fn accrue_interest(bank: &mut Bank, liquidity: u64, current_time: i64) -> Result<Option<InterestAccrued>> {
+    require!(bank.interest_accrual_period > 0, ErrorCode::InvalidInterestAccrualPeriod);
+    let liquidity = available_liquidity(bank, liquidity);
+    let utilization = calculate_utilization(bank, liquidity)?;
+    let borrow_rate = calculate_borrow_rate(bank, utilization)?;
+    debug_msg!("[accrue_interest] Utilization: {}", utilization);
+    debug_msg!("[accrue_interest] Borrow rate per period: {}", borrow_rate);
+
+    let elapsed_periods = current_time
+        .checked_sub(bank.last_compound_time)
+        .ok_or(ErrorCode::MathOverflow)?
+        / bank.interest_accrual_period;
+    let mut periods = 0;
+    let mut protocol_fee = 0;
+    if elapsed_periods > 0 {
+        let old_borrow_index = Decimal::from_scaled_val(bank.borrow_index);
+        let new_borrow_index;
+        (new_borrow_index, periods) = compound_index(old_borrow_index, borrow_rate, elapsed_periods)?;
+        debug_msg!("[accrue_interest] Compounding {} of {} periods", periods, elapsed_periods);
+
+        let borrowed_shares = Decimal::from(bank.total_borrowed_shares);
+        let interest = borrowed_shares
+            .try_mul(new_borrow_index)?
+            .try_sub(borrowed_shares.try_mul(old_borrow_index)?)?;
+
+        protocol_fee = interest
+            .try_mul(Decimal::from_bps(bank.reserve_factor))?
+            .try_to_u64(Rounding::Down)?;
+        bank.accumulated_fees = bank.accumulated_fees
+            .checked_add(protocol_fee)
             .ok_or(ErrorCode::MathOverflow)?;
+        let supplier_interest = interest.try_sub(Decimal::from(protocol_fee))?;
+        debug_msg!("[accrue_interest] Protocol fee: {}", protocol_fee);
+
+        let supply_shares = total_supply_shares(bank)?;
+        if supply_shares > 0 {
+            let supply_index_increase = supplier_interest.try_div(Decimal::from(supply_shares))?;
+            bank.supply_index = Decimal::from_scaled_val(bank.supply_index)
+                .try_add(supply_index_increase)?
+                .to_scaled_val()?;
+        }
+        bank.borrow_index = new_borrow_index.to_scaled_val()?;
+        bank.last_compound_time = bank.last_compound_time
+            .checked_add(periods.checked_mul(bank.interest_accrual_period).ok_or(ErrorCode::MathOverflow)?)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        debug_msg!("[accrue_interest] Supply index: {}", Decimal::from_scaled_val(bank.supply_index));
+        debug_msg!("[accrue_interest] Borrow index: {}", Decimal::from_scaled_val(bank.borrow_index));
+    }
+
+    // Publish the rates implied by current utilization for clients.
+    let rate_precision = Decimal::from(RATE_PRECISION);
+    bank.borrow_interest_rate = borrow_rate.try_mul(rate_precision)?.try_to_u64(Rounding::Down)?;
+    bank.deposit_interest_rate = borrow_rate
+        .try_mul(utilization)?
+        .try_mul(Decimal::one().try_sub(Decimal::from_bps(bank.reserve_factor))?)?
+        .try_mul(rate_precision)?
+        .try_to_u64(Rounding::Down)?;
 
-        let total_assets = calculate_total_assets(bank);
-        let compounded = compound_interest(total_assets, rate_per_period, periods as u32)?;
-        
-        bank.total_borrowed_shares = compounded
-            .checked_mul(bank.total_borrowed_shares as u128)
-            .and_then(|v| v.checked_div(total_assets))
-            .ok_or(ErrorCode::MathOverflow)? as u64;
-        
-        bank.last_compound_time += periods * bank.interest_accrual_period;
+    if periods == 0 {
+        return Ok(None);
     }
+    Ok(Some(InterestAccrued {
+        mint: bank.mint_address,
+        periods,
+        supply_index: bank.supply_index,
+        borrow_index: bank.borrow_index,
+        deposit_interest_rate: bank.deposit_interest_rate,
+        borrow_interest_rate: bank.borrow_interest_rate,
+        protocol_fee,
+        accumulated_fees: bank.accumulated_fees,
+        timestamp: current_time,
+    }))
+}
+
+/// Sanity rules every bank's risk, rate and fee parameters must satisfy,
+/// checked whenever they are set.
+pub fn validate_bank_config(bank: &Bank) -> Result<()> {
+    require!(bank.interest_accrual_period > 0, ErrorCode::InvalidInterestAccrualPeriod);
+    validate_risk_params(&RiskParams::from(bank))?;
+    require!(
+        bank.liquidation_close_factor > 0 && bank.liquidation_close_factor <= BPS_DENOMINATOR,
+        ErrorCode::InvalidRiskParameters
+    );
+    require!(
+        bank.oracle_max_age > 0 && bank.oracle_max_confidence < BPS_DENOMINATOR,
+        ErrorCode::InvalidOracleConfig
+    );
+    require!(
+        bank.optimal_utilization > 0 && bank.optimal_utilization <= BPS_DENOMINATOR,
+        ErrorCode::InvalidRateModel
+    );
+    require!(
+        bank.deposit_fee < BPS_DENOMINATOR
+            && bank.withdrawal_fee < BPS_DENOMINATOR
+            && bank.reserve_factor <= BPS_DENOMINATOR
+            && bank.flash_loan_fee < BPS_DENOMINATOR,
+        ErrorCode::InvalidFee
+    );
+    require!(
+        bank.isolated_borrow_mints.len() <= MAX_ISOLATED_BORROW_MINTS
+            && !bank.isolated_borrow_mints.contains(&bank.mint_address),
+        ErrorCode::InvalidIsolationConfig
+    );
+    Ok(())
+}
+
+/// LTV, liquidation threshold and bonus rules shared by banks and e-mode categories.
+pub fn validate_risk_params(params: &RiskParams) -> Result<()> {
+    require!(
+        params.max_ltv < params.liquidation_threshold && params.liquidation_threshold <= BPS_DENOMINATOR,
+        ErrorCode::InvalidRiskParameters
+    );
+    require!(params.liquidation_bonus <= BPS_DENOMINATOR, ErrorCode::InvalidRiskParameters);
+    // Seizing collateral plus the bonus must not exceed the collateral that
+    // backs a position right at the liquidation threshold.
+    let threshold_with_bonus = (params.liquidation_threshold as u128)
+        * ((BPS_DENOMINATOR + params.liquidation_bonus) as u128);
+    require!(
+        threshold_with_bonus <= (BPS_DENOMINATOR as u128) * (BPS_DENOMINATOR as u128),
+        ErrorCode::InvalidRiskParameters
+    );
     Ok(())
 }
 
-pub fn calculate_user_assets(bank: &Bank, user_shares: u64, last_update: i64) -> Result<u128> {
-    let time_elapsed = Clock::get()?.unix_timestamp.checked_sub(last_update).unwrap_or(0);
-    let periods = time_elapsed / bank.interest_accrual_period;
-    
-    let rate_per_period = (bank.deposit_interest_rate as u128)
-        .checked_mul(1_000_000)
-        .and_then(|v| v.checked_div(1_000_000))
+/// Fails if `operation` (one of the PAUSE_* bits) is frozen protocol-wide or on `bank`.
+pub fn require_not_paused(protocol_config: &ProtocolConfig, bank: &Bank, operation: u8) -> Result<()> {
+    require!(protocol_config.paused_operations & operation == 0, ErrorCode::ProtocolPaused);
+    require!(bank.paused_operations & operation == 0, ErrorCode::BankPaused);
+    Ok(())
+}
+
+/// USD value in the millionths isolated debt ceilings are tracked in, rounded up.
+pub fn to_usd_units(value: Decimal) -> Result<u64> {
+    value.try_mul(Decimal::from(USD_PRECISION))?.try_to_u64(Rounding::Up)
+}
+
+/// Releases the share of `position`'s isolated debt that `repaid_shares` of
+/// its debt stand for from the collateral bank's ceiling usage. The debt is
+/// charged at its USD value when borrowed and released pro rata, so interest
+/// and price moves never consume the ceiling.
+pub fn release_isolated_debt(bank_collateral: &mut Bank, position: &mut BorrowPosition, repaid_shares: u64) -> Result<()> {
+    if position.isolated_debt == 0 || position.borrowed_shares == 0 {
+        return Ok(());
+    }
+    let released = if repaid_shares >= position.borrowed_shares {
+        position.isolated_debt
+    } else {
+        ((position.isolated_debt as u128) * (repaid_shares as u128) / (position.borrowed_shares as u128)) as u64
+    };
+    position.isolated_debt -= released;
+    bank_collateral.isolated_debt = bank_collateral.isolated_debt.saturating_sub(released);
+    debug_msg!("Released {} of isolated debt, {} outstanding on the bank", released, bank_collateral.isolated_debt);
+    Ok(())
+}
+
+/// Treasury balance that can be lent out or withdrawn by depositors, i.e.
+/// excluding protocol fees awaiting collection.
+pub fn available_liquidity(bank: &Bank, treasury_balance: u64) -> u64 {
+    treasury_balance.saturating_sub(bank.accumulated_fees)
+}
+
+/// Fee of `fee_bps` on `amount`, rounded up in the protocol's favour.
+pub fn calculate_fee(amount: u64, fee_bps: u64) -> Result<u64> {
+    Decimal::from(amount)
+        .try_mul(Decimal::from_bps(fee_bps))?
+        .try_to_u64(Rounding::Up)
+}
+
+/// Gross amount whose remainder after a `fee_bps` fee is at least `net_amount`.
+pub fn calculate_amount_before_fee(net_amount: u64, fee_bps: u64) -> Result<u64> {
+    let remainder_bps = BPS_DENOMINATOR.checked_sub(fee_bps).ok_or(ErrorCode::InvalidFee)?;
+    Decimal::from(net_amount)
+        .try_div_rounded(Decimal::from_bps(remainder_bps), Rounding::Up)?
+        .try_to_u64(Rounding::Up)
+}
+
+/// Share of the bank's funds that is lent out: borrows / (borrows + liquidity).
+pub fn calculate_utilization(bank: &Bank, liquidity: u64) -> Result<Decimal> {
+    let borrowed = calculate_debt_amount(bank, bank.total_borrowed_shares, Rounding::Up)?;
+    let total = (borrowed as u128)
+        .checked_add(liquidity as u128)
+        .ok_or(ErrorCode::MathOverflow)?;
+    if total == 0 {
+        return Ok(Decimal::zero());
+    }
+    Decimal::from(borrowed as u128).try_div(Decimal::from(total))
+}
+
+/// Borrow rate per accrual period at `utilization`, following the kinked curve:
+/// `base + slope1 * u / optimal` below the kink and
+/// `base + slope1 + slope2 * (u - optimal) / (1 - optimal)` above it.
+pub fn calculate_borrow_rate(bank: &Bank, utilization: Decimal) -> Result<Decimal> {
+    let rate_precision = Decimal::from(RATE_PRECISION);
+    let base_rate = Decimal::from(bank.base_borrow_rate).try_div(rate_precision)?;
+    let slope1 = Decimal::from(bank.borrow_rate_slope1).try_div(rate_precision)?;
+    let slope2 = Decimal::from(bank.borrow_rate_slope2).try_div(rate_precision)?;
+    let optimal = Decimal::from_bps(bank.optimal_utilization);
+
+    if utilization <= optimal {
+        base_rate.try_add(slope1.try_mul(utilization)?.try_div(optimal)?)
+    } else {
+        let excess = utilization.try_sub(optimal)?;
+        let excess_range = Decimal::one().try_sub(optimal)?;
+        base_rate
+            .try_add(slope1)?
+            .try_add(slope2.try_mul(excess)?.try_div(excess_range)?)
+    }
+}
+
+/// All deposit shares, whether free or locked as collateral.
+pub fn total_supply_shares(bank: &Bank) -> Result<u64> {
+    bank.total_deposited_shares
+        .checked_add(bank.total_collateral_shares)
+        .ok_or(ErrorCode::MathOverflow.into())
+}
+
+/// Tokens represented by `shares` deposit shares at the current supply index.
+pub fn calculate_deposit_amount(bank: &Bank, shares: u64, rounding: Rounding) -> Result<u64> {
+    Decimal::from(shares)
+        .try_mul_rounded(Decimal::from_scaled_val(bank.supply_index), rounding)?
+        .try_to_u64(rounding)
+}
+
+/// Deposit shares worth `amount` tokens at the current supply index.
+pub fn calculate_deposit_shares(bank: &Bank, amount: u64, rounding: Rounding) -> Result<u64> {
+    Decimal::from(amount)
+        .try_div_rounded(Decimal::from_scaled_val(bank.supply_index), rounding)?
+        .try_to_u64(rounding)
+}
+
+/// Tokens owed for `shares` borrow shares at the current borrow index.
+pub fn calculate_debt_amount(bank: &Bank, shares: u64, rounding: Rounding) -> Result<u64> {
+    Decimal::from(shares)
+        .try_mul_rounded(Decimal::from_scaled_val(bank.borrow_index), rounding)?
+        .try_to_u64(rounding)
+}
+
+/// Borrow shares worth `amount` tokens at the current borrow index.
+pub fn calculate_borrow_shares(bank: &Bank, amount: u64, rounding: Rounding) -> Result<u64> {
+    Decimal::from(amount)
+        .try_div_rounded(Decimal::from_scaled_val(bank.borrow_index), rounding)?
+        .try_to_u64(rounding)
+}
+
+/// Edge of the confidence interval that favours the protocol when a USD
+/// value is rounded in `rounding`'s direction: `price - conf` for values
+/// rounded down (collateral), `price + conf` for values rounded up (debt).
+fn conservative_price(price: &Price, rounding: Rounding) -> Result<u64> {
+    require!(price.price > 0, ErrorCode::InvalidPrice);
+    let adjusted = match rounding {
+        Rounding::Down => (price.price as u64).checked_sub(price.conf),
+        Rounding::Up => (price.price as u64).checked_add(price.conf),
+    };
+    match adjusted {
+        Some(adjusted) if adjusted > 0 => Ok(adjusted),
+        _ => Err(ErrorCode::InvalidPrice.into()),
+    }
+}
+
+/// USD value of `amount` base units of a mint with `decimals` at `price`.
+/// Collateral should be valued with `Rounding::Down` and debt with
+/// `Rounding::Up`, which also picks the matching confidence bound.
+pub fn calculate_token_value(amount: u64, decimals: u8, price: &Price, rounding: Rounding) -> Result<Decimal> {
+    let unit_price = conservative_price(price, rounding)?;
+    let exponent = price.exponent
+        .checked_sub(decimals as i32)
+        .ok_or(ErrorCode::MathOverflow)?;
+    // amount * price fits in a u128, so only the final power-of-ten scaling rounds
+    let raw_value = (amount as u128)
+        .checked_mul(unit_price as u128)
+        .ok_or(ErrorCode::MathOverflow)?;
+    Decimal::from(raw_value).try_mul_pow10(exponent, rounding)
+}
+
+/// Number of base units of a mint with `decimals` worth `value` USD at `price`.
+/// Rounding up (e.g. collateral to lock) prices the token at `price - conf`,
+/// rounding down (e.g. collateral to release) at `price + conf`.
+pub fn calculate_token_amount(value: Decimal, decimals: u8, price: &Price, rounding: Rounding) -> Result<u64> {
+    let unit_price = match rounding {
+        Rounding::Up => conservative_price(price, Rounding::Down)?,
+        Rounding::Down => conservative_price(price, Rounding::Up)?,
+    };
+    let exponent = (decimals as i32)
+        .checked_sub(price.exponent)
+        .ok_or(ErrorCode::MathOverflow)?;
+    value
+        .try_mul_pow10_div(exponent, unit_price, rounding)?
+        .try_to_u64(rounding)
+}
+
+/// Reads the price for `bank` from `price_update`, rejecting accounts that
+/// are not owned by the bank's oracle program or carry a different feed, and
+/// prices that fail the bank's staleness, verification or confidence gates.
+///
+/// For interest-bearing mints the price is scaled by the interest the mint
+/// has accrued, so it applies to raw token amounts like any other mint.
+pub fn get_validated_price(price_update: &Account<PriceUpdateV2>, bank: &Bank) -> Result<Price> {
+    require_keys_eq!(*price_update.to_account_info().owner, bank.oracle_owner, ErrorCode::InvalidPriceFeed);
+    require!(price_update.price_message.feed_id == bank.oracle_feed_id, ErrorCode::InvalidPriceFeed);
+    debug_msg!("[get_validated_price] Feed ID: {:?}", bank.oracle_feed_id);
+    let clock = Clock::get()?;
+    let mut price = price_update
+        .get_price_no_older_than_with_custom_verification_level(
+            &clock,
+            bank.oracle_max_age,
+            &bank.oracle_feed_id,
+            bank.oracle_min_verification.into(),
+        )
+        .map_err(|err| match err {
+            GetPriceError::InsufficientVerificationLevel => ErrorCode::InsufficientPriceVerification,
+            GetPriceError::PriceTooOld => ErrorCode::StalePrice,
+            _ => ErrorCode::InvalidPriceFeed,
+        })?;
+    require!(price.price > 0, ErrorCode::InvalidPrice);
+
+    let max_conf = (price.price as u128)
+        .checked_mul(bank.oracle_max_confidence as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        / BPS_DENOMINATOR as u128;
+    debug_msg!("[get_validated_price] Price: {}, conf: {}, max conf: {}", price.price, price.conf, max_conf);
+    require!(price.conf as u128 <= max_conf, ErrorCode::PriceConfidenceTooWide);
+
+    if bank.mint_interest_config.is_some() {
+        let multiplier = interest_multiplier(bank, clock.unix_timestamp)?;
+        price.price = i64::try_from(
+            Decimal::from(price.price as u64).try_mul(multiplier)?.try_to_u64(Rounding::Down)?,
+        )
+        .map_err(|_| ErrorCode::MathOverflow)?;
+        price.conf = Decimal::from(price.conf).try_mul_rounded(multiplier, Rounding::Up)?.try_to_u64(Rounding::Up)?;
+        debug_msg!("[get_validated_price] Interest multiplier: {}, scaled price: {}", multiplier, price.price);
+    }
+    Ok(price)
+}
+
+// ---------------------------------------------------------------------------
+// Token-2022 mint extensions
+// ---------------------------------------------------------------------------
+
+/// Rejects mints whose extensions let someone else move or freeze the
+/// treasury's tokens, or whose transfers need hook accounts this program
+/// never passes. Legacy SPL mints have no extensions.
+pub fn validate_mint_extensions(mint_info: &AccountInfo) -> Result<()> {
+    if *mint_info.owner != spl_token_2022::ID {
+        return Ok(());
+    }
+    let data = mint_info.try_borrow_data()?;
+    let mint = StateWithExtensions::<MintState>::unpack(&data)?;
+
+    if let Ok(extension) = mint.get_extension::<PermanentDelegate>() {
+        let delegate: Option<Pubkey> = extension.delegate.into();
+        debug_msg!("[validate_mint_extensions] Permanent delegate: {:?}", delegate);
+        require!(delegate.is_none(), ErrorCode::UnsupportedMintExtension);
+    }
+    if let Ok(extension) = mint.get_extension::<DefaultAccountState>() {
+        debug_msg!("[validate_mint_extensions] Default account state: {}", extension.state);
+        require!(extension.state != AccountState::Frozen as u8, ErrorCode::UnsupportedMintExtension);
+    }
+    if let Ok(extension) = mint.get_extension::<TransferHook>() {
+        let program_id: Option<Pubkey> = extension.program_id.into();
+        debug_msg!("[validate_mint_extensions] Transfer hook program: {:?}", program_id);
+        require!(program_id.is_none(), ErrorCode::UnsupportedMintExtension);
+    }
+    Ok(())
+}
+
+/// Refreshes the bank's copy of the mint's interest-bearing config, which the
+/// rate authority can change at any time.
+pub fn sync_mint_interest_config(bank: &mut Bank, mint_info: &AccountInfo) -> Result<()> {
+    if *mint_info.owner != spl_token_2022::ID {
+        bank.mint_interest_config = None;
+        return Ok(());
+    }
+    let data = mint_info.try_borrow_data()?;
+    let mint = StateWithExtensions::<MintState>::unpack(&data)?;
+    bank.mint_interest_config = mint
+        .get_extension::<InterestBearingConfig>()
+        .ok()
+        .map(|config| MintInterestConfig {
+            initialization_timestamp: config.initialization_timestamp.into(),
+            pre_update_average_rate: config.pre_update_average_rate.into(),
+            last_update_timestamp: config.last_update_timestamp.into(),
+            current_rate: config.current_rate.into(),
+        });
+    Ok(())
+}
+
+/// Interest an interest-bearing mint has accrued by `current_time`: the
+/// factor between raw amounts and the UI amounts that oracles price.
+/// Continuously compounded, as in the Token-2022 extension. One for other mints.
+pub fn interest_multiplier(bank: &Bank, current_time: i64) -> Result<Decimal> {
+    match bank.mint_interest_config {
+        Some(config) => continuous_interest(&config, current_time),
+        None => Ok(Decimal::one()),
+    }
+}
+
+/// `e^(rate * years)` for the mint's rate history, in fixed point. Rates can
+/// be negative, in which case the factor is below one.
+fn continuous_interest(config: &MintInterestConfig, current_time: i64) -> Result<Decimal> {
+    // 365.24 days, as in the Token-2022 extension
+    const SECONDS_PER_YEAR: u128 = 31_556_736;
+    let pre_update = config.last_update_timestamp.saturating_sub(config.initialization_timestamp) as i128;
+    let post_update = current_time.saturating_sub(config.last_update_timestamp) as i128;
+    let rate_seconds = (config.pre_update_average_rate as i128)
+        .checked_mul(pre_update)
+        .and_then(|pre| (config.current_rate as i128).checked_mul(post_update)?.checked_add(pre))
+        .ok_or(ErrorCode::MathOverflow)?;
+    let growth = Decimal::from(rate_seconds.unsigned_abs())
+        .try_div(Decimal::from(SECONDS_PER_YEAR * BPS_DENOMINATOR as u128))?
+        .try_exp()?;
+    if rate_seconds < 0 {
+        Decimal::one().try_div(growth)
+    } else {
+        Ok(growth)
+    }
+}
+
+/// Amount to send so that `net_amount` arrives after the mint's transfer fee.
+pub fn amount_with_transfer_fee(mint_info: &AccountInfo, net_amount: u64) -> Result<u64> {
+    if *mint_info.owner != spl_token_2022::ID {
+        return Ok(net_amount);
+    }
+    let data = mint_info.try_borrow_data()?;
+    let mint = StateWithExtensions::<MintState>::unpack(&data)?;
+    let Ok(config) = mint.get_extension::<TransferFeeConfig>() else {
+        return Ok(net_amount);
+    };
+    let fee = config
+        .calculate_inverse_epoch_fee(Clock::get()?.epoch, net_amount)
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok(net_amount.checked_add(fee).ok_or(ErrorCode::MathOverflow)?)
+}
+// ---------------------------------------------------------------------------
+// E-mode
+// ---------------------------------------------------------------------------
+
+/// Risk parameters a bank is valued with for one user, in basis points.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RiskParams {
+    pub max_ltv: u64,
+    pub liquidation_threshold: u64,
+    pub liquidation_bonus: u64,
+}
+
+impl From<&Bank> for RiskParams {
+    fn from(bank: &Bank) -> Self {
+        RiskParams {
+            max_ltv: bank.max_ltv,
+            liquidation_threshold: bank.liquidation_threshold,
+            liquidation_bonus: bank.liquidation_bonus,
+        }
+    }
+}
+
+impl From<&EModeCategory> for RiskParams {
+    fn from(category: &EModeCategory) -> Self {
+        RiskParams {
+            max_ltv: category.max_ltv,
+            liquidation_threshold: category.liquidation_threshold,
+            liquidation_bonus: category.liquidation_bonus,
+        }
+    }
+}
+
+/// The category's parameters if `bank` belongs to the user's e-mode category,
+/// the bank's own otherwise.
+pub fn risk_params(bank: &Bank, emode: Option<&EModeCategory>) -> RiskParams {
+    match emode {
+        Some(category) if in_emode(bank, category) => RiskParams::from(category),
+        _ => RiskParams::from(bank),
+    }
+}
+
+pub fn in_emode(bank: &Bank, category: &EModeCategory) -> bool {
+    bank.emode_category != 0 && bank.emode_category == category.category_id
+}
+
+/// Resolves the e-mode category account passed alongside a user's global
+/// state. Its seeds are derived from the user's category, so all that is left
+/// to check is that it is present when the user opted into one.
+pub fn user_emode<'a>(
+    global_state: &UserGlobalState,
+    emode_category: &'a Option<Box<Account<EModeCategory>>>,
+) -> Result<Option<&'a EModeCategory>> {
+    if global_state.emode_category == 0 {
+        return Ok(None);
+    }
+    let category = emode_category.as_deref().ok_or(ErrorCode::InvalidEModeCategory)?;
+    Ok(Some(&**category))
+}
+
+// ---------------------------------------------------------------------------
+// Position collateral
+// ---------------------------------------------------------------------------
+
+/// Accounts per extra collateral in `remaining_accounts`.
+pub const EXTRA_COLLATERAL_ACCOUNTS: usize = 5;
+
+/// A collateral of a borrow position beyond the instruction's own
+/// `mint_collateral`, read from `remaining_accounts` as the tuple
+/// `[bank, user_token_state, receipt_mint, receipt_token_account, price_update]`,
+/// where the receipt account is the user's associated token account for the
+/// bank's receipt mint. Banks are not accrued, which can only understate
+/// what their shares are worth.
+pub struct ExtraCollateral<'info> {
+    pub bank: Account<'info, Bank>,
+    pub user_state: Account<'info, UserTokenState>,
+    pub receipt_mint: InterfaceAccount<'info, Mint>,
+    pub receipt_account: InterfaceAccount<'info, TokenAccount>,
+    pub price: Price,
+}
+
+impl<'info> ExtraCollateral<'info> {
+    pub fn health_asset(&self) -> HealthAsset<'_> {
+        HealthAsset {
+            bank: &self.bank,
+            user_state: &self.user_state,
+            receipt_balance: self.receipt_account.amount,
+            price: self.price,
+        }
+    }
+
+    /// Moves `shares` from the user's free deposits into a position, burning
+    /// their receipts.
+    pub fn lock(
+        &mut self,
+        shares: u64,
+        user: &AccountInfo<'info>,
+        token_program: &Interface<'info, TokenInterface>,
+        current_time: i64,
+    ) -> Result<()> {
+        burn(
+            CpiContext::new(
+                token_program.to_account_info(),
+                Burn {
+                    mint: self.receipt_mint.to_account_info(),
+                    from: self.receipt_account.to_account_info(),
+                    authority: user.clone(),
+                },
+            ),
+            shares,
+        )?;
+        lock_collateral_shares(&mut self.bank, &mut self.user_state, shares, current_time)?;
+        self.persist()
+    }
+
+    /// Releases `shares` from a position back to the user's free deposits,
+    /// minting their receipts.
+    pub fn unlock(
+        &mut self,
+        shares: u64,
+        token_program: &Interface<'info, TokenInterface>,
+        current_time: i64,
+    ) -> Result<()> {
+        let mint = self.bank.mint_address;
+        let (_, bump) = Pubkey::find_program_address(&[mint.as_ref()], &crate::ID);
+        let bank_seeds: &[&[&[u8]]] = &[&[mint.as_ref(), &[bump]]];
+        mint_to(
+            CpiContext::new_with_signer(
+                token_program.to_account_info(),
+                MintTo {
+                    mint: self.receipt_mint.to_account_info(),
+                    to: self.receipt_account.to_account_info(),
+                    authority: self.bank.to_account_info(),
+                },
+                bank_seeds,
+            ),
+            shares,
+        )?;
+        unlock_collateral_shares(&mut self.bank, &mut self.user_state, shares, current_time)?;
+        self.persist()
+    }
+
+    fn persist(&self) -> Result<()> {
+        self.bank.exit(&crate::ID)?;
+        self.user_state.exit(&crate::ID)
+    }
+}
+
+/// Reads `count` extra collaterals of `user` from the front of
+/// `remaining_accounts` and returns them with the accounts that follow.
+pub fn load_extra_collaterals<'info>(
+    user: &Pubkey,
+    remaining_accounts: &'info [AccountInfo<'info>],
+    count: usize,
+) -> Result<(Vec<ExtraCollateral<'info>>, &'info [AccountInfo<'info>])> {
+    let len = count
+        .checked_mul(EXTRA_COLLATERAL_ACCOUNTS)
         .ok_or(ErrorCode::MathOverflow)?;
+    require!(remaining_accounts.len() >= len, ErrorCode::InvalidCollateralAccounts);
+    let (collateral_infos, rest) = remaining_accounts.split_at(len);
+
+    let mut collaterals: Vec<ExtraCollateral> = Vec::with_capacity(count);
+    for tuple in collateral_infos.chunks(EXTRA_COLLATERAL_ACCOUNTS) {
+        let bank = Account::<Bank>::try_from(&tuple[0])?;
+        require!(!bank.flash_loan_active, ErrorCode::BankLocked);
+        require!(
+            collaterals.iter().all(|collateral| collateral.bank.mint_address != bank.mint_address),
+            ErrorCode::InvalidCollateralAccounts
+        );
+        let user_state = Account::<UserTokenState>::try_from(&tuple[1])?;
+        require_keys_eq!(user_state.owner, *user, ErrorCode::InvalidCollateralAccounts);
+        require_keys_eq!(user_state.mint_address, bank.mint_address, ErrorCode::InvalidCollateralAccounts);
+        let receipt_mint = InterfaceAccount::<Mint>::try_from(&tuple[2])?;
+        require_keys_eq!(receipt_mint.key(), bank.receipt_mint, ErrorCode::InvalidCollateralAccounts);
+        let receipt_account = InterfaceAccount::<TokenAccount>::try_from(&tuple[3])?;
+        let expected_receipt_account = get_associated_token_address_with_program_id(
+            user,
+            &bank.receipt_mint,
+            tuple[3].owner,
+        );
+        require_keys_eq!(receipt_account.key(), expected_receipt_account, ErrorCode::InvalidCollateralAccounts);
+        let price_update = Account::<PriceUpdateV2>::try_from(&tuple[4])?;
+        let price = get_validated_price(&price_update, &bank)?;
+        collaterals.push(ExtraCollateral { bank, user_state, receipt_mint, receipt_account, price });
+    }
+    Ok((collaterals, rest))
+}
+
+/// Deposit shares worth up to `remaining_credit` of LTV-weighted USD value,
+/// out of `available_shares` of `bank` valued at `price` and weighted by
+/// `max_ltv`. The credit they stand for is taken off `remaining_credit`.
+///
+/// Used to spread a borrow over a position's collaterals when locking
+/// (round up, so never less than required is locked) and a repayment when
+/// unlocking (round down, so never more than repaid is released).
+pub fn collateral_shares_for_credit(
+    bank: &Bank,
+    price: &Price,
+    max_ltv: u64,
+    available_shares: u64,
+    remaining_credit: &mut Decimal,
+    rounding: Rounding,
+) -> Result<u64> {
+    if available_shares == 0 || *remaining_credit == Decimal::zero() {
+        return Ok(0);
+    }
+    let ltv = Decimal::from_bps(max_ltv);
+    let available_amount = calculate_deposit_amount(bank, available_shares, Rounding::Down)?;
+    let available_credit = calculate_token_value(available_amount, bank.mint_decimals, price, Rounding::Down)?
+        .try_mul(ltv)?;
+    let credit = if available_credit < *remaining_credit { available_credit } else { *remaining_credit };
+    *remaining_credit = remaining_credit.try_sub(credit)?;
+
+    let value = credit.try_div_rounded(ltv, rounding)?;
+    let amount = calculate_token_amount(value, bank.mint_decimals, price, rounding)?;
+    Ok(calculate_deposit_shares(bank, amount, rounding)?.min(available_shares))
+}
+
+/// Part of `locked_shares` that backs `repaid_shares` of a position's
+/// `borrowed_shares`, rounded down.
+pub fn pro_rata_collateral_shares(locked_shares: u64, repaid_shares: u64, borrowed_shares: u64) -> Result<u64> {
+    require!(borrowed_shares > 0, ErrorCode::MathOverflow);
+    let shares = (locked_shares as u128)
+        .checked_mul(repaid_shares.min(borrowed_shares) as u128)
+        .ok_or(ErrorCode::MathOverflow)?
+        / borrowed_shares as u128;
+    Ok(shares as u64)
+}
+
+/// Moves `shares` from free deposits to locked collateral on the bank and
+/// the user's token state.
+pub fn lock_collateral_shares(bank: &mut Bank, user_state: &mut UserTokenState, shares: u64, current_time: i64) -> Result<()> {
+    bank.total_collateral_shares = bank.total_collateral_shares.checked_add(shares).ok_or(ErrorCode::MathOverflow)?;
+    bank.total_deposited_shares = bank.total_deposited_shares.checked_sub(shares).ok_or(ErrorCode::MathOverflow)?;
+    user_state.deposited_shares = user_state.deposited_shares.checked_sub(shares).ok_or(ErrorCode::MathOverflow)?;
+    user_state.collateral_shares = user_state.collateral_shares.checked_add(shares).ok_or(ErrorCode::MathOverflow)?;
+    user_state.last_updated_deposited = current_time;
+    user_state.last_updated_collateral = current_time;
+    Ok(())
+}
+
+/// Moves `shares` from locked collateral back to free deposits on the bank
+/// and the user's token state.
+pub fn unlock_collateral_shares(bank: &mut Bank, user_state: &mut UserTokenState, shares: u64, current_time: i64) -> Result<()> {
+    bank.total_collateral_shares = bank.total_collateral_shares.checked_sub(shares).ok_or(ErrorCode::MathOverflow)?;
+    bank.total_deposited_shares = bank.total_deposited_shares.checked_add(shares).ok_or(ErrorCode::MathOverflow)?;
+    user_state.collateral_shares = user_state.collateral_shares.checked_sub(shares).ok_or(ErrorCode::MathOverflow)?;
+    user_state.deposited_shares = user_state.deposited_shares.checked_add(shares).ok_or(ErrorCode::MathOverflow)?;
+    user_state.last_updated_deposited = current_time;
+    user_state.last_updated_collateral = current_time;
+    Ok(())
+}
+
+/// Shares of `mint` locked in `position`.
+pub fn position_collateral_shares(position: &BorrowPosition, mint: &Pubkey) -> u64 {
+    position.collaterals
+        .iter()
+        .find(|collateral| collateral.mint == *mint)
+        .map_or(0, |collateral| collateral.shares)
+}
+
+/// Adds `shares` of `mint` to `position`, appending an entry for a new mint.
+pub fn add_position_collateral(position: &mut BorrowPosition, mint: Pubkey, shares: u64) -> Result<()> {
+    match position.collaterals.iter_mut().find(|collateral| collateral.mint == mint) {
+        Some(collateral) => {
+            collateral.shares = collateral.shares.checked_add(shares).ok_or(ErrorCode::MathOverflow)?;
+        }
+        None => {
+            require!(position.collaterals.len() < MAX_POSITION_COLLATERALS, ErrorCode::TooManyPositionCollaterals);
+            position.collaterals.push(PositionCollateral { mint, shares });
+        }
+    }
+    Ok(())
+}
+
+/// Takes `shares` of `mint` off `position`. Emptied entries other than the
+/// position's own `collateral_mint` are dropped.
+pub fn remove_position_collateral(position: &mut BorrowPosition, mint: &Pubkey, shares: u64) -> Result<()> {
+    let primary_mint = position.collateral_mint;
+    let collateral = position.collaterals
+        .iter_mut()
+        .find(|collateral| collateral.mint == *mint)
+        .ok_or(ErrorCode::InvalidCollateralAccounts)?;
+    collateral.shares = collateral.shares.checked_sub(shares).ok_or(ErrorCode::MathOverflow)?;
+    position.collaterals.retain(|collateral| collateral.shares > 0 || collateral.mint == primary_mint);
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Account health
+// ---------------------------------------------------------------------------
+
+/// A bank and the user's state in it that the instruction already holds (and
+/// may have accrued) in its own accounts, along with the user's receipt
+/// balance and a validated price.
+pub struct HealthAsset<'a> {
+    pub bank: &'a Bank,
+    pub user_state: &'a UserTokenState,
+    pub receipt_balance: u64,
+    pub price: Price,
+}
+
+/// Free deposit shares the user can still act on. Receipts can be moved out
+/// of the user's associated token account, so `deposited_shares` only counts
+/// while the matching receipts are still held there.
+pub fn free_deposit_shares(user_state: &UserTokenState, receipt_balance: u64) -> u64 {
+    user_state.deposited_shares.min(receipt_balance)
+}
 
-    let assets = compound_interest(user_shares as u128, rate_per_period, periods as u32)?;
-    Ok(assets)
-}
-
-pub fn calculate_total_assets(bank: &Bank) -> u128 {
-    // Returns the actual token amount (including interest) represented by deposited shares
-    let time_elapsed = Clock::get().unwrap().unix_timestamp - bank.last_compound_time;
-    let periods = time_elapsed / bank.interest_accrual_period;
-    compound_interest(
-        bank.total_deposited_shares as u128,
-        bank.deposit_interest_rate as u128,
-        periods as u32
-    ).unwrap()
-}
-
-pub fn calculate_borrowed_assets(bank: &Bank) -> u128 {
-    // Returns the actual token amount (including interest) represented by borrowed shares
-    let time_elapsed = Clock::get().unwrap().unix_timestamp - bank.last_compound_time;
-    let periods = time_elapsed / bank.interest_accrual_period;
-    compound_interest(
-        bank.total_borrowed_shares as u128,
-        bank.borrow_interest_rate as u128,
-        periods as u32
-    ).unwrap()
-}
-
-pub fn compound_interest(principal: u128, rate_per_period: u128, periods: u32) -> Result<u128> {
-    // Use proper compound interest formula
-    let mut amount = principal;
-    msg!("[compound_interest] Principal: {}", amount);
-    for _ in 0..periods {
-        amount = amount
-            .checked_add(
-                amount.checked_mul(rate_per_period)
-                    .ok_or(ErrorCode::MathOverflow)?
-                    .checked_div(1_000_000)
-                    .ok_or(ErrorCode::MathOverflow)?
-            )
+/// Drops free deposit shares whose receipts have left the user's associated
+/// token account for good, e.g. redeemed from another wallet. The bank
+/// already released those shares when the receipts were burned.
+pub fn reconcile_deposited_shares(user_state: &mut UserTokenState, receipt_balance: u64) {
+    let free_shares = free_deposit_shares(user_state, receipt_balance);
+    if free_shares < user_state.deposited_shares {
+        debug_msg!("Dropping {} deposit shares without receipts", user_state.deposited_shares - free_shares);
+        user_state.deposited_shares = free_shares;
+    }
+}
+
+/// USD totals across every deposit and borrow position of one user.
+pub struct AccountHealth {
+    /// Collateral value weighted by each bank's (or e-mode category's) `max_ltv`.
+    pub borrow_limit: Decimal,
+    /// Collateral value weighted by each bank's (or e-mode category's) `liquidation_threshold`.
+    pub liquidation_limit: Decimal,
+    pub debt_value: Decimal,
+    /// Mints with collateral locked behind an active borrow position.
+    pub collateral_mints: Vec<Pubkey>,
+    /// Whether any of `collateral_mints` belongs to an isolated bank.
+    pub has_isolated_collateral: bool,
+    /// Whether any locked collateral or debt sits in a bank outside the
+    /// e-mode category the health was computed under.
+    pub has_positions_outside_emode: bool,
+}
+
+impl AccountHealth {
+    pub fn is_liquidatable(&self) -> bool {
+        self.debt_value > self.liquidation_limit
+    }
+}
+
+/// Values every deposit (free and locked) and every active borrow position of
+/// `user` in USD, and records which mints back those positions. Banks in
+/// `emode` are weighted with the category's parameters instead of their own.
+/// Free deposits in isolated banks are left out.
+///
+/// `remaining_accounts` must hold, in order:
+/// 1. one `BorrowPosition` per entry of `active_positions`, and
+/// 2. for each distinct entry of `deposited_mints` not covered by
+///    `known_assets`, the tuple
+///    `[bank, user_token_state, receipt_token_account, price_update]`, where
+///    the receipt account is the user's associated token account for the
+///    bank's receipt mint.
+///
+/// Every mint is valued once, so a mint listed twice (or also present in
+/// `known_assets`) cannot count its collateral twice, and each tuple must
+/// belong to a different mint.
+///
+/// Banks read from `remaining_accounts` are not accrued here: their debt is
+/// projected forward at the last published borrow rate, and their supply
+/// index is left as stored, which can only understate collateral.
+pub fn calculate_account_health<'info>(
+    user: &Pubkey,
+    global_state: &UserGlobalState,
+    known_assets: &[HealthAsset],
+    remaining_accounts: &'info [AccountInfo<'info>],
+    emode: Option<&EModeCategory>,
+    current_time: i64,
+) -> Result<AccountHealth> {
+    let positions_len = global_state.active_positions.len();
+    require!(remaining_accounts.len() >= positions_len, ErrorCode::InvalidHealthAccounts);
+    let (position_infos, mut asset_infos) = remaining_accounts.split_at(positions_len);
+
+    let mut positions = Vec::with_capacity(positions_len);
+    let mut collateral_mints = Vec::new();
+    for (key, info) in global_state.active_positions.iter().zip(position_infos) {
+        require_keys_eq!(*key, info.key(), ErrorCode::InvalidHealthAccounts);
+        let position = Account::<BorrowPosition>::try_from(info)?;
+        require_keys_eq!(position.owner, *user, ErrorCode::InvalidHealthAccounts);
+        positions.push((position.borrow_mint, position.borrowed_shares));
+        for collateral in position.collaterals.iter() {
+            if collateral.shares > 0 && !collateral_mints.contains(&collateral.mint) {
+                collateral_mints.push(collateral.mint);
+            }
+        }
+    }
+    // Every borrowed or locked mint has a user token state, so it must be listed.
+    // A position without debt may outlive the (closed) state of its borrow mint.
+    let borrowed_mints = positions.iter().filter(|(_, shares)| *shares > 0).map(|(borrow_mint, _)| borrow_mint);
+    for mint in borrowed_mints.chain(collateral_mints.iter()) {
+        require!(global_state.deposited_mints.contains(mint), ErrorCode::InvalidHealthAccounts);
+    }
+
+    let mut health = AccountHealth {
+        borrow_limit: Decimal::zero(),
+        liquidation_limit: Decimal::zero(),
+        debt_value: Decimal::zero(),
+        collateral_mints,
+        has_isolated_collateral: false,
+        has_positions_outside_emode: false,
+    };
+    let mut valued_mints: Vec<Pubkey> = Vec::with_capacity(global_state.deposited_mints.len());
+    for mint in global_state.deposited_mints.iter() {
+        if valued_mints.contains(mint) {
+            continue;
+        }
+        valued_mints.push(*mint);
+        let borrowed_shares = positions
+            .iter()
+            .filter(|(borrow_mint, _)| borrow_mint == mint)
+            .try_fold(0u64, |total, (_, shares)| total.checked_add(*shares))
             .ok_or(ErrorCode::MathOverflow)?;
+
+        if let Some(asset) = known_assets.iter().find(|asset| asset.bank.mint_address == *mint) {
+            record_membership(&mut health, asset.bank, borrowed_shares, emode);
+            add_asset_health(&mut health, asset, borrowed_shares, emode, current_time)?;
+            continue;
+        }
+
+        require!(asset_infos.len() >= 4, ErrorCode::InvalidHealthAccounts);
+        let (tuple, rest) = asset_infos.split_at(4);
+        asset_infos = rest;
+
+        let bank = Account::<Bank>::try_from(&tuple[0])?;
+        require_keys_eq!(bank.mint_address, *mint, ErrorCode::InvalidHealthAccounts);
+        let user_state = Account::<UserTokenState>::try_from(&tuple[1])?;
+        require_keys_eq!(user_state.owner, *user, ErrorCode::InvalidHealthAccounts);
+        require_keys_eq!(user_state.mint_address, *mint, ErrorCode::InvalidHealthAccounts);
+        let receipt_account = InterfaceAccount::<TokenAccount>::try_from(&tuple[2])?;
+        let expected_receipt_account = get_associated_token_address_with_program_id(
+            user,
+            &bank.receipt_mint,
+            tuple[2].owner,
+        );
+        require_keys_eq!(receipt_account.key(), expected_receipt_account, ErrorCode::InvalidHealthAccounts);
+        let price_update = Account::<PriceUpdateV2>::try_from(&tuple[3])?;
+        let price = get_validated_price(&price_update, &bank)?;
+
+        record_membership(&mut health, &bank, borrowed_shares, emode);
+        let asset = HealthAsset {
+            bank: &bank,
+            user_state: &user_state,
+            receipt_balance: receipt_account.amount,
+            price,
+        };
+        add_asset_health(&mut health, &asset, borrowed_shares, emode, current_time)?;
     }
-    msg!("[compound_interest] Amount: {}", amount);
-    Ok(amount)
+
+    debug_msg!("[account_health] Borrow limit: {}", health.borrow_limit);
+    debug_msg!("[account_health] Liquidation limit: {}", health.liquidation_limit);
+    debug_msg!("[account_health] Debt value: {}", health.debt_value);
+    Ok(health)
 }
 
-pub fn get_validated_price(
-    price_update: &Account<PriceUpdateV2>,
-    feed_id: &Account<PythNetworkFeedId>
-) -> Result<Price> {
-    let feed_id_bytes = get_feed_id_from_hex(feed_id.feed_id.as_str())
-        .map_err(|_| ErrorCode::InvalidPriceFeed)?;
-    msg!("[get_validated_price] Feed ID: {:?}", feed_id_bytes);
-    price_update.get_price_no_older_than(&Clock::get()?, MAXIMUM_AGE, &feed_id_bytes)
-        .map_err(|_| ErrorCode::StalePrice.into())
-}
\ No newline at end of file
+fn record_membership(health: &mut AccountHealth, bank: &Bank, borrowed_shares: u64, emode: Option<&EModeCategory>) {
+    let is_collateral = health.collateral_mints.contains(&bank.mint_address);
+    if bank.isolated && is_collateral {
+        health.has_isolated_collateral = true;
+    }
+    if (is_collateral || borrowed_shares > 0) && emode.is_some_and(|category| !in_emode(bank, category)) {
+        health.has_positions_outside_emode = true;
+    }
+}
+
+fn add_asset_health(
+    health: &mut AccountHealth,
+    asset: &HealthAsset,
+    borrowed_shares: u64,
+    emode: Option<&EModeCategory>,
+    current_time: i64,
+) -> Result<()> {
+    let bank = asset.bank;
+    let params = risk_params(bank, emode);
+    // Collateral locked in an isolated bank only backs positions isolated on
+    // it, and its free deposits back nothing until such a position locks them.
+    let supplied_shares = if bank.isolated {
+        asset.user_state.collateral_shares
+    } else {
+        free_deposit_shares(asset.user_state, asset.receipt_balance)
+            .checked_add(asset.user_state.collateral_shares)
+            .ok_or(ErrorCode::MathOverflow)?
+    };
+    let supplied_amount = calculate_deposit_amount(bank, supplied_shares, Rounding::Down)?;
+    let collateral_value = calculate_token_value(supplied_amount, bank.mint_decimals, &asset.price, Rounding::Down)?;
+    health.borrow_limit = health.borrow_limit
+        .try_add(collateral_value.try_mul(Decimal::from_bps(params.max_ltv))?)?;
+    health.liquidation_limit = health.liquidation_limit
+        .try_add(collateral_value.try_mul(Decimal::from_bps(params.liquidation_threshold))?)?;
+
+    if borrowed_shares > 0 {
+        let debt_value = debt_value(bank, borrowed_shares, &asset.price, current_time)?;
+        health.debt_value = health.debt_value.try_add(debt_value)?;
+    }
+    Ok(())
+}
+
+/// USD value of `borrowed_shares` of `bank`, with the borrow index projected
+/// to `current_time`.
+pub fn debt_value(bank: &Bank, borrowed_shares: u64, price: &Price, current_time: i64) -> Result<Decimal> {
+    let debt_amount = Decimal::from(borrowed_shares)
+        .try_mul_rounded(projected_borrow_index(bank, current_time)?, Rounding::Up)?
+        .try_to_u64(Rounding::Up)?;
+    calculate_token_value(debt_amount, bank.mint_decimals, price, Rounding::Up)
+}
+
+/// Value of `position`'s collateral weighted by each bank's (or the e-mode
+/// category's) `max_ltv`: the most the position can owe.
+///
+/// Banks in `known_banks` are taken as given. `remaining_accounts` must hold
+/// the pair `[bank, price_update]` for each other collateral with locked
+/// shares, in position order.
+pub fn position_borrow_limit<'info>(
+    position: &BorrowPosition,
+    known_banks: &[(&Bank, Price)],
+    remaining_accounts: &'info [AccountInfo<'info>],
+    emode: Option<&EModeCategory>,
+) -> Result<Decimal> {
+    weighted_position_collateral(position, known_banks, remaining_accounts, emode, |params| params.max_ltv)
+}
+
+/// Value of `position`'s collateral weighted by each bank's (or the e-mode
+/// category's) `liquidation_threshold`: the position can be liquidated once
+/// its debt is worth more. Takes the same accounts as `position_borrow_limit`.
+pub fn position_liquidation_limit<'info>(
+    position: &BorrowPosition,
+    known_banks: &[(&Bank, Price)],
+    remaining_accounts: &'info [AccountInfo<'info>],
+    emode: Option<&EModeCategory>,
+) -> Result<Decimal> {
+    weighted_position_collateral(position, known_banks, remaining_accounts, emode, |params| params.liquidation_threshold)
+}
+
+fn weighted_position_collateral<'info>(
+    position: &BorrowPosition,
+    known_banks: &[(&Bank, Price)],
+    remaining_accounts: &'info [AccountInfo<'info>],
+    emode: Option<&EModeCategory>,
+    weight_bps: fn(&RiskParams) -> u64,
+) -> Result<Decimal> {
+    let mut pairs = remaining_accounts.chunks(2);
+    let mut total = Decimal::zero();
+    for collateral in position.collaterals.iter().filter(|collateral| collateral.shares > 0) {
+        let loaded;
+        let (bank, price) = match known_banks.iter().find(|(bank, _)| bank.mint_address == collateral.mint) {
+            Some((bank, price)) => (*bank, *price),
+            None => {
+                let pair = pairs.next().filter(|pair| pair.len() == 2).ok_or(ErrorCode::InvalidCollateralAccounts)?;
+                loaded = Account::<Bank>::try_from(&pair[0])?;
+                require_keys_eq!(loaded.mint_address, collateral.mint, ErrorCode::InvalidCollateralAccounts);
+                let price_update = Account::<PriceUpdateV2>::try_from(&pair[1])?;
+                let price = get_validated_price(&price_update, &loaded)?;
+                (&*loaded, price)
+            }
+        };
+        let amount = calculate_deposit_amount(bank, collateral.shares, Rounding::Down)?;
+        let value = calculate_token_value(amount, bank.mint_decimals, &price, Rounding::Down)?;
+        total = total.try_add(value.try_mul(Decimal::from_bps(weight_bps(&risk_params(bank, emode))))?)?;
+    }
+    Ok(total)
+}
+
+/// Borrow index projected to `current_time` at the bank's last published
+/// borrow rate. Equal to the stored index for banks accrued this slot. Like
+/// `accrue_interest`, it projects at most what one accrual would apply.
+fn projected_borrow_index(bank: &Bank, current_time: i64) -> Result<Decimal> {
+    let borrow_index = Decimal::from_scaled_val(bank.borrow_index);
+    let periods = current_time.saturating_sub(bank.last_compound_time) / bank.interest_accrual_period;
+    if periods <= 0 {
+        return Ok(borrow_index);
+    }
+    let rate = Decimal::from(bank.borrow_interest_rate).try_div(Decimal::from(RATE_PRECISION))?;
+    Ok(compound_index(borrow_index, rate, periods)?.0)
+}
+
+/// Compounds `index` by `rate` for up to `periods` periods and returns the
+/// new index with the number of periods applied. At most
+/// `MAX_COMPOUND_PERIODS` are applied, halving further until the growth and
+/// the index fit, so an idle bank never gets stuck on an overflow.
+fn compound_index(index: Decimal, rate: Decimal, periods: i64) -> Result<(Decimal, i64)> {
+    let base = Decimal::one().try_add(rate)?;
+    let mut applied = periods.min(MAX_COMPOUND_PERIODS);
+    while applied > 0 {
+        let compounded = base
+            .try_pow(applied as u64)
+            .and_then(|growth| index.try_mul_rounded(growth, Rounding::Up))
+            .and_then(|new_index| new_index.to_scaled_val().map(|_| new_index));
+        if let Ok(new_index) = compounded {
+            return Ok((new_index, applied));
+        }
+        applied /= 2;
+    }
+    Err(ErrorCode::MathOverflow.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::WAD;
+
+    // 0.0001% per period, the smallest rate a bank can publish
+    fn slow_rate() -> Decimal {
+        Decimal::from(1u64).try_div(Decimal::from(RATE_PRECISION)).unwrap()
+    }
+
+    fn interest_config(pre_update_average_rate: i16, current_rate: i16, last_update_timestamp: i64) -> MintInterestConfig {
+        MintInterestConfig {
+            initialization_timestamp: 0,
+            pre_update_average_rate,
+            last_update_timestamp,
+            current_rate,
+        }
+    }
+
+    #[test]
+    fn continuous_interest_matches_exp() {
+        const YEAR: i64 = 31_556_736;
+        // e^0.05 = 1.051271096376024039..., e^-0.05 = 0.951229424500714017...
+        let up = continuous_interest(&interest_config(0, 500, 0), YEAR).unwrap().to_scaled_val().unwrap();
+        assert!(up.abs_diff(1_051_271_096_376_024_039) <= 10);
+        let down = continuous_interest(&interest_config(0, -500, 0), YEAR).unwrap().to_scaled_val().unwrap();
+        assert!(down.abs_diff(951_229_424_500_714_017) <= 10);
+        // Half a year at 10% followed by half a year at 0% is also 5%
+        let split = continuous_interest(&interest_config(1_000, 0, YEAR / 2), YEAR).unwrap().to_scaled_val().unwrap();
+        assert!(split.abs_diff(1_051_271_096_376_024_039) <= 10);
+        assert_eq!(continuous_interest(&interest_config(500, 500, 0), 0).unwrap(), Decimal::one());
+    }
+
+    // Accounts decode from zeroed data, leaving only the fields a test sets.
+    fn zeroed<T: AnchorDeserialize>(space: usize) -> T {
+        T::deserialize(&mut &vec![0u8; space][..]).unwrap()
+    }
+
+    // A $1 stablecoin bank at 80% LTV, and `user`'s $1,000 of free deposits in it.
+    fn dollar_deposits(user: Pubkey, mint: Pubkey) -> (Bank, UserTokenState) {
+        let mut bank: Bank = zeroed(Bank::INIT_SPACE);
+        bank.mint_address = mint;
+        bank.mint_decimals = 6;
+        bank.max_ltv = 8_000;
+        bank.liquidation_threshold = 8_500;
+        bank.supply_index = WAD as u128;
+        bank.borrow_index = WAD as u128;
+        bank.interest_accrual_period = 1;
+        let mut user_state: UserTokenState = zeroed(UserTokenState::INIT_SPACE);
+        user_state.owner = user;
+        user_state.mint_address = mint;
+        user_state.deposited_shares = 1_000_000_000;
+        (bank, user_state)
+    }
+
+    const DOLLAR: Price = Price { price: 100_000_000, conf: 0, exponent: -8, publish_time: 0 };
+
+    #[test]
+    fn account_health_values_a_repeated_mint_once() {
+        let mint = Pubkey::new_unique();
+        let user = Pubkey::new_unique();
+        let (bank, user_state) = dollar_deposits(user, mint);
+        let known_assets = [HealthAsset {
+            bank: &bank,
+            user_state: &user_state,
+            receipt_balance: 1_000_000_000,
+            price: DOLLAR,
+        }];
+
+        let mut global_state: UserGlobalState = zeroed(UserGlobalState::INIT_SPACE);
+        global_state.user = user;
+        global_state.deposited_mints = vec![mint];
+        let once = calculate_account_health(&user, &global_state, &known_assets, &[], None, 0).unwrap();
+        global_state.deposited_mints = vec![mint, mint, mint];
+        let repeated = calculate_account_health(&user, &global_state, &known_assets, &[], None, 0).unwrap();
+
+        // $1,000 of deposits at 80% LTV
+        assert_eq!(once.borrow_limit, Decimal::from(800u64));
+        assert_eq!(repeated.borrow_limit, once.borrow_limit);
+        assert_eq!(repeated.liquidation_limit, once.liquidation_limit);
+    }
+
+    #[test]
+    fn account_health_counts_only_locked_isolated_collateral() {
+        let mint = Pubkey::new_unique();
+        let user = Pubkey::new_unique();
+        let (mut bank, mut user_state) = dollar_deposits(user, mint);
+        bank.isolated = true;
+        let mut global_state: UserGlobalState = zeroed(UserGlobalState::INIT_SPACE);
+        global_state.user = user;
+        global_state.deposited_mints = vec![mint];
+        let health = |bank: &Bank, user_state: &UserTokenState| {
+            let known_assets = [HealthAsset { bank, user_state, receipt_balance: 1_000_000_000, price: DOLLAR }];
+            calculate_account_health(&user, &global_state, &known_assets, &[], None, 0).unwrap()
+        };
+
+        assert_eq!(health(&bank, &user_state).borrow_limit, Decimal::zero());
+
+        // $250 locked behind an isolated position still counts
+        user_state.deposited_shares = 750_000_000;
+        user_state.collateral_shares = 250_000_000;
+        assert_eq!(health(&bank, &user_state).borrow_limit, Decimal::from(200u64));
+    }
+
+    #[test]
+    fn pro_rata_collateral_rounds_down() {
+        // A third of the debt frees a third of the collateral, rounded down
+        assert_eq!(pro_rata_collateral_shares(1_000, 1, 3).unwrap(), 333);
+        assert_eq!(pro_rata_collateral_shares(1_000, 3, 3).unwrap(), 1_000);
+        assert_eq!(pro_rata_collateral_shares(u64::MAX, u64::MAX - 1, u64::MAX).unwrap(), u64::MAX - 1);
+        assert!(pro_rata_collateral_shares(1_000, 1, 0).is_err());
+    }
+
+    #[test]
+    fn compound_index_applies_short_gaps_in_full() {
+        let (index, applied) = compound_index(Decimal::one(), slow_rate(), 3_600).unwrap();
+        assert_eq!(applied, 3_600);
+        assert_eq!(index, Decimal::one().try_add(slow_rate()).unwrap().try_pow(3_600).unwrap());
+    }
+
+    #[test]
+    fn compound_index_catches_up_on_a_long_idle_gap() {
+        // Ten idle years of one-second periods cannot be compounded in one go
+        let rate = slow_rate();
+        let periods = 10 * MAX_COMPOUND_PERIODS;
+        assert!(Decimal::one().try_add(rate).unwrap().try_pow(periods as u64).is_err());
+
+        let mut index = Decimal::one();
+        let mut remaining = periods;
+        for _ in 0..3 {
+            let (new_index, applied) = compound_index(index, rate, remaining).unwrap();
+            assert!(applied > 0 && applied <= remaining);
+            assert!(new_index > index);
+            index = new_index;
+            remaining -= applied;
+        }
+    }
+
+    #[test]
+    fn compound_index_caps_periods_per_call() {
+        let (_, applied) = compound_index(Decimal::one(), slow_rate(), 10 * MAX_COMPOUND_PERIODS).unwrap();
+        assert!(applied <= MAX_COMPOUND_PERIODS);
+        assert!(applied > 0);
+    }
+}
@@ -1,10 +1,19 @@
-import { BN, Program } from '@coral-xyz/anchor';
+import { BN, LangErrorCode, Program } from '@coral-xyz/anchor';
 import { BankrunProvider } from 'anchor-bankrun';
-import { TOKEN_PROGRAM_ID } from '@solana/spl-token';
+import {
+  ExtensionType,
+  TOKEN_2022_PROGRAM_ID,
+  TOKEN_PROGRAM_ID,
+  createInitializeMintInstruction,
+  createInitializePermanentDelegateInstruction,
+  getAssociatedTokenAddressSync,
+  getMintLen,
+  unpackAccount,
+} from '@solana/spl-token';
 import { createAccount, createMint, mintTo } from 'spl-token-bankrun';
 import { PythSolanaReceiver } from '@pythnetwork/pyth-solana-receiver';
 import { startAnchor, BanksClient, ProgramTestContext } from 'solana-bankrun';
-import { PublicKey, Keypair, Connection } from '@solana/web3.js';
+import { PublicKey, Keypair, Connection, SystemProgram, Transaction } from '@solana/web3.js';
 import IDL from '../target/idl/lending.json';
 import { Lending } from '../target/types/lending';
 import { BankrunContextWrapper } from './bankrun-utils/bankrunConnection';
@@ -30,6 +39,56 @@ describe('Lending Smart Contract Tests', () => {
   let pythSolanaReceiver: PythSolanaReceiver;
   const SOL_PRICE_FEED_ID = '0xef0d8b6fda2ceba41da15d4095d1da392a0d2f8ed0c6c7bc0f4cfac8c280b56d';
   const USDC_PRICE_FEED_ID = '0xeaa020c61cc479712813461ce153894a96a6c00b21ed0cfc2798d1f9a9e9c94a';
+  // update_bank_config arguments that leave every parameter unchanged
+  const emptyBankConfigUpdate = {
+    liquidationThreshold: null,
+    liquidationBonus: null,
+    liquidationCloseFactor: null,
+    maxLtv: null,
+    baseBorrowRate: null,
+    optimalUtilization: null,
+    borrowRateSlope1: null,
+    borrowRateSlope2: null,
+    reserveFactor: null,
+    depositFee: null,
+    withdrawalFee: null,
+    minDeposit: null,
+    supplyCap: null,
+    borrowCap: null,
+    interestAccrualPeriod: null,
+    feeRecipient: null,
+    flashLoanFee: null,
+    oracleFeedId: null,
+    oracleOwner: null,
+    oracleMaxAge: null,
+    oracleMaxConfidence: null,
+    oracleMinVerification: null,
+    isolated: null,
+    isolatedBorrowMints: null,
+    isolatedDebtCeiling: null,
+    emodeCategory: null,
+  };
+  // Error number a rejected call failed with, whether the client surfaced it
+  // as an AnchorError, a ProgramError or a raw "custom program error"
+  const failureCode = async (call: Promise<unknown>): Promise<number | undefined> => {
+    const error: any = await call.then(() => undefined, (err) => err);
+    expect(error).toBeDefined();
+    if (error.error?.errorCode) {
+      return error.error.errorCode.number;
+    }
+    if (typeof error.code === 'number') {
+      return error.code;
+    }
+    const match = /custom program error: (0x[0-9a-f]+)/.exec(String(error));
+    return match ? parseInt(match[1], 16) : undefined;
+  };
+  // Fails unless `call` is rejected with the program's (or Anchor's) error `name`
+  const expectError = async (call: Promise<unknown>, name: string) => {
+    const expected = IDL.errors.find((error) => error.name === name)?.code
+      ?? (LangErrorCode as Record<string, number>)[name];
+    expect(expected).toBeDefined();
+    expect(await failureCode(call)).toBe(expected);
+  };
 
   beforeAll(async () => {
     const pyth = new PublicKey('pythWSnswVUd12oZpeFP8e9CVaEqJg25g1Vtc2biRsT');
@@ -130,6 +189,93 @@ describe('Lending Smart Contract Tests', () => {
     console.log('USDC Bank Token Account', usdcBankTokenAccount.toBase58());
   }, 30000);
 
+  it('Test Init Protocol Config', async () => {
+    // Only the upgrade authority recorded in the program's ProgramData account
+    // may claim the admin role, so make the suite's signer that authority
+    const bpfLoaderUpgradeable = new PublicKey('BPFLoaderUpgradeab1e11111111111111111111111');
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      bpfLoaderUpgradeable
+    );
+    const deployed = await banksClient.getAccount(programData);
+    // UpgradeableLoaderState::ProgramData: u32 tag (3), u64 slot, Option<Pubkey> authority
+    const programDataBytes = Buffer.from(deployed?.data ?? new Uint8Array(45));
+    programDataBytes.writeUInt32LE(3, 0);
+    programDataBytes[12] = 1;
+    signer.publicKey.toBuffer().copy(programDataBytes, 13);
+    context.setAccount(programData, {
+      lamports: deployed?.lamports ?? 1_000_000_000,
+      data: programDataBytes,
+      owner: bpfLoaderUpgradeable,
+      executable: false,
+    });
+
+    const outsider = Keypair.generate();
+    context.setAccount(outsider.publicKey, {
+      lamports: 1_000_000_000,
+      data: Buffer.alloc(0),
+      owner: SystemProgram.programId,
+      executable: false,
+    });
+    await expectError(
+      program.methods
+        .initProtocolConfig(outsider.publicKey, outsider.publicKey)
+        .accounts({ signer: outsider.publicKey })
+        .signers([outsider])
+        .rpc({ commitment: 'confirmed' }),
+      'unauthorized'
+    );
+
+    const initProtocolConfigTx = await program.methods
+      .initProtocolConfig(signer.publicKey, signer.publicKey)
+      .accounts({
+        signer: signer.publicKey,
+      })
+      .rpc({ commitment: 'confirmed' });
+
+    expect(initProtocolConfigTx).toBeTruthy();
+  });
+
+  it('Test Admin Handover', async () => {
+    const newAdmin = Keypair.generate();
+    const transferAdmin = async (from: Keypair, to: Keypair) => {
+      await program.methods
+        .proposeAdmin(to.publicKey)
+        .accounts({ admin: from.publicKey })
+        .signers([from])
+        .rpc({ commitment: 'confirmed' });
+      await program.methods
+        .acceptAdmin()
+        .accounts({ pendingAdmin: to.publicKey })
+        .signers([to])
+        .rpc({ commitment: 'confirmed' });
+    };
+
+    // Accepting without a matching proposal is refused
+    await expectError(
+      program.methods
+        .acceptAdmin()
+        .accounts({ pendingAdmin: newAdmin.publicKey })
+        .signers([newAdmin])
+        .rpc({ commitment: 'confirmed' }),
+      'unauthorized'
+    );
+
+    await transferAdmin(signer, newAdmin);
+    const [protocolConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from('protocol_config')],
+      program.programId
+    );
+    let config = await program.account.protocolConfig.fetch(protocolConfig);
+    expect(config.admin.toBase58()).toBe(newAdmin.publicKey.toBase58());
+    expect(config.pendingAdmin).toBeNull();
+
+    // Hand control back for the rest of the suite
+    await transferAdmin(newAdmin, signer);
+    config = await program.account.protocolConfig.fetch(protocolConfig);
+    expect(config.admin.toBase58()).toBe(signer.publicKey.toBase58());
+  });
+
   it('Test store symbol feed id', async () => {
     const storeSymbolFeedIdTx = await program.methods
       .storeSymbolFeedId('SOL', SOL_PRICE_FEED_ID)
@@ -180,47 +326,125 @@ describe('Lending Smart Contract Tests', () => {
     expect(initUserSolTx).toBeTruthy();
   });
 
+  it('Test Repeated Init User Token State', async () => {
+    const [userGlobalState] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_global"), signer.publicKey.toBuffer()],
+      program.programId
+    );
+    const before = await program.account.userGlobalState.fetch(userGlobalState);
+
+    // Re-running the init must not list the mint again, or health would count it twice
+    await program.methods
+      .initUserTokenState(mintUSDC)
+      .accounts({ signer: signer.publicKey })
+      .rpc({ commitment: 'confirmed' });
+
+    const after = await program.account.userGlobalState.fetch(userGlobalState);
+    expect(after.depositedMints.map((mint) => mint.toBase58()))
+      .toEqual(before.depositedMints.map((mint) => mint.toBase58()));
+  });
+
+  it('Test Init Bank With Unscaled Risk Parameters', async () => {
+    // The original suite's values: a 5 bps liquidation threshold under a 75%
+    // max LTV would make every loan liquidatable on opening, so it is refused
+    await expectError(
+      program.methods
+        .initBank(
+          new BN(5),
+          new BN(5),
+          new BN(50),
+          new BN(7500),
+          new BN(10),
+          new BN(8000),
+          new BN(100),
+          new BN(3000),
+          new BN(1000),
+          "USDC Bank",
+          "USDC Bank Description",
+          new BN(5),
+          new BN(5),
+          new BN(10000),
+          new BN(86400),
+          new BN(0),
+          new BN(0),
+        )
+        .accounts({
+          signer: signer.publicKey,
+          mint: mintUSDC,
+          pythNetworkFeedId: PublicKey.findProgramAddressSync(
+            [Buffer.from("pyth_network_feed_id"), Buffer.from("USDC")],
+            program.programId
+          )[0],
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc({ commitment: 'confirmed' }),
+      'invalidRiskParameters'
+    );
+  });
+
   it('Test Init Bank', async () => {
+    // Each bank is bound to the feed stored for its symbol
+    const [usdcPythNetworkFeedId] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pyth_network_feed_id"), Buffer.from("USDC")],
+      program.programId
+    );
+    const [solPythNetworkFeedId] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pyth_network_feed_id"), Buffer.from("SOL")],
+      program.programId
+    );
+
     const initUSDCBankTx = await program.methods
       .initBank(
-        new BN(5),
-        new BN(5),
-        new BN(50),
+        new BN(8000),
+        new BN(500),
+        new BN(5000),
         new BN(7500),
-        new BN(5),
         new BN(10),
+        new BN(8000),
+        new BN(100),
+        new BN(3000),
+        new BN(1000),
         "USDC Bank",
         "USDC Bank Description",
         new BN(5),
         new BN(5),
         new BN(10000),
         new BN(86400),
+        new BN(0),
+        new BN(0),
       )
       .accounts({
         signer: signer.publicKey,
         mint: mintUSDC,
+        pythNetworkFeedId: usdcPythNetworkFeedId,
         tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc({ commitment: 'confirmed' });
 
     const initSOLBankTx = await program.methods
       .initBank(
-        new BN(5),
-        new BN(5),
-        new BN(50),
+        new BN(8000),
+        new BN(500),
+        new BN(5000),
         new BN(7500),
-        new BN(3),
-        new BN(15),
+        new BN(10),
+        new BN(8000),
+        new BN(100),
+        new BN(3000),
+        new BN(1000),
         "SOL Bank",
         "SOL Bank Description", 
         new BN(3),
         new BN(3),
         new BN(10000),
         new BN(86400),
+        new BN(0),
+        new BN(0),
       )
       .accounts({
         signer: signer.publicKey,
         mint: mintSOL,
+        pythNetworkFeedId: solPythNetworkFeedId,
         tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc({ commitment: 'confirmed' });
@@ -231,6 +455,124 @@ describe('Lending Smart Contract Tests', () => {
     expect(initSOLBankTx).toBeTruthy();
   });
 
+  it('Test Init Bank Twice', async () => {
+    // Banks can only be initialized once per mint: the system program refuses
+    // to allocate the bank account again (AccountAlreadyInUse)
+    expect(await failureCode(
+      program.methods
+        .initBank(
+          new BN(8000),
+          new BN(500),
+          new BN(5000),
+          new BN(7500),
+          new BN(10),
+          new BN(8000),
+          new BN(100),
+          new BN(3000),
+          new BN(1000),
+          "USDC Bank",
+          "USDC Bank Description",
+          new BN(5),
+          new BN(5),
+          new BN(10000),
+          new BN(86400),
+          new BN(0),
+          new BN(0),
+        )
+        .accounts({
+          signer: signer.publicKey,
+          mint: mintUSDC,
+          pythNetworkFeedId: PublicKey.findProgramAddressSync(
+            [Buffer.from("pyth_network_feed_id"), Buffer.from("USDC")],
+            program.programId
+          )[0],
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc({ commitment: 'confirmed' }),
+    )).toBe(0);
+  });
+
+  it('Test Init Bank Rejects Permanent Delegate Mint', async () => {
+    // A permanent delegate could move tokens out of the treasury, so such mints are refused
+    const mintKeypair = Keypair.generate();
+    const mintLen = getMintLen([ExtensionType.PermanentDelegate]);
+    const rent = await banksClient.getRent();
+    const createMintTx = new Transaction().add(
+      SystemProgram.createAccount({
+        fromPubkey: signer.publicKey,
+        newAccountPubkey: mintKeypair.publicKey,
+        space: mintLen,
+        lamports: Number(rent.minimumBalance(BigInt(mintLen))),
+        programId: TOKEN_2022_PROGRAM_ID,
+      }),
+      createInitializePermanentDelegateInstruction(mintKeypair.publicKey, signer.publicKey, TOKEN_2022_PROGRAM_ID),
+      createInitializeMintInstruction(mintKeypair.publicKey, 6, signer.publicKey, null, TOKEN_2022_PROGRAM_ID),
+    );
+    createMintTx.recentBlockhash = context.lastBlockhash;
+    createMintTx.feePayer = signer.publicKey;
+    createMintTx.sign(signer, mintKeypair);
+    await banksClient.processTransaction(createMintTx);
+
+    await expectError(
+      program.methods
+        .initBank(
+          new BN(8000),
+          new BN(500),
+          new BN(5000),
+          new BN(7500),
+          new BN(10),
+          new BN(8000),
+          new BN(100),
+          new BN(3000),
+          new BN(1000),
+          "Delegated Bank",
+          "Mint with a permanent delegate",
+          new BN(5),
+          new BN(5),
+          new BN(10000),
+          new BN(86400),
+          new BN(0),
+          new BN(0),
+        )
+        .accounts({
+          signer: signer.publicKey,
+          mint: mintKeypair.publicKey,
+          pythNetworkFeedId: PublicKey.findProgramAddressSync(
+            [Buffer.from("pyth_network_feed_id"), Buffer.from("USDC")],
+            program.programId
+          )[0],
+          tokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .rpc({ commitment: 'confirmed' }),
+      'unsupportedMintExtension'
+    );
+  });
+
+  it('Test Update Bank Config', async () => {
+    const updateTx = await program.methods
+      .updateBankConfig({ ...emptyBankConfigUpdate, maxLtv: new BN(7500) })
+      .accounts({
+        authority: signer.publicKey,
+        mint: mintUSDC,
+        emodeCategory: null,
+      })
+      .rpc({ commitment: 'confirmed' });
+    expect(updateTx).toBeTruthy();
+
+    // max_ltv must stay below the liquidation threshold
+    await expectError(
+      program.methods
+        .updateBankConfig({ ...emptyBankConfigUpdate, maxLtv: new BN(9000) })
+        .accounts({
+          authority: signer.publicKey,
+          mint: mintUSDC,
+          emodeCategory: null,
+        })
+        .rpc({ commitment: 'confirmed' }),
+      'invalidRiskParameters'
+    );
+  });
+
   it('Test Init and Fund USDC Bank', async () => {
     const amount = 10_000 * 10 ** 6;
     const mintTx = await mintTo(
@@ -316,7 +658,7 @@ describe('Lending Smart Contract Tests', () => {
   it('Test Deposit', async () => {
     const depositAmount = 1000 * 10**6;
     const depositUSDC = await program.methods
-      .deposit(new BN(depositAmount))
+      .deposit({ tokens: [new BN(depositAmount)] })
       .accounts({
         signer: signer.publicKey,
         mint: mintUSDC,
@@ -340,6 +682,16 @@ describe('Lending Smart Contract Tests', () => {
     
     expect(userTokenState.depositedShares.toString()).not.toBe('0');
     console.log("USDC Deposited Shares:", userTokenState.depositedShares.toString());
+
+    // Free shares are mirrored 1:1 by receipt tokens in the user's wallet
+    const [receiptMintUSDC] = PublicKey.findProgramAddressSync(
+      [Buffer.from("receipt"), mintUSDC.toBuffer()],
+      program.programId
+    );
+    const receiptAccount = getAssociatedTokenAddressSync(receiptMintUSDC, signer.publicKey);
+    const receiptInfo = await banksClient.getAccount(receiptAccount);
+    const receipts = unpackAccount(receiptAccount, { ...receiptInfo, data: Buffer.from(receiptInfo.data) });
+    expect(receipts.amount.toString()).toBe(userTokenState.depositedShares.toString());
     
     const [userGlobalStatePDA] = PublicKey.findProgramAddressSync(
       [Buffer.from("user_global"), signer.publicKey.toBuffer()],
@@ -360,7 +712,7 @@ describe('Lending Smart Contract Tests', () => {
   it('Test SOL Deposit', async () => {
     const depositAmount = 1000 * 10**9;
     const depositSOL = await program.methods
-      .deposit(new BN(depositAmount))
+      .deposit({ tokens: [new BN(depositAmount)] })
       .accounts({
         signer: signer.publicKey,
         mint: mintSOL,
@@ -403,34 +755,138 @@ describe('Lending Smart Contract Tests', () => {
     console.log("SOL deposit verification completed successfully");
   });
   
+  it('Test Supply Cap', async () => {
+    const setSupplyCap = (supplyCap: BN) => program.methods
+      .updateBankConfig({ ...emptyBankConfigUpdate, supplyCap })
+      .accounts({
+        authority: signer.publicKey,
+        mint: mintUSDC,
+        emodeCategory: null,
+      })
+      .rpc({ commitment: 'confirmed' });
+
+    // Cap USDC below what is already supplied, so any further deposit is refused
+    await setSupplyCap(new BN(1));
+    await expectError(
+      program.methods
+        .deposit({ tokens: [new BN(10 * 10**6)] })
+        .accounts({
+          signer: signer.publicKey,
+          mint: mintUSDC,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc({ commitment: 'confirmed' }),
+      'supplyCapExceeded'
+    );
+    await setSupplyCap(new BN(0));
+  });
+
+  it('Test Pause Flags', async () => {
+    const PAUSE_DEPOSIT = 1 << 0;
+    const PAUSE_BORROW = 1 << 2;
+    const depositUSDC = () => program.methods
+      .deposit({ tokens: [new BN(10 * 10**6)] })
+      .accounts({
+        signer: signer.publicKey,
+        mint: mintUSDC,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc({ commitment: 'confirmed' });
+
+    // Protocol-wide freeze of deposits
+    await program.methods
+      .setProtocolPause(PAUSE_DEPOSIT)
+      .accounts({ signer: signer.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    await expectError(depositUSDC(), 'protocolPaused');
+    await program.methods
+      .setProtocolPause(0)
+      .accounts({ signer: signer.publicKey })
+      .rpc({ commitment: 'confirmed' });
+
+    // Freezing borrows on a bank leaves its deposits open
+    const setUsdcBankPause = (pausedOperations: number) => program.methods
+      .setBankPause(pausedOperations)
+      .accounts({ signer: signer.publicKey, mint: mintUSDC })
+      .rpc({ commitment: 'confirmed' });
+    await setUsdcBankPause(PAUSE_BORROW);
+    expect(await depositUSDC()).toBeTruthy();
+
+    // ...but the paused bank cannot back a new loan as collateral either
+    await expectError(
+      program.methods
+        .borrow(new BN(1), { tokens: [new BN(1 * 10**8)] }, 0)
+        .accounts({
+          signer: signer.publicKey,
+          mintBorrow: mintSOL,
+          mintCollateral: mintUSDC,
+          emodeCategory: null,
+          priceUpdateBorrowToken: new PublicKey(solUsdPriceFeedAccount),
+          priceUpdateCollateralToken: new PublicKey(usdcUsdPriceFeedAccount),
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc({ commitment: 'confirmed' }),
+      'bankPaused'
+    );
+    await setUsdcBankPause(PAUSE_DEPOSIT);
+    await expectError(depositUSDC(), 'bankPaused');
+    await setUsdcBankPause(0);
+  });
+
+  it('Test Isolated Collateral', async () => {
+    const updateUsdcBank = (update: Partial<typeof emptyBankConfigUpdate>) =>
+      program.methods
+        .updateBankConfig({ ...emptyBankConfigUpdate, ...update })
+        .accounts({ authority: signer.publicKey, mint: mintUSDC, emodeCategory: null })
+        .rpc({ commitment: 'confirmed' });
+    // Runs before any position locks USDC, while its isolation can still change
+    const borrowAgainstUsdc = () =>
+      program.methods
+        .borrow(new BN(3), { tokens: [new BN(1 * 10**8)] }, 0)
+        .accounts({
+          signer: signer.publicKey,
+          mintBorrow: mintSOL,
+          mintCollateral: mintUSDC,
+          emodeCategory: null,
+          priceUpdateBorrowToken: new PublicKey(solUsdPriceFeedAccount),
+          priceUpdateCollateralToken: new PublicKey(usdcUsdPriceFeedAccount),
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc({ commitment: 'confirmed' });
+
+    // SOL is not whitelisted against isolated USDC
+    await updateUsdcBank({ isolated: true, isolatedDebtCeiling: new BN(1_000_000_000) });
+    await expectError(borrowAgainstUsdc(), 'isolatedBorrowMintNotAllowed');
+
+    // Whitelisted, but a $1 ceiling cannot absorb 0.1 SOL
+    await updateUsdcBank({ isolatedBorrowMints: [mintSOL], isolatedDebtCeiling: new BN(1_000_000) });
+    await expectError(borrowAgainstUsdc(), 'isolatedDebtCeilingExceeded');
+
+    const usdcBank = await program.account.bank.fetch(
+      PublicKey.findProgramAddressSync([mintUSDC.toBuffer()], program.programId)[0]
+    );
+    expect(usdcBank.isolated).toBe(true);
+    expect(usdcBank.isolatedDebt.toNumber()).toBe(0);
+
+    await updateUsdcBank({ isolated: false, isolatedBorrowMints: [] });
+  });
+
   it('Test Borrow', async () => {
     // Reduce borrow amount to 2 SOL (2,000,000,000 lamports)
     const borrowAmount = 1 * 10**9;
     const positionId1 = 1;
     const positionId2 = 2;
 
-    // derive PythNetworkFeedId account
-    const [solPythNetworkFeedId] = PublicKey.findProgramAddressSync(
-      [Buffer.from("pyth_network_feed_id"),Buffer.from("SOL")],
-      program.programId
-    );
-    const [usdcPythNetworkFeedId] = PublicKey.findProgramAddressSync(
-      [Buffer.from("pyth_network_feed_id"),Buffer.from("USDC")],
-      program.programId
-    );
-
     // Fix price feed validation
     const accounts = {
       signer: signer.publicKey,
       mintBorrow: mintSOL,
       mintCollateral: mintUSDC,
+      emodeCategory: null,
 
       priceUpdateBorrowToken: new PublicKey(solUsdPriceFeedAccount),
       priceUpdateCollateralToken: new PublicKey(usdcUsdPriceFeedAccount),
 
-      pythNetworkFeedIdBorrowToken: solPythNetworkFeedId,
-      pythNetworkFeedIdCollateralToken: usdcPythNetworkFeedId,
-
       tokenProgram: TOKEN_PROGRAM_ID,
     };
 
@@ -439,7 +895,7 @@ describe('Lending Smart Contract Tests', () => {
     };
 
     const borrowSOL = await program.methods
-      .borrow(new BN(positionId1), new BN(borrowAmount))
+      .borrow(new BN(positionId1), { tokens: [new BN(borrowAmount)] }, 0)
       .accounts(borrowAccounts1)
       .rpc({ commitment: 'confirmed', skipPreflight: true });
     expect(borrowSOL).toBeTruthy();
@@ -450,97 +906,681 @@ describe('Lending Smart Contract Tests', () => {
       ...accounts,
     };
 
+    // Account health walks every open position; both mints are already in the instruction's accounts
+    const [position1] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        signer.publicKey.toBuffer(),
+        mintUSDC.toBuffer(),
+        mintSOL.toBuffer(),
+        new BN(positionId1).toArrayLike(Buffer, 'le', 8),
+      ],
+      program.programId
+    );
+
     const borrowSOLSecond = await program.methods
-      .borrow(new BN(positionId2), new BN(secondBorrowAmount))
+      .borrow(new BN(positionId2), { tokens: [new BN(secondBorrowAmount)] }, 0)
       .accounts(borrowAccounts2)
+      .remainingAccounts([{ pubkey: position1, isSigner: false, isWritable: false }])
       .rpc({ commitment: 'confirmed', skipPreflight: true });
     expect(borrowSOLSecond).toBeTruthy();
+
+    // Without extra collateral accounts, only the position's own collateral is locked
+    const position = await program.account.borrowPosition.fetch(position1);
+    expect(position.collaterals.length).toBe(1);
+    expect(position.collaterals[0].mint.toBase58()).toBe(mintUSDC.toBase58());
+    expect(position.collaterals[0].shares.toNumber()).toBeGreaterThan(0);
   });
 
-  it('Test Repay', async () => {
-    // derive PythNetworkFeedId account
-    const [solPythNetworkFeedId] = PublicKey.findProgramAddressSync(
-      [Buffer.from("pyth_network_feed_id"), Buffer.from("SOL")],
+  it('Test Borrow With Mismatched Price Feed', async () => {
+    // Collateral priced with another asset's feed must be rejected by the bank's oracle config
+    await expectError(
+      program.methods
+        .borrow(new BN(3), { tokens: [new BN(1 * 10**8)] }, 0)
+        .accounts({
+          signer: signer.publicKey,
+          mintBorrow: mintSOL,
+          mintCollateral: mintUSDC,
+          emodeCategory: null,
+          priceUpdateBorrowToken: new PublicKey(solUsdPriceFeedAccount),
+          priceUpdateCollateralToken: new PublicKey(solUsdPriceFeedAccount),
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc({ commitment: 'confirmed' }),
+      'invalidPriceFeed'
+    );
+  });
+
+  it('Test Borrow Against The Same Mint', async () => {
+    // One bank cannot be both sides of a position
+    await expectError(
+      program.methods
+        .borrow(new BN(3), { tokens: [new BN(1 * 10**6)] }, 0)
+        .accounts({
+          signer: signer.publicKey,
+          mintBorrow: mintUSDC,
+          mintCollateral: mintUSDC,
+          emodeCategory: null,
+          priceUpdateBorrowToken: new PublicKey(usdcUsdPriceFeedAccount),
+          priceUpdateCollateralToken: new PublicKey(usdcUsdPriceFeedAccount),
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc({ commitment: 'confirmed' }),
+      'sameBorrowAndCollateralMint'
+    );
+  });
+
+  it('Test Isolation Locked Under Collateral', async () => {
+    // Positions now lock USDC, so its isolation cannot change under them
+    await expectError(
+      program.methods
+        .updateBankConfig({ ...emptyBankConfigUpdate, isolated: true })
+        .accounts({ authority: signer.publicKey, mint: mintUSDC, emodeCategory: null })
+        .rpc({ commitment: 'confirmed' }),
+      'isolationLocked'
+    );
+  });
+
+  it('Test Add And Remove Collateral', async () => {
+    const [borrowPosition] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        signer.publicKey.toBuffer(),
+        mintUSDC.toBuffer(),
+        mintSOL.toBuffer(),
+        new BN(1).toArrayLike(Buffer, 'le', 8),
+      ],
       program.programId
     );
-    const [usdcPythNetworkFeedId] = PublicKey.findProgramAddressSync(
-      [Buffer.from("pyth_network_feed_id"), Buffer.from("USDC")],
+    const lockedShares = async () =>
+      (await program.account.borrowPosition.fetch(borrowPosition)).collaterals[0].shares;
+    const before = await lockedShares();
+
+    const addTx = await program.methods
+      .addCollateral(new BN(1), { tokens: [new BN(100 * 10**6)] })
+      .accounts({
+        signer: signer.publicKey,
+        mint: mintUSDC,
+        borrowPosition,
+        emodeCategory: null,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc({ commitment: 'confirmed' });
+    expect(addTx).toBeTruthy();
+    const added = (await lockedShares()).sub(before);
+    expect(added.toNumber()).toBeGreaterThan(0);
+
+    // Collateral in the borrowed mint could never be liquidated
+    await expectError(
+      program.methods
+        .addCollateral(new BN(1), { tokens: [new BN(1 * 10**9)] })
+        .accounts({
+          signer: signer.publicKey,
+          mint: mintSOL,
+          borrowPosition,
+          emodeCategory: null,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc({ commitment: 'confirmed' }),
+      'sameBorrowAndCollateralMint'
+    );
+
+    const removeCollateral = (mode: any) =>
+      program.methods
+        .removeCollateral(new BN(1), mode)
+        .accounts({
+          signer: signer.publicKey,
+          mint: mintUSDC,
+          borrowPosition,
+          emodeCategory: null,
+          priceUpdate: new PublicKey(usdcUsdPriceFeedAccount),
+          priceUpdateBorrowToken: new PublicKey(solUsdPriceFeedAccount),
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc({ commitment: 'confirmed' });
+
+    // The position's debt still needs its original collateral
+    await expectError(removeCollateral({ max: {} }), 'insufficientCollateral');
+
+    expect(await removeCollateral({ shares: [added] })).toBeTruthy();
+    expect((await lockedShares()).toString()).toBe(before.toString());
+  });
+
+  it('Test Increase Borrow', async () => {
+    const [position1, position2] = [1, 2].map((positionId) => PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        signer.publicKey.toBuffer(),
+        mintUSDC.toBuffer(),
+        mintSOL.toBuffer(),
+        new BN(positionId).toArrayLike(Buffer, 'le', 8),
+      ],
+      program.programId
+    )[0]);
+    const [userGlobalState] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_global"), signer.publicKey.toBuffer()],
       program.programId
     );
+    const before = await program.account.borrowPosition.fetch(position1);
+    const globalBefore = await program.account.userGlobalState.fetch(userGlobalState);
+
+    // Borrowing on an open position id adds to it rather than opening a new one
+    const increaseTx = await program.methods
+      .borrow(new BN(1), { tokens: [new BN(1 * 10**8)] }, 0)
+      .accounts({
+        signer: signer.publicKey,
+        mintBorrow: mintSOL,
+        mintCollateral: mintUSDC,
+        emodeCategory: null,
+        priceUpdateBorrowToken: new PublicKey(solUsdPriceFeedAccount),
+        priceUpdateCollateralToken: new PublicKey(usdcUsdPriceFeedAccount),
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .remainingAccounts([position1, position2].map((pubkey) => ({ pubkey, isSigner: false, isWritable: false })))
+      .rpc({ commitment: 'confirmed' });
+    expect(increaseTx).toBeTruthy();
+
+    const after = await program.account.borrowPosition.fetch(position1);
+    const globalAfter = await program.account.userGlobalState.fetch(userGlobalState);
+    expect(after.borrowedShares.gt(before.borrowedShares)).toBe(true);
+    expect(after.collaterals[0].shares.gt(before.collaterals[0].shares)).toBe(true);
+    expect(globalAfter.positions.toString()).toBe(globalBefore.positions.toString());
+    expect(globalAfter.activePositions.length).toBe(globalBefore.activePositions.length);
+  });
 
+  it('Test E-Mode Category', async () => {
+    const [stablesCategory] = PublicKey.findProgramAddressSync(
+      [Buffer.from("emode"), Buffer.from([1])],
+      program.programId
+    );
+    const initTx = await program.methods
+      .initEmodeCategory(1, "Stablecoins", new BN(9000), new BN(9500), new BN(200))
+      .accounts({ admin: signer.publicKey })
+      .rpc({ commitment: 'confirmed' });
+    expect(initTx).toBeTruthy();
+
+    const category = await program.account.eModeCategory.fetch(stablesCategory);
+    expect(category.categoryId).toBe(1);
+    expect(category.maxLtv.toNumber()).toBe(9000);
+
+    // Same rules as bank parameters: max LTV must stay below the threshold
+    await expectError(
+      program.methods
+        .updateEmodeCategory(new BN(9500), new BN(9500), new BN(200))
+        .accounts({ admin: signer.publicKey, emodeCategory: stablesCategory })
+        .rpc({ commitment: 'confirmed' }),
+      'invalidRiskParameters'
+    );
+
+    // Banks join a category only by passing its account
+    const setUsdcCategory = (categoryId: number, emodeCategory: PublicKey | null) =>
+      program.methods
+        .updateBankConfig({ ...emptyBankConfigUpdate, emodeCategory: categoryId })
+        .accounts({ authority: signer.publicKey, mint: mintUSDC, emodeCategory })
+        .rpc({ commitment: 'confirmed' });
+    await expectError(setUsdcCategory(2, null), 'invalidEModeCategory');
+    await setUsdcCategory(1, stablesCategory);
+    const usdcBankAddress = PublicKey.findProgramAddressSync([mintUSDC.toBuffer()], program.programId)[0];
+    expect((await program.account.bank.fetch(usdcBankAddress)).emodeCategory).toBe(1);
+    await setUsdcCategory(0, null);
+
+    // Open positions must be passed for the health check
+    await expectError(
+      program.methods
+        .setUserEmode(1)
+        .accounts({ signer: signer.publicKey, emodeCategory: stablesCategory })
+        .rpc({ commitment: 'confirmed' }),
+      'invalidHealthAccounts'
+    );
+  });
+
+  it('Test Liquidate Healthy Position', async () => {
+    const [borrowPosition] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        signer.publicKey.toBuffer(),
+        mintUSDC.toBuffer(),
+        mintSOL.toBuffer(),
+        new BN(1).toArrayLike(Buffer, 'le', 8),
+      ],
+      program.programId
+    );
+    const accounts = {
+      liquidator: signer.publicKey,
+      borrower: signer.publicKey,
+      mintBorrow: mintSOL,
+      mintCollateral: mintUSDC,
+      borrowPosition,
+      emodeCategory: null,
+
+      priceUpdateBorrowToken: new PublicKey(solUsdPriceFeedAccount),
+      priceUpdateCollateralToken: new PublicKey(usdcUsdPriceFeedAccount),
+
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+
+    const [otherPosition] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        signer.publicKey.toBuffer(),
+        mintUSDC.toBuffer(),
+        mintSOL.toBuffer(),
+        new BN(2).toArrayLike(Buffer, 'le', 8),
+      ],
+      program.programId
+    );
+
+    // Freshly opened positions sit at max LTV, below the liquidation threshold
+    await expectError(
+      program.methods
+        .liquidate(new BN(1), new BN(1 * 10**8))
+        .accounts(accounts)
+        .remainingAccounts([borrowPosition, otherPosition].map((pubkey) => ({ pubkey, isSigner: false, isWritable: false })))
+        .rpc({ commitment: 'confirmed' }),
+      'healthyAccount'
+    );
+  });
+
+  it('Test Liquidate Position', async () => {
+    // A borrower whose only deposit is USDC, so a SOL rally sinks the whole account
+    const borrower = Keypair.generate();
+    context.setAccount(borrower.publicKey, {
+      lamports: 10 * 10**9,
+      data: Buffer.alloc(0),
+      owner: SystemProgram.programId,
+      executable: false,
+    });
+    const borrowerUsdc = await createAccount(
+      // @ts-ignore
+      banksClient,
+      signer,
+      mintUSDC,
+      borrower.publicKey
+    );
+    await mintTo(
+      // @ts-ignore
+      banksClient,
+      signer,
+      mintUSDC,
+      borrowerUsdc,
+      signer,
+      100 * 10**6
+    );
+    await program.methods.initUser().accounts({ signer: borrower.publicKey }).signers([borrower]).rpc({ commitment: 'confirmed' });
+    for (const mint of [mintUSDC, mintSOL]) {
+      await program.methods
+        .initUserTokenState(mint)
+        .accounts({ signer: borrower.publicKey })
+        .signers([borrower])
+        .rpc({ commitment: 'confirmed' });
+    }
+    await program.methods
+      .deposit({ tokens: [new BN(100 * 10**6)] })
+      .accounts({ signer: borrower.publicKey, mint: mintUSDC, tokenProgram: TOKEN_PROGRAM_ID })
+      .signers([borrower])
+      .rpc({ commitment: 'confirmed' });
+
+    // PriceUpdateV2: discriminator, write authority, verification level
+    // (1 byte when Full, 2 when Partial), feed id, then price, conf, exponent
+    const priceOffset = (data: Buffer) => 8 + 32 + (data[40] === 1 ? 1 : 2) + 32;
+    const solFeed = new PublicKey(solUsdPriceFeedAccount);
+    const originalFeed = await banksClient.getAccount(solFeed);
+    const feedData = Buffer.from(originalFeed.data);
+    const solPrice = new BN(feedData.readBigInt64LE(priceOffset(feedData)).toString());
+    const solExponent = feedData.readInt32LE(priceOffset(feedData) + 16);
+    const usdcData = Buffer.from((await banksClient.getAccount(new PublicKey(usdcUsdPriceFeedAccount))).data);
+    const usdcPrice = new BN(usdcData.readBigInt64LE(priceOffset(usdcData)).toString());
+    const usdcExponent = usdcData.readInt32LE(priceOffset(usdcData) + 16);
+
+    // $60 of SOL against $100 of USDC, within the 75% max LTV
+    const borrowAmount = Math.floor(60 / (solPrice.toNumber() * 10**solExponent) * 10**9);
+    await program.methods
+      .borrow(new BN(1), { tokens: [new BN(borrowAmount)] }, 0)
+      .accounts({
+        signer: borrower.publicKey,
+        mintBorrow: mintSOL,
+        mintCollateral: mintUSDC,
+        emodeCategory: null,
+        priceUpdateBorrowToken: solFeed,
+        priceUpdateCollateralToken: new PublicKey(usdcUsdPriceFeedAccount),
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([borrower])
+      .rpc({ commitment: 'confirmed' });
+    const [borrowPosition] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        borrower.publicKey.toBuffer(),
+        mintUSDC.toBuffer(),
+        mintSOL.toBuffer(),
+        new BN(1).toArrayLike(Buffer, 'le', 8),
+      ],
+      program.programId
+    );
+
+    // SOL doubles: $120 of debt against $80 of threshold-weighted USDC
+    const movedData = Buffer.from(feedData);
+    movedData.writeBigInt64LE(BigInt(solPrice.muln(2).toString()), priceOffset(movedData));
+    context.setAccount(solFeed, { ...originalFeed, data: movedData });
+
+    const tokenBalance = async (mint: PublicKey) => {
+      const address = getAssociatedTokenAddressSync(mint, signer.publicKey);
+      const info = await banksClient.getAccount(address);
+      return new BN(unpackAccount(address, { ...info, data: Buffer.from(info.data) }).amount.toString());
+    };
+    const usdcBank = await program.account.bank.fetch(usdcBankAccount);
+    const before = await program.account.borrowPosition.fetch(borrowPosition);
+    const solBefore = await tokenBalance(mintSOL);
+    const usdcBefore = await tokenBalance(mintUSDC);
+
+    const repayAmount = Math.floor(borrowAmount / 10);
+    const liquidateTx = await program.methods
+      .liquidate(new BN(1), new BN(repayAmount))
+      .accounts({
+        liquidator: signer.publicKey,
+        borrower: borrower.publicKey,
+        mintBorrow: mintSOL,
+        mintCollateral: mintUSDC,
+        borrowPosition,
+        emodeCategory: null,
+        priceUpdateBorrowToken: solFeed,
+        priceUpdateCollateralToken: new PublicKey(usdcUsdPriceFeedAccount),
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .remainingAccounts([{ pubkey: borrowPosition, isSigner: false, isWritable: false }])
+      .rpc({ commitment: 'confirmed' });
+    expect(liquidateTx).toBeTruthy();
+    context.setAccount(solFeed, originalFeed);
+
+    // The liquidator paid the requested debt and got its USDC value plus the bonus
+    expect(solBefore.sub(await tokenBalance(mintSOL)).toNumber()).toBe(repayAmount);
+    const scale = (exponent: number) => new BN(10).pow(new BN(exponent));
+    const expectedSeized = new BN(repayAmount)
+      .mul(solPrice.muln(2))
+      .mul(usdcBank.liquidationBonus.addn(10_000))
+      .mul(scale(6 + solExponent + 20))
+      .div(usdcPrice.mul(scale(9 + 20 + usdcExponent)).muln(10_000));
+    const seized = (await tokenBalance(mintUSDC)).sub(usdcBefore);
+    expect(seized.gtn(0)).toBe(true);
+    expect(seized.lte(expectedSeized) && expectedSeized.sub(seized).lten(1)).toBe(true);
+
+    // The position stays open with less debt and less collateral
+    const after = await program.account.borrowPosition.fetch(borrowPosition);
+    expect(after.active).toBe(true);
+    expect(after.borrowedShares.lt(before.borrowedShares)).toBe(true);
+    expect(before.collaterals[0].shares.sub(after.collaterals[0].shares).gtn(0)).toBe(true);
+  });
+
+  it('Test Repay', async () => {
     const accounts = {
       signer: signer.publicKey,
       mintBorrow: mintSOL,
       mintCollateral: mintUSDC,
+      emodeCategory: null,
 
       priceUpdateBorrowToken: new PublicKey(pythSolanaReceiver
         .getPriceFeedAccountAddress(0, SOL_PRICE_FEED_ID).toBase58()),
-      pythNetworkFeedIdBorrowToken: solPythNetworkFeedId,
-
       priceUpdateCollateralToken: new PublicKey(pythSolanaReceiver
         .getPriceFeedAccountAddress(0, USDC_PRICE_FEED_ID).toBase58()),
-      pythNetworkFeedIdCollateralToken: usdcPythNetworkFeedId,
 
       tokenProgram: TOKEN_PROGRAM_ID,
     };
 
+    const [position1, position2] = [1, 2].map((positionId) => PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        signer.publicKey.toBuffer(),
+        mintUSDC.toBuffer(),
+        mintSOL.toBuffer(),
+        new BN(positionId).toArrayLike(Buffer, 'le', 8),
+      ],
+      program.programId
+    )[0]);
+    const before = await program.account.borrowPosition.fetch(position1);
+    const otherBefore = await program.account.borrowPosition.fetch(position2);
+
+    // A partial repay reduces only this position's debt and keeps it open
+    const partialRepay = await program.methods
+      .repay(new BN(1), { tokens: [new BN(1 * 10**8)] })
+      .accounts(accounts)
+      .rpc({ commitment: 'confirmed', skipPreflight: true});
+    expect(partialRepay).toBeTruthy();
+    const partial = await program.account.borrowPosition.fetch(position1);
+    expect(partial.active).toBe(true);
+    expect(partial.borrowedShares.lt(before.borrowedShares)).toBe(true);
+    // Collateral is released in proportion to the debt shares burned
+    const burned = before.borrowedShares.sub(partial.borrowedShares);
+    expect(before.collaterals[0].shares.sub(partial.collaterals[0].shares).toString())
+      .toBe(before.collaterals[0].shares.mul(burned).div(before.borrowedShares).toString());
+    expect((await program.account.borrowPosition.fetch(position2)).borrowedShares.toString())
+      .toBe(otherBefore.borrowedShares.toString());
+
+    // More shares than the position owes are rejected, even though position 2 shares the mint
+    await expectError(
+      program.methods
+        .repay(new BN(1), { shares: [partial.borrowedShares.addn(1)] })
+        .accounts(accounts)
+        .rpc({ commitment: 'confirmed' }),
+      'overRepayRequest'
+    );
+
     const repaySOL = await program.methods
-      .repay(new BN(1), new BN(1 * 10**9))
+      .repay(new BN(1), { max: {} })
       .accounts(accounts)
       .rpc({ commitment: 'confirmed', skipPreflight: true});
     expect(repaySOL).toBeTruthy();
+
+    // Clearing the position's own debt closes it and releases its collateral
+    const repaid = await program.account.borrowPosition.fetch(position1);
+    expect(repaid.active).toBe(false);
+    expect(repaid.borrowedShares.toNumber()).toBe(0);
+    expect(repaid.collaterals.every((collateral: any) => collateral.shares.isZero())).toBe(true);
   });
 
 
   it('Test Repay 2', async () => {
-    // derive PythNetworkFeedId account
-    const [solPythNetworkFeedId] = PublicKey.findProgramAddressSync(
-      [Buffer.from("pyth_network_feed_id"), Buffer.from("SOL")],
-      program.programId
-    );
-    const [usdcPythNetworkFeedId] = PublicKey.findProgramAddressSync(
-      [Buffer.from("pyth_network_feed_id"), Buffer.from("USDC")],
-      program.programId
-    );
-
     const accounts = {
       signer: signer.publicKey,
       mintBorrow: mintSOL,
       mintCollateral: mintUSDC,
+      emodeCategory: null,
 
       priceUpdateBorrowToken: new PublicKey(pythSolanaReceiver
         .getPriceFeedAccountAddress(0, SOL_PRICE_FEED_ID).toBase58()),
-      pythNetworkFeedIdBorrowToken: solPythNetworkFeedId,
-
       priceUpdateCollateralToken: new PublicKey(pythSolanaReceiver
         .getPriceFeedAccountAddress(0, USDC_PRICE_FEED_ID).toBase58()),
-      pythNetworkFeedIdCollateralToken: usdcPythNetworkFeedId,
 
       tokenProgram: TOKEN_PROGRAM_ID,
     };
 
     const repaySOL = await program.methods
-      .repay(new BN(2), new BN(1 * 10**9))
+      .repay(new BN(2), { max: {} })
       .accounts(accounts)
       .rpc({ commitment: 'confirmed', skipPreflight: true});
     expect(repaySOL).toBeTruthy();
   });
 
+  it('Test Close Position', async () => {
+    const [position1] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("position"),
+        signer.publicKey.toBuffer(),
+        mintUSDC.toBuffer(),
+        mintSOL.toBuffer(),
+        new BN(1).toArrayLike(Buffer, 'le', 8),
+      ],
+      program.programId
+    );
+    const rentBefore = (await banksClient.getAccount(position1)).lamports;
+    const balanceBefore = await banksClient.getBalance(signer.publicKey);
+
+    const closeTx = await program.methods
+      .closePosition(new BN(1))
+      .accounts({ signer: signer.publicKey, borrowPosition: position1 })
+      .rpc({ commitment: 'confirmed' });
+    expect(closeTx).toBeTruthy();
+    expect(await banksClient.getAccount(position1)).toBeNull();
+    expect(Number(await banksClient.getBalance(signer.publicKey))).toBeGreaterThan(
+      Number(balanceBefore) + rentBefore - 10_000
+    );
+
+    // The USDC token state still holds deposits, so it stays open
+    await expectError(
+      program.methods
+        .closeUserTokenState(mintUSDC)
+        .accounts({ signer: signer.publicKey, tokenProgram: TOKEN_PROGRAM_ID })
+        .rpc({ commitment: 'confirmed' }),
+      'accountNotEmpty'
+    );
+  });
+
   it('Test Withdraw', async () => {
     const accounts = {
       signer: signer.publicKey,
       mint: mintUSDC,
+      emodeCategory: null,
+      priceUpdate: new PublicKey(usdcUsdPriceFeedAccount),
       tokenProgram: TOKEN_PROGRAM_ID,
     };
 
     const withdrawAmount = 1000 * 10**6;
     const withdrawSOL = await program.methods
-      .withdraw(new BN(withdrawAmount))
+      .withdraw({ tokens: [new BN(withdrawAmount)] })
       .accounts(accounts)
       .rpc({ commitment: 'confirmed' });
     expect(withdrawSOL).toBeTruthy();
   });
 
+  it('Test Redeem', async () => {
+    // Receipts can be redeemed directly, without going through a user token state
+    const [receiptMintUSDC] = PublicKey.findProgramAddressSync(
+      [Buffer.from("receipt"), mintUSDC.toBuffer()],
+      program.programId
+    );
+    const redeemTx = await program.methods
+      .redeem(new BN(1 * 10**6))
+      .accounts({
+        signer: signer.publicKey,
+        mint: mintUSDC,
+        receiptTokenAccount: getAssociatedTokenAddressSync(receiptMintUSDC, signer.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc({ commitment: 'confirmed' });
+    expect(redeemTx).toBeTruthy();
+
+    // The redeemed receipts' shares are dropped from the token state on the next withdraw
+    const receiptAccount = getAssociatedTokenAddressSync(receiptMintUSDC, signer.publicKey);
+    const [userTokenStateUSDC] = PublicKey.findProgramAddressSync(
+      [signer.publicKey.toBuffer(), mintUSDC.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .withdraw({ tokens: [new BN(1 * 10**6)] })
+      .accounts({
+        signer: signer.publicKey,
+        mint: mintUSDC,
+        emodeCategory: null,
+        priceUpdate: new PublicKey(usdcUsdPriceFeedAccount),
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc({ commitment: 'confirmed' });
+    const receiptInfo = await banksClient.getAccount(receiptAccount);
+    const receipts = unpackAccount(receiptAccount, { ...receiptInfo, data: Buffer.from(receiptInfo.data) });
+    const userTokenState = await program.account.userTokenState.fetch(userTokenStateUSDC);
+    expect(userTokenState.depositedShares.toString()).toBe(receipts.amount.toString());
+  });
+
+  it('Test Collect Fees', async () => {
+    const collectFees = await program.methods
+      .collectFees()
+      .accounts({
+        authority: signer.publicKey,
+        mint: mintUSDC,
+        feeRecipientTokenAccount: getAssociatedTokenAddressSync(mintUSDC, signer.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc({ commitment: 'confirmed' });
+    expect(collectFees).toBeTruthy();
+  });
+
+  it('Test Bank Control Follows Admin', async () => {
+    const newAdmin = Keypair.generate();
+    const updateUsdcBank = (authority: Keypair) =>
+      program.methods
+        .updateBankConfig({ ...emptyBankConfigUpdate, minDeposit: new BN(10000) })
+        .accounts({ authority: authority.publicKey, mint: mintUSDC, emodeCategory: null })
+        .signers([authority])
+        .rpc({ commitment: 'confirmed' });
+    const transferAdmin = async (from: Keypair, to: Keypair) => {
+      await program.methods
+        .proposeAdmin(to.publicKey)
+        .accounts({ admin: from.publicKey })
+        .signers([from])
+        .rpc({ commitment: 'confirmed' });
+      await program.methods
+        .acceptAdmin()
+        .accounts({ pendingAdmin: to.publicKey })
+        .signers([to])
+        .rpc({ commitment: 'confirmed' });
+    };
+
+    // After a handover the old admin loses every existing bank and the new one gains them
+    await transferAdmin(signer, newAdmin);
+    await expectError(updateUsdcBank(signer), 'unauthorized');
+    await expectError(
+      program.methods
+        .setBankPause(0)
+        .accounts({ signer: signer.publicKey, mint: mintUSDC })
+        .rpc({ commitment: 'confirmed' }),
+      'unauthorized'
+    );
+    expect(await updateUsdcBank(newAdmin)).toBeTruthy();
+
+    await transferAdmin(newAdmin, signer);
+    expect(await updateUsdcBank(signer)).toBeTruthy();
+  });
+
+  it('Test Flash Loan', async () => {
+    const usdcTokenAccount = getAssociatedTokenAddressSync(mintUSDC, signer.publicKey);
+    const flashAmount = new BN(100 * 10**6);
+
+    // Without a matching flash_repay later in the transaction the loan is refused
+    await expectError(
+      program.methods
+        .flashBorrow(flashAmount)
+        .accounts({
+          signer: signer.publicKey,
+          mint: mintUSDC,
+          destinationTokenAccount: usdcTokenAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc({ commitment: 'confirmed' }),
+      'missingFlashRepay'
+    );
+
+    const flashRepayIx = await program.methods
+      .flashRepay()
+      .accounts({
+        signer: signer.publicKey,
+        mint: mintUSDC,
+        sourceTokenAccount: usdcTokenAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .instruction();
+
+    const flashLoanTx = await program.methods
+      .flashBorrow(flashAmount)
+      .accounts({
+        signer: signer.publicKey,
+        mint: mintUSDC,
+        destinationTokenAccount: usdcTokenAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .postInstructions([flashRepayIx])
+      .rpc({ commitment: 'confirmed' });
+    expect(flashLoanTx).toBeTruthy();
+  });
+
   it("Test Get User Financial Profile", async () => {
     console.log("Starting Test Get User Financial Profile");
     // Derive the user's global state PDA using the seed [b"user_global", userPublicKey].
@@ -593,7 +1633,9 @@ describe('Lending Smart Contract Tests', () => {
       console.log("  - Owner:", borrowPosition.owner.toBase58());
       console.log("  - Collateral Mint:", borrowPosition.collateralMint.toBase58());
       console.log("  - Borrow Mint:", borrowPosition.borrowMint.toBase58());
-      console.log("  - Collateral Shares:", borrowPosition.collateralShares.toString());
+      for (const collateral of borrowPosition.collaterals) {
+        console.log("  - Collateral Shares:", collateral.mint.toBase58(), collateral.shares.toString());
+      }
       console.log("  - Borrowed Shares:", borrowPosition.borrowedShares.toString());
       console.log("  - Last Updated:", borrowPosition.lastUpdated.toString());
       console.log("  - Active:", borrowPosition.active);
@@ -677,7 +1719,7 @@ describe('Lending Smart Contract Tests', () => {
         owner: borrowPosition.owner.toBase58(),
         collateralMint: borrowPosition.collateralMint.toBase58(),
         borrowMint: borrowPosition.borrowMint.toBase58(),
-        collateralShares: borrowPosition.collateralShares,
+        collaterals: borrowPosition.collaterals,
         borrowedShares: borrowPosition.borrowedShares,
         lastUpdated: borrowPosition.lastUpdated,
         active: borrowPosition.active,